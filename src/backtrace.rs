@@ -0,0 +1,139 @@
+//! A rudimentary backtrace, that walks the frame pointer chain.
+//!
+//! The chain exists only when the kernel is compiled with frame pointers
+//! (`-C force-frame-pointers=yes`), otherwise the walk stops early or yields garbage addresses.
+//!
+
+use core::arch::asm;
+use core::ops::Range;
+
+/// The maximum number of return addresses `log_backtrace` logs.
+pub const MAX_FRAMES: usize = 16;
+
+/// An iterator over the return addresses of the frame pointer chain.
+///
+/// Every frame is checked to be inside `stack` before it's read, and the frames must be strictly
+/// increasing, so a corrupted chain ends the walk instead of faulting or looping.
+#[derive(Debug, Clone)]
+pub struct Frames {
+    fp: usize,
+    stack: Range<usize>,
+}
+
+impl Frames {
+    /// Creates an iterator over the chain that starts at the frame pointer `fp`.
+    ///
+    /// # Safety
+    /// `stack` must be readable memory.
+    pub unsafe fn new(fp: usize, stack: Range<usize>) -> Self {
+        Self { fp, stack }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let fp = self.fp;
+        if !fp.is_multiple_of(8) || fp < self.stack.start || self.stack.end < fp.checked_add(16)? {
+            return None;
+        }
+
+        // A frame starts with the caller's frame pointer, followed by the return address.
+        let (next_fp, return_address) =
+            unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+
+        // Stop after this frame if the chain doesn't go up the stack.
+        self.fp = if fp < next_fp { next_fp } else { 0 };
+
+        Some(return_address)
+    }
+}
+
+/// Returns the current stack pointer and frame pointer.
+#[inline(always)]
+pub fn current_registers() -> (usize, usize) {
+    let (rsp, rbp): (usize, usize);
+    unsafe {
+        asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp, options(nomem, nostack));
+    }
+    (rsp, rbp)
+}
+
+/// Logs the stack pointer and up to `MAX_FRAMES` return addresses.
+///
+/// The kernel stack is a single 2MiB page, so the walk is bounded by the end of the page of the
+/// stack pointer.
+#[inline(always)]
+pub fn log_backtrace() {
+    let (rsp, rbp) = current_registers();
+    let stack = rsp..(rsp & !0x1fffff) + 0x200000;
+
+    log::error!("Backtrace (rsp=0x{:x}, rbp=0x{:x}):", rsp, rbp);
+    for (i, return_address) in unsafe { Frames::new(rbp, stack) }
+        .take(MAX_FRAMES)
+        .enumerate()
+    {
+        crate::sprintln!("    {}: 0x{:x}", i, return_address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Links frames at the given word indices of `stack`, each with its index as the return
+    /// address, and the last with `last_fp` as the caller's frame pointer.
+    fn link(stack: &mut [usize], frames: &[usize], last_fp: usize) {
+        let base = stack.as_ptr() as usize;
+        for (i, &frame) in frames.iter().enumerate() {
+            stack[frame] = frames.get(i + 1).map_or(last_fp, |&next| base + 8 * next);
+            stack[frame + 1] = frame;
+        }
+    }
+
+    fn walk(stack: &[usize], fp: usize) -> Vec<usize> {
+        let range = stack.as_ptr_range();
+        unsafe { Frames::new(fp, range.start as usize..range.end as usize) }.collect()
+    }
+
+    #[test]
+    fn walks_the_chain_until_it_leaves_the_stack() {
+        let mut stack = [0; 32];
+        let base = stack.as_ptr() as usize;
+        link(&mut stack, &[2, 8, 20], base + 8 * 32);
+        assert_eq!(walk(&stack, base + 8 * 2), [2, 8, 20]);
+        // The last frame must fit in the stack.
+        link(&mut stack, &[2, 8, 20], base + 8 * 31);
+        assert_eq!(walk(&stack, base + 8 * 2), [2, 8, 20]);
+        link(&mut stack, &[2, 8, 20, 30], 0);
+        assert_eq!(walk(&stack, base + 8 * 2), [2, 8, 20, 30]);
+    }
+
+    #[test]
+    fn stops_when_the_chain_doesnt_go_up() {
+        let mut stack = [0; 32];
+        let base = stack.as_ptr() as usize;
+        // A loop.
+        link(&mut stack, &[4, 10], base + 8 * 4);
+        assert_eq!(walk(&stack, base + 8 * 4), [4, 10]);
+        // A frame that points to itself.
+        link(&mut stack, &[4], base + 8 * 4);
+        assert_eq!(walk(&stack, base + 8 * 4), [4]);
+    }
+
+    #[test]
+    fn rejects_bad_frame_pointers() {
+        let mut stack = [0; 32];
+        let base = stack.as_ptr() as usize;
+        link(&mut stack, &[4, 10], base + 8 * 10 + 4);
+        // The misaligned frame isn't read.
+        assert_eq!(walk(&stack, base + 8 * 4), [4, 10]);
+        assert!(walk(&stack, base + 8 * 4 + 4).is_empty());
+        assert!(walk(&stack, base - 16).is_empty());
+        assert!(walk(&stack, base + 8 * 32).is_empty());
+        assert!(walk(&stack, usize::MAX & !7).is_empty());
+        assert!(walk(&stack, 0).is_empty());
+    }
+}
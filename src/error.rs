@@ -0,0 +1,53 @@
+//! The kernel's error type, and the single point where unrecoverable errors are reported.
+//!
+
+use crate::mem::{MapError, MemInitError};
+use core::fmt;
+
+/// The errors of the boot process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    /// The Multiboot2 information structure couldn't be loaded.
+    InvalidMultibootInfo,
+    /// A required Multiboot2 tag is missing, the name of the tag.
+    MissingMultibootTag(&'static str),
+    /// The kernel has no allocated ELF sections.
+    NoKernelSections,
+    /// The memory map has more usable regions than `mem::MAX_MEMORY_REGIONS`.
+    TooManyMemoryRegions,
+    /// A frame allocator ran out of frames.
+    FrameAllocExhausted,
+    /// The kernel stack couldn't be mapped.
+    MapStack(MapError),
+    /// `mem::init` failed.
+    MemInit(MemInitError),
+}
+
+impl From<MemInitError> for KernelError {
+    fn from(err: MemInitError) -> Self {
+        Self::MemInit(err)
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMultibootInfo => write!(f, "Invalid Multiboot2 information structure"),
+            Self::MissingMultibootTag(tag) => write!(f, "{} tag required", tag),
+            Self::NoKernelSections => write!(f, "The kernel has no allocated ELF sections"),
+            Self::TooManyMemoryRegions => write!(
+                f,
+                "Too many memory areas, the capacity is {}",
+                crate::mem::MAX_MEMORY_REGIONS
+            ),
+            Self::FrameAllocExhausted => write!(f, "Out of physical frames"),
+            Self::MapStack(err) => write!(f, "Failed to map the kernel stack: {:?}", err),
+            Self::MemInit(err) => write!(f, "Failed to initialize the memory: {:?}", err),
+        }
+    }
+}
+
+/// Reports an unrecoverable error and halts, by panicking.
+pub fn fatal(err: KernelError) -> ! {
+    panic!("Fatal kernel error: {}", err)
+}
@@ -14,39 +14,39 @@ use x86_64::VirtAddr;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 /// The IST index for the page fault callback.
 pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+/// The IST index for the NMI callback.
+pub const NMI_IST_INDEX: u16 = 2;
+
+/// The size of the static early boot stacks of the ISTs.
+pub const IST_STACK_SIZE: usize = 4096 * 6;
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 6;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+/// The TSS, it's mutable so the IST stacks can be replaced after boot. The CPU reads an IST entry
+/// only when it delivers an interrupt with that index.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
-            stack_end
-        };
-        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 6;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Returns the end of a new static stack of `IST_STACK_SIZE` bytes, every expansion is a distinct
+/// stack.
+macro_rules! static_ist_stack {
+    () => {{
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+        let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!(STACK));
+        stack_start + IST_STACK_SIZE
+    }};
+}
 
-            stack_end
-        };
-        tss
-    };
+lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe {
+            &*core::ptr::addr_of!(TSS)
+        }));
         (
             gdt,
             Selectors {
@@ -57,11 +57,74 @@ lazy_static! {
     };
 }
 
-/// Initializes the GDT
+/// Replaces the stack of the IST entry `index` with `stack`, for example a chunk from the global
+/// chunk allocator instead of the small static early boot stack.
+///
+/// # Safety
+/// No interrupt may be running on the previous stack of `index`, and `stack` mustn't be used for
+/// anything else.
+pub unsafe fn set_ist_stack(index: u16, stack: &'static mut [u8]) {
+    let stack_end = VirtAddr::from_ptr(stack.as_ptr_range().end).align_down(16u64);
+    TSS.interrupt_stack_table[index as usize] = stack_end;
+}
+
+/// Initializes the GDT, and the TSS with a distinct static stack for every IST index.
 pub fn init_gdt() {
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = static_ist_stack!();
+        TSS.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = static_ist_stack!();
+        TSS.interrupt_stack_table[NMI_IST_INDEX as usize] = static_ist_stack!();
+    }
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use std::boxed::Box;
+    use std::vec;
+    use x86_64::PrivilegeLevel;
+
+    #[test]
+    fn selectors_index_the_code_and_tss_descriptors() {
+        let (gdt, selectors) = &*GDT;
+        assert_eq!(selectors.code_selector.index(), 1);
+        assert_eq!(selectors.code_selector.rpl(), PrivilegeLevel::Ring0);
+        assert_eq!(selectors.code_selector.0, 0x08);
+        // The TSS descriptor is a 16-byte system descriptor, it takes the entries 2 and 3.
+        assert_eq!(selectors.tss_selector.index(), 2);
+        assert_eq!(selectors.tss_selector.0, 0x10);
+        assert_eq!(gdt.as_raw_slice().len(), 4);
+    }
+
+    #[test]
+    fn tss_descriptor_points_at_the_tss() {
+        let raw = GDT.0.as_raw_slice();
+        let (low, high) = (raw[2], raw[3]);
+        let base = (low >> 16 & 0xff_ffff) | (low >> 56 & 0xff) << 24 | high << 32;
+        let limit = (low & 0xffff) | (low >> 48 & 0xf) << 16;
+        assert_eq!(base, core::ptr::addr_of!(TSS) as u64);
+        assert_eq!(limit, size_of::<TaskStateSegment>() as u64 - 1);
+        // Present, and the type of an available 64-bit TSS.
+        assert_eq!(low >> 40 & 0xff, 0x89);
+    }
+
+    #[test]
+    fn set_ist_stack_stores_the_aligned_end() {
+        // Only this test touches the TSS, `init_gdt` isn't called on the host.
+        let stack = Box::leak(vec![0u8; 0x1008].into_boxed_slice());
+        let start = stack.as_ptr() as u64;
+        unsafe {
+            set_ist_stack(NMI_IST_INDEX, &mut stack[..0x1003]);
+            let end = (*core::ptr::addr_of!(TSS)).interrupt_stack_table[NMI_IST_INDEX as usize];
+            assert_eq!(end.as_u64(), (start + 0x1003) & !0xf);
+            assert!(end.as_u64() <= start + 0x1003);
+            assert!(end.is_aligned(16u64));
+        }
+    }
+}
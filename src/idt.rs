@@ -3,31 +3,275 @@
 //! To initialize the IDT call `crate::idt::init_idt()`.
 //!
 
+pub mod pic;
+
 use crate::gdt::*;
 use crate::serial::Indent;
+use core::fmt;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+/// The vector offset of the primary PIC.
+pub const PIC_1_OFFSET: u8 = 32;
+/// The vector offset of the secondary PIC.
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// The vectors of the hardware interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    /// The PIT timer interrupt (IRQ0).
+    Timer = PIC_1_OFFSET,
+    /// The PS/2 keyboard interrupt (IRQ1).
+    Keyboard = PIC_1_OFFSET + 1,
+    /// The primary PIC's lowest priority interrupt (IRQ7), it may be spurious.
+    Irq7 = PIC_1_OFFSET + 7,
+    /// The secondary PIC's lowest priority interrupt (IRQ15), it may be spurious.
+    Irq15 = PIC_2_OFFSET + 7,
+}
+
+impl InterruptIndex {
+    /// Returns the PIC IRQ number of the interrupt.
+    #[inline]
+    pub fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+/// A lock-free single-producer single-consumer ring buffer of scancodes. The keyboard interrupt
+/// handler is the only producer, and `read_scancode` is the only consumer.
+struct ScancodeQueue {
+    buffer: [AtomicU8; SCANCODE_QUEUE_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl ScancodeQueue {
+    const fn new() -> Self {
+        Self {
+            buffer: [const { AtomicU8::new(0) }; SCANCODE_QUEUE_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes `scancode`, returns `false` if the queue is full and the scancode was dropped.
+    fn push(&self, scancode: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next_tail = (tail + 1) % SCANCODE_QUEUE_SIZE;
+        if next_tail == self.head.load(Ordering::Acquire) {
+            false
+        } else {
+            self.buffer[tail].store(scancode, Ordering::Relaxed);
+            self.tail.store(next_tail, Ordering::Release);
+            true
+        }
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            None
+        } else {
+            let scancode = self.buffer[head].load(Ordering::Relaxed);
+            self.head
+                .store((head + 1) % SCANCODE_QUEUE_SIZE, Ordering::Release);
+            Some(scancode)
+        }
+    }
+}
+
+static SCANCODE_QUEUE: ScancodeQueue = ScancodeQueue::new();
+
+/// The breakpoint hook as a `fn(&InterruptStackFrame)`, or 0 if there's none. It's atomic so the
+/// handler never has to lock.
+static BREAKPOINT_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// What the divide error handler does, see `set_divide_error_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivideErrorPolicy {
+    /// Log the error and halt, like the other faults. It's the default.
+    Halt,
+    /// Log the error and resume after the faulting `div` or `idiv`. If its encoding isn't one
+    /// that `div_instruction_len` decodes, halt.
+    Skip,
+}
+
+/// True if the divide error policy is `DivideErrorPolicy::Skip`.
+static SKIP_DIVIDE_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Sets what the divide error handler does.
+pub fn set_divide_error_policy(policy: DivideErrorPolicy) {
+    SKIP_DIVIDE_ERRORS.store(policy == DivideErrorPolicy::Skip, Ordering::Relaxed);
+}
+
+/// Returns what the divide error handler does.
+pub fn divide_error_policy() -> DivideErrorPolicy {
+    if SKIP_DIVIDE_ERRORS.load(Ordering::Relaxed) {
+        DivideErrorPolicy::Skip
+    } else {
+        DivideErrorPolicy::Halt
+    }
+}
+
+/// Returns the length of the 64-bit mode `div` or `idiv` at the start of `code`, or `None` if it
+/// isn't one or its encoding isn't supported.
+///
+/// Only the one byte opcodes `F6 /6`, `F6 /7`, `F7 /6` and `F7 /7` are supported, optionally with
+/// an operand size prefix (`66`) followed by a REX prefix. Any other prefix isn't.
+pub fn div_instruction_len(code: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    if *code.get(len)? == 0x66 {
+        len += 1;
+    }
+    if *code.get(len)? & 0xf0 == 0x40 {
+        len += 1;
+    }
+    if !matches!(*code.get(len)?, 0xf6 | 0xf7) {
+        return None;
+    }
+    len += 1;
+
+    let modrm = *code.get(len)?;
+    len += 1;
+    let (mode, reg, rm) = (modrm >> 6, modrm >> 3 & 7, modrm & 7);
+    if reg != 6 && reg != 7 {
+        return None;
+    }
+    if mode != 3 && rm == 4 {
+        let sib = *code.get(len)?;
+        len += 1;
+        // A SIB base of 5 without a displacement means a 32-bit displacement and no base.
+        if mode == 0 && sib & 7 == 5 {
+            len += 4;
+        }
+    }
+    len += match (mode, rm) {
+        // RIP relative.
+        (0, 5) => 4,
+        (1, _) => 1,
+        (2, _) => 4,
+        _ => 0,
+    };
+    Some(len)
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX);
         }
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(NMI_IST_INDEX);
+        }
         unsafe {
             idt.page_fault
                 .set_handler_fn(page_fault_handler)
                 .set_stack_index(PAGE_FAULT_IST_INDEX);
         }
+        idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Irq7 as usize].set_handler_fn(irq7_handler);
+        idt[InterruptIndex::Irq15 as usize].set_handler_fn(irq15_handler);
         idt
     };
 }
 
+extern "x86-interrupt" fn divide_error_handler(mut stack_frame: InterruptStackFrame) {
+    /// The maximal length of an x86 instruction.
+    const MAX_INSTRUCTION_LEN: u64 = 15;
+
+    let rip = stack_frame.instruction_pointer;
+    if divide_error_policy() == DivideErrorPolicy::Skip {
+        // The bytes after the instruction may be unmapped, so don't read past its 4KiB page.
+        let readable_len = MAX_INSTRUCTION_LEN.min(0x1000 - (rip.as_u64() & 0xfff));
+        let code = unsafe { slice::from_raw_parts(rip.as_ptr::<u8>(), readable_len as usize) };
+        if let Some(len) = div_instruction_len(code) {
+            log::warn!("DIVIDE_ERROR at {:?}, skipping {} bytes", rip, len);
+            unsafe {
+                stack_frame
+                    .as_mut()
+                    .update(|frame| frame.instruction_pointer += len as u64);
+            }
+            return;
+        }
+    }
+
+    unsafe {
+        crate::serial::SERIAL_LOGGER.force_unlock();
+        crate::sprintln!();
+    }
+    log::error!("DIVIDE_ERROR: {:#?}", stack_frame);
+
+    crate::hlt_loop()
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    log::info!("BREAKPOINT_INTERRUPT: {:#?}", stack_frame);
+    on_breakpoint(&stack_frame);
+}
+
+/// The body of `breakpoint_handler`, it runs the breakpoint hook or logs the stack frame.
+fn on_breakpoint(stack_frame: &InterruptStackFrame) {
+    match BREAKPOINT_HOOK.load(Ordering::Acquire) {
+        0 => log::info!("BREAKPOINT_INTERRUPT: {:#?}", stack_frame),
+        hook => {
+            let hook: fn(&InterruptStackFrame) = unsafe { core::mem::transmute(hook) };
+            hook(stack_frame);
+        }
+    }
+}
+
+/// The machine state the NMI handler reports, NMIs can be injected to probe a hung kernel (e.g.
+/// with `nmi` in the QEMU monitor).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NmiSnapshot {
+    rip: u64,
+    ticks: u64,
+    cr2: u64,
+    /// `None` if the global chunk allocator was locked or uninitialized.
+    free_chunks: Option<usize>,
+}
+
+impl fmt::Display for NmiSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "NMI: rip={:#x} ticks={} cr2={:#x} free_chunks=",
+            self.rip, self.ticks, self.cr2
+        )?;
+        match self.free_chunks {
+            Some(free_chunks) => write!(f, "{}", free_chunks),
+            None => f.write_str("unavailable"),
+        }
+    }
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::registers::control::Cr2;
+
+    // An NMI can interrupt anything, even a print or an allocation, so it must not wait for a
+    // lock.
+    let snapshot = NmiSnapshot {
+        rip: stack_frame.instruction_pointer.as_u64(),
+        ticks: crate::watchdog::ticks(),
+        cr2: Cr2::read().as_u64(),
+        free_chunks: crate::mem::try_free_chunk_count(),
+    };
+    crate::serial::try_sprint(format_args!("{}\n", snapshot));
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, code: u64) -> ! {
@@ -37,7 +281,7 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame,
     }
     log::error!("DOUBLE_FAULT(code={}): {:#?}", code, stack_frame);
 
-    loop {}
+    crate::hlt_loop()
 }
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -57,10 +301,349 @@ extern "x86-interrupt" fn page_fault_handler(
         Indent::new(1, &stack_frame),
     );
 
-    loop {}
+    crate::hlt_loop()
+}
+
+extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFrame, code: u64) {
+    unsafe {
+        crate::serial::SERIAL_LOGGER.force_unlock();
+        crate::sprintln!();
+    }
+    log::error!("ALIGNMENT_CHECK(code={}): {:#?}", code, stack_frame);
+
+    crate::hlt_loop()
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    use x86_64::registers::model_specific::Msr;
+
+    const IA32_MCG_CAP: u32 = 0x179;
+    const IA32_MCG_STATUS: u32 = 0x17a;
+    const IA32_MC0_STATUS: u32 = 0x401;
+
+    unsafe {
+        crate::serial::SERIAL_LOGGER.force_unlock();
+        crate::sprintln!();
+    }
+    log::error!("MACHINE_CHECK: {:#?}", stack_frame);
+
+    // A machine check is delivered only if CR4.MCE is set, which requires the machine check
+    // architecture, so its MSRs are readable.
+    unsafe {
+        let mcg_cap = Msr::new(IA32_MCG_CAP).read();
+        let mcg_status = Msr::new(IA32_MCG_STATUS).read();
+        log::error!("MCG_CAP=0x{:x} MCG_STATUS=0x{:x}", mcg_cap, mcg_status);
+
+        for bank in 0..(mcg_cap & 0xff) as u32 {
+            let status = Msr::new(IA32_MC0_STATUS + 4 * bank).read();
+            // Only the banks with a valid error (VAL) are logged.
+            if status & 1 << 63 == 0 {
+                continue;
+            }
+            // The ADDR and MISC registers are valid only if ADDRV and MISCV are set.
+            let addr =
+                (status & 1 << 58 != 0).then(|| Msr::new(IA32_MC0_STATUS + 4 * bank + 1).read());
+            let misc =
+                (status & 1 << 59 != 0).then(|| Msr::new(IA32_MC0_STATUS + 4 * bank + 2).read());
+            log::error!(
+                "MC{}_STATUS=0x{:x} MC{}_ADDR={:x?} MC{}_MISC={:x?}",
+                bank,
+                status,
+                bank,
+                addr,
+                bank,
+                misc,
+            );
+        }
+    }
+
+    crate::hlt_loop()
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::watchdog::tick();
+
+    unsafe {
+        pic::notify_end_of_interrupt(InterruptIndex::Timer.irq());
+    }
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // The decoding is left to the consumer, the handler should stay as short as possible.
+    let scancode = unsafe { Port::<u8>::new(0x60).read() };
+    SCANCODE_QUEUE.push(scancode);
+
+    unsafe {
+        pic::notify_end_of_interrupt(InterruptIndex::Keyboard.irq());
+    }
+}
+
+extern "x86-interrupt" fn irq7_handler(_stack_frame: InterruptStackFrame) {
+    // Nothing is attached to IRQ7 yet, so this only makes sure that spurious IRQs aren't
+    // acknowledged.
+    unsafe {
+        pic::notify_end_of_interrupt(InterruptIndex::Irq7.irq());
+    }
+}
+
+extern "x86-interrupt" fn irq15_handler(_stack_frame: InterruptStackFrame) {
+    unsafe {
+        pic::notify_end_of_interrupt(InterruptIndex::Irq15.irq());
+    }
+}
+
+/// Pops the oldest scancode received from the PS/2 keyboard, or `None` if there's none.
+pub fn read_scancode() -> Option<u8> {
+    SCANCODE_QUEUE.pop()
+}
+
+/// Sets a hook that is called on `int3` instead of logging the stack frame.
+pub fn set_breakpoint_hook(hook: fn(&InterruptStackFrame)) {
+    BREAKPOINT_HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Removes the breakpoint hook, so `int3` logs the stack frame again.
+pub fn clear_breakpoint_hook() {
+    BREAKPOINT_HOOK.store(0, Ordering::Release);
+}
+
+/// Fires a breakpoint exception with `int3`, it runs the breakpoint hook or logs the stack frame.
+///
+/// It's meant for validating the IDT: set a hook with `set_breakpoint_hook`, call this, and check
+/// that the hook ran. It needs no hardware, `int3` is a software interrupt.
+pub fn trigger_int3() {
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Runs what the handler installed in the IDT at `vector` runs, with `stack_frame`, as if the
+/// interrupt fired. Test-only: the host tests run in user mode, where `int` doesn't reach the IDT.
+///
+/// # Panics
+/// Panics if no handler is installed at `vector`, or if its handler can't run on the host, like
+/// the ones that access ports. Only the breakpoint handler can.
+#[cfg(test)]
+pub(crate) fn fire_vector(vector: u8, stack_frame: &InterruptStackFrame) {
+    let handler = IDT[usize::from(vector)].handler_addr().as_u64();
+    assert_ne!(
+        handler, 0,
+        "No handler is installed at the vector {}",
+        vector
+    );
+
+    if handler == breakpoint_handler as *const () as u64 {
+        on_breakpoint(stack_frame);
+    } else {
+        panic!("The handler of the vector {} can't run on the host", vector);
+    }
 }
 
 /// Initializes the IDT
 pub fn init_idt() {
     IDT.load();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicU64;
+    use x86_64::structures::idt::InterruptStackFrameValue;
+    use x86_64::VirtAddr;
+
+    /// Serializes the tests that set the breakpoint hook, it's global.
+    static BREAKPOINT_HOOK_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+    /// Returns a stack frame of an interrupt at `rip` in ring 0.
+    fn stack_frame(rip: u64) -> InterruptStackFrame {
+        let value = InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(rip),
+            code_segment: 0x8,
+            cpu_flags: 0x202,
+            stack_pointer: VirtAddr::new(0x7ff000),
+            stack_segment: 0,
+        };
+        // `InterruptStackFrame` is a `repr(C)` wrapper of the value.
+        unsafe { core::mem::transmute(value) }
+    }
+
+    #[test]
+    fn fire_vector_runs_the_installed_breakpoint_handler() {
+        static HOOK_RAN: AtomicBool = AtomicBool::new(false);
+        let _lock = BREAKPOINT_HOOK_LOCK.lock();
+
+        set_breakpoint_hook(|_| HOOK_RAN.store(true, Ordering::Relaxed));
+        fire_vector(3, &stack_frame(0x100000));
+        clear_breakpoint_hook();
+        assert!(HOOK_RAN.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn breakpoint_hook_gets_the_stack_frame() {
+        static HOOK_RIP: AtomicU64 = AtomicU64::new(0);
+        let _lock = BREAKPOINT_HOOK_LOCK.lock();
+
+        set_breakpoint_hook(|stack_frame| {
+            HOOK_RIP.store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed)
+        });
+        fire_vector(3, &stack_frame(0x123456));
+        assert_eq!(HOOK_RIP.load(Ordering::Relaxed), 0x123456);
+        fire_vector(3, &stack_frame(0x200000));
+        clear_breakpoint_hook();
+        assert_eq!(HOOK_RIP.load(Ordering::Relaxed), 0x200000);
+    }
+
+    #[test]
+    fn alignment_and_machine_checks_are_registered() {
+        // Both vectors are typed entries, `fire_vector` can't index them: the alignment check
+        // pushes an error code and the machine check handler diverges.
+        assert_eq!(
+            IDT.alignment_check.handler_addr().as_u64(),
+            alignment_check_handler as *const () as u64
+        );
+        assert_eq!(
+            IDT.machine_check.handler_addr().as_u64(),
+            machine_check_handler as *const () as u64
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "No handler is installed at the vector 100")]
+    fn fire_vector_panics_without_a_handler() {
+        fire_vector(100, &stack_frame(0x100000));
+    }
+
+    #[test]
+    fn nmi_snapshot_display() {
+        let mut snapshot = NmiSnapshot {
+            rip: 0x10f00d,
+            ticks: 42,
+            cr2: 0xdead000,
+            free_chunks: Some(17),
+        };
+        assert_eq!(
+            format!("{}", snapshot),
+            "NMI: rip=0x10f00d ticks=42 cr2=0xdead000 free_chunks=17"
+        );
+        snapshot.free_chunks = None;
+        assert_eq!(
+            format!("{}", snapshot),
+            "NMI: rip=0x10f00d ticks=42 cr2=0xdead000 free_chunks=unavailable"
+        );
+    }
+
+    #[test]
+    fn div_instruction_len_register_operands() {
+        // div ecx
+        assert_eq!(div_instruction_len(&[0xf7, 0xf1]), Some(2));
+        // idiv bl
+        assert_eq!(div_instruction_len(&[0xf6, 0xfb]), Some(2));
+        // div cx
+        assert_eq!(div_instruction_len(&[0x66, 0xf7, 0xf1]), Some(3));
+        // div rcx
+        assert_eq!(div_instruction_len(&[0x48, 0xf7, 0xf1]), Some(3));
+        // idiv r8
+        assert_eq!(div_instruction_len(&[0x49, 0xf7, 0xf8]), Some(3));
+    }
+
+    #[test]
+    fn div_instruction_len_memory_operands() {
+        // div dword [rax]
+        assert_eq!(div_instruction_len(&[0xf7, 0x30]), Some(2));
+        // div qword [rsp]
+        assert_eq!(div_instruction_len(&[0x48, 0xf7, 0x34, 0x24]), Some(4));
+        // div dword [rbp + 0x8]
+        assert_eq!(div_instruction_len(&[0xf7, 0x75, 0x08]), Some(3));
+        // idiv qword [rip + 0x10]
+        assert_eq!(
+            div_instruction_len(&[0x48, 0xf7, 0x3d, 0x10, 0x00, 0x00, 0x00]),
+            Some(7)
+        );
+        // div dword [rax * 4 + 0x1000]
+        assert_eq!(
+            div_instruction_len(&[0xf7, 0x34, 0x85, 0x00, 0x10, 0x00, 0x00]),
+            Some(7)
+        );
+        // div dword [rbx + rcx * 8 + 0x12345678]
+        assert_eq!(
+            div_instruction_len(&[0xf7, 0xb4, 0xcb, 0x78, 0x56, 0x34, 0x12]),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn div_instruction_len_rejects_other_encodings() {
+        // mul ecx
+        assert_eq!(div_instruction_len(&[0xf7, 0xe1]), None);
+        // nop
+        assert_eq!(div_instruction_len(&[0x90]), None);
+        // div dword cs:[rax], the segment prefix isn't supported.
+        assert_eq!(div_instruction_len(&[0x2e, 0xf7, 0x30]), None);
+        // A REX prefix must come right before the opcode.
+        assert_eq!(div_instruction_len(&[0x48, 0x66, 0xf7, 0xf1]), None);
+        // Cut before the ModRM byte.
+        assert_eq!(div_instruction_len(&[0xf7]), None);
+        assert_eq!(div_instruction_len(&[]), None);
+    }
+
+    #[test]
+    fn scancode_queue_is_fifo() {
+        let queue = ScancodeQueue::new();
+        assert_eq!(queue.pop(), None);
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert_eq!(queue.pop(), Some(1));
+        assert!(queue.push(3));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn scancode_queue_drops_when_full_and_wraps() {
+        let queue = ScancodeQueue::new();
+        // One slot stays empty to tell a full queue from an empty one.
+        for i in 0..SCANCODE_QUEUE_SIZE - 1 {
+            assert!(queue.push(i as u8));
+        }
+        assert!(!queue.push(0xff));
+        for i in 0..SCANCODE_QUEUE_SIZE / 2 {
+            assert_eq!(queue.pop(), Some(i as u8));
+        }
+        for i in 0..SCANCODE_QUEUE_SIZE / 2 {
+            assert!(queue.push(0x80 | i as u8));
+        }
+        assert!(!queue.push(0xff));
+        for i in SCANCODE_QUEUE_SIZE / 2..SCANCODE_QUEUE_SIZE - 1 {
+            assert_eq!(queue.pop(), Some(i as u8));
+        }
+        for i in 0..SCANCODE_QUEUE_SIZE / 2 {
+            assert_eq!(queue.pop(), Some(0x80 | i as u8));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn scancode_queue_passes_scancodes_between_threads() {
+        const COUNT: usize = 100_000;
+        static QUEUE: ScancodeQueue = ScancodeQueue::new();
+
+        // The keyboard handler is the only producer and the task the only consumer.
+        let producer = std::thread::spawn(|| {
+            for i in 0..COUNT {
+                while !QUEUE.push(i as u8) {
+                    std::hint::spin_loop();
+                }
+            }
+        });
+        for i in 0..COUNT {
+            let scancode = loop {
+                match QUEUE.pop() {
+                    Some(scancode) => break scancode,
+                    None => std::hint::spin_loop(),
+                }
+            };
+            assert_eq!(scancode, i as u8);
+        }
+        producer.join().unwrap();
+        assert_eq!(QUEUE.pop(), None);
+    }
+}
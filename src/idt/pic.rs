@@ -0,0 +1,177 @@
+//! This module contains everything related to the legacy 8259 PICs.
+//!
+
+use x86_64::instructions::port::Port;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_ISR: u8 = 0x0b;
+
+/// ICW1: start the initialization, an ICW4 follows.
+const ICW1_INIT_WITH_ICW4: u8 = 0x11;
+/// ICW3 of the primary PIC: the secondary is cascaded on IRQ2.
+const ICW3_PIC_1_CASCADE: u8 = 1 << 2;
+/// ICW3 of the secondary PIC: its cascade identity.
+const ICW3_PIC_2_CASCADE: u8 = 2;
+/// ICW4: 8086/88 mode.
+const ICW4_8086: u8 = 0x01;
+
+/// Returns the `(port, byte)` writes that initialize both PICs, with the vector offsets
+/// `offset1` and `offset2`. The masks aren't part of the sequence.
+pub const fn init_command_words(offset1: u8, offset2: u8) -> [(u16, u8); 8] {
+    [
+        (PIC_1_COMMAND, ICW1_INIT_WITH_ICW4),
+        (PIC_2_COMMAND, ICW1_INIT_WITH_ICW4),
+        (PIC_1_DATA, offset1),
+        (PIC_2_DATA, offset2),
+        (PIC_1_DATA, ICW3_PIC_1_CASCADE),
+        (PIC_2_DATA, ICW3_PIC_2_CASCADE),
+        (PIC_1_DATA, ICW4_8086),
+        (PIC_2_DATA, ICW4_8086),
+    ]
+}
+
+/// Waits a tiny bit for the PIC to handle the previous write, by writing to an unused port.
+unsafe fn io_wait() {
+    Port::<u8>::new(0x80).write(0);
+}
+
+/// Remaps the PICs' vectors to `offset1` (IRQ0-7) and `offset2` (IRQ8-15), so they don't
+/// collide with the CPU exceptions. The masks are preserved.
+///
+/// # Safety
+/// Interrupts must be disabled, and the IDT must handle the new vectors before they're enabled.
+pub unsafe fn init_pics(offset1: u8, offset2: u8) {
+    let mut pic1_data = Port::<u8>::new(PIC_1_DATA);
+    let mut pic2_data = Port::<u8>::new(PIC_2_DATA);
+    let (mask1, mask2) = (pic1_data.read(), pic2_data.read());
+
+    for (port, byte) in init_command_words(offset1, offset2) {
+        Port::<u8>::new(port).write(byte);
+        io_wait();
+    }
+
+    pic1_data.write(mask1);
+    pic2_data.write(mask2);
+}
+
+/// Returns the data port and the bit of `irq` in that PIC's mask.
+fn mask_port_and_bit(irq: u8) -> (Port<u8>, u8) {
+    assert!(irq < 16, "IRQ {} doesn't exist", irq);
+    if irq < 8 {
+        (Port::new(PIC_1_DATA), 1 << irq)
+    } else {
+        (Port::new(PIC_2_DATA), 1 << irq - 8)
+    }
+}
+
+/// Masks `irq`, so the PIC won't raise it.
+pub fn mask(irq: u8) {
+    let (mut port, bit) = mask_port_and_bit(irq);
+    unsafe {
+        let mask = port.read();
+        port.write(mask | bit);
+    }
+}
+
+/// Unmasks `irq`. IRQ8-15 also need IRQ2, the cascade, to be unmasked.
+pub fn unmask(irq: u8) {
+    let (mut port, bit) = mask_port_and_bit(irq);
+    unsafe {
+        let mask = port.read();
+        port.write(mask & !bit);
+    }
+}
+
+/// Reads the in-service registers of both PICs, the primary's is in the low byte.
+pub fn read_isr() -> u16 {
+    unsafe {
+        let mut pic1_command = Port::<u8>::new(PIC_1_COMMAND);
+        let mut pic2_command = Port::<u8>::new(PIC_2_COMMAND);
+
+        pic1_command.write(CMD_READ_ISR);
+        pic2_command.write(CMD_READ_ISR);
+
+        u16::from(pic2_command.read()) << 8 | u16::from(pic1_command.read())
+    }
+}
+
+/// Returns true if `irq` is spurious according to the in-service register `isr`.
+/// Only the lowest priority IRQ of each PIC (IRQ7 and IRQ15) can be spurious.
+#[inline]
+pub fn is_spurious_in(irq: u8, isr: u16) -> bool {
+    (irq == 7 || irq == 15) && isr & 1 << irq == 0
+}
+
+/// Returns true if `irq` is spurious, the PIC raised it but it isn't in service.
+pub fn is_spurious(irq: u8) -> bool {
+    (irq == 7 || irq == 15) && is_spurious_in(irq, read_isr())
+}
+
+/// Sends an End-Of-Interrupt for `irq` to the PICs.
+///
+/// A spurious IRQ isn't acknowledged, since the PIC that raised it isn't servicing anything,
+/// except that a spurious IRQ15 still came through the primary PIC's cascade, so the primary gets
+/// its EOI.
+///
+/// # Safety
+/// Must be called only at the end of `irq`'s interrupt handler.
+pub unsafe fn notify_end_of_interrupt(irq: u8) {
+    if is_spurious(irq) {
+        if irq == 15 {
+            Port::<u8>::new(PIC_1_COMMAND).write(CMD_END_OF_INTERRUPT);
+        }
+        return;
+    }
+
+    if 8 <= irq {
+        Port::<u8>::new(PIC_2_COMMAND).write(CMD_END_OF_INTERRUPT);
+    }
+    Port::<u8>::new(PIC_1_COMMAND).write(CMD_END_OF_INTERRUPT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_irq7_and_irq15_can_be_spurious() {
+        assert!(is_spurious_in(7, 0));
+        assert!(is_spurious_in(15, 0));
+        // The primary PIC is servicing the cascade, but not IRQ15.
+        assert!(is_spurious_in(15, 1 << 2));
+        for irq in (0..16).filter(|&irq| irq != 7 && irq != 15) {
+            assert!(!is_spurious_in(irq, 0), "IRQ {} can't be spurious", irq);
+        }
+    }
+
+    #[test]
+    fn irq_in_service_isnt_spurious() {
+        assert!(!is_spurious_in(7, 1 << 7));
+        assert!(!is_spurious_in(15, 1 << 15 | 1 << 2));
+        // The other PIC's in-service bits don't matter.
+        assert!(is_spurious_in(7, 1 << 15));
+        assert!(is_spurious_in(15, 1 << 7));
+    }
+
+    #[test]
+    fn init_command_words_remap_in_order() {
+        assert_eq!(
+            init_command_words(0x20, 0x28),
+            [
+                (0x20, 0x11),
+                (0xa0, 0x11),
+                (0x21, 0x20),
+                (0xa1, 0x28),
+                (0x21, 4),
+                (0xa1, 2),
+                (0x21, 1),
+                (0xa1, 1),
+            ]
+        );
+    }
+}
@@ -4,23 +4,30 @@
 //! NOTE: This should be updated if my goals change, or if my Engrish has improved.
 //!
 
-#![no_std]
+// The unit tests run on the host, with `make test`.
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
 // #![feature(asm)]
 // #![feature(const_fn_trait_bound)]
-#![feature(default_alloc_error_handler)]
 #![warn(missing_docs)]
+// The address arithmetic leans on the precedence of the shifts and the bitwise operators, like
+// `size + 0x1fffff & !0x1fffff`.
+#![allow(clippy::precedence)]
 
 // extern crate alloc;
 
+pub mod backtrace;
+pub mod error;
 pub mod gdt;
 pub mod idt;
 pub mod mem;
 pub mod ref_stack;
 pub mod serial;
 pub mod stack_vec;
+pub mod task;
+pub mod watchdog;
 
-use core::panic::PanicInfo;
+use error::KernelError;
 
 // /// Internal stuff
 // #[doc(hidden)]
@@ -49,61 +56,200 @@ use core::panic::PanicInfo;
 //     }
 // }
 
-fn init() {
+/// The first init phase, initializes only the serial logger so early boot can log.
+///
+/// The init phases should be called in order: `init_early()` and then `init_cpu()`.
+pub fn init_early() {
+    watchdog::set_phase(watchdog::BootPhase::Early);
+    serial::init_logger();
+}
+
+/// The second init phase, initializes the GDT and then the IDT (the IDT refers to the GDT's
+/// interrupt stacks), and remaps the PICs to the IDT's hardware interrupt vectors. Should be
+/// called after `init_early()`, so faults can be logged.
+pub fn init_cpu() {
+    watchdog::set_phase(watchdog::BootPhase::Cpu);
     gdt::init_gdt();
     idt::init_idt();
-    serial::init_logger();
+    // Interrupts are still disabled, they were never enabled.
+    unsafe {
+        idt::pic::init_pics(idt::PIC_1_OFFSET, idt::PIC_2_OFFSET);
+    }
+}
+
+/// Runs `f` with interrupts disabled, and then restores the interrupt flag to what it was. Locks
+/// that interrupt handlers may take, like the allocators' locks, must be taken inside it, or an
+/// interrupt that comes while the lock is held deadlocks.
+#[inline]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    // The unit tests run in user mode on the host, where `cli` faults and nothing interrupts them.
+    if cfg!(test) {
+        return f();
+    }
+    masking_interrupts(&CpuInterruptFlag, f)
+}
+
+/// The interrupt flag that `masking_interrupts` clears and restores.
+trait InterruptFlag {
+    fn is_enabled(&self) -> bool;
+    fn set(&self, enabled: bool);
+}
+
+/// The interrupt flag of the CPU, in RFLAGS.
+struct CpuInterruptFlag;
+
+impl InterruptFlag for CpuInterruptFlag {
+    #[inline]
+    fn is_enabled(&self) -> bool {
+        x86_64::instructions::interrupts::are_enabled()
+    }
+
+    #[inline]
+    fn set(&self, enabled: bool) {
+        if enabled {
+            x86_64::instructions::interrupts::enable();
+        } else {
+            x86_64::instructions::interrupts::disable();
+        }
+    }
+}
+
+/// Runs `f` with `flag` cleared, and then sets it again if it was set before. The flag handling of
+/// `without_interrupts`.
+#[inline]
+fn masking_interrupts<R>(flag: &impl InterruptFlag, f: impl FnOnce() -> R) -> R {
+    let were_enabled = flag.is_enabled();
+    if were_enabled {
+        flag.set(false);
+    }
+    let result = f();
+    if were_enabled {
+        flag.set(true);
+    }
+    result
+}
+
+/// Halts the CPU forever. Interrupts still wake it up while they're enabled, and their handlers
+/// run.
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Resets the machine.
+///
+/// First it pulses the CPU reset line through the 8042 keyboard controller, see
+/// `pulse_reset_line`. If the machine is still running (there may be no 8042), it loads an empty
+/// IDT and triggers an interrupt, the resulting triple fault resets the CPU.
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
+    use x86_64::instructions::{interrupts, tables};
+    use x86_64::structures::DescriptorTablePointer;
+
+    /// The status register (read) and the command register (write) of the 8042.
+    const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
+
+    log::info!("Rebooting");
+    interrupts::disable();
+
+    unsafe {
+        let mut status = PortReadOnly::<u8>::new(KEYBOARD_CONTROLLER_PORT);
+        let mut command = PortWriteOnly::<u8>::new(KEYBOARD_CONTROLLER_PORT);
+        pulse_reset_line(|| status.read(), |byte| command.write(byte));
+
+        tables::lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::zero(),
+        });
+        interrupts::int3();
+    }
+
+    hlt_loop()
+}
+
+/// Sends the 8042 keyboard controller the command that pulses the CPU reset line, once its input
+/// buffer is empty. `read_status` reads its status register, and `write_command` writes its
+/// command register.
+///
+/// Returns false if the input buffer stayed full for too long and nothing was sent.
+fn pulse_reset_line(
+    mut read_status: impl FnMut() -> u8,
+    mut write_command: impl FnMut(u8),
+) -> bool {
+    /// The status bit that is set while the 8042's input buffer is full.
+    const STATUS_INPUT_BUFFER_FULL: u8 = 1 << 1;
+    /// The 8042 command that pulses the CPU reset line.
+    const CMD_PULSE_RESET: u8 = 0xfe;
+    /// How many times the status is polled before giving up.
+    const MAX_POLLS: usize = 0x10000;
+
+    for _ in 0..MAX_POLLS {
+        if read_status() & STATUS_INPUT_BUFFER_FULL == 0 {
+            write_command(CMD_PULSE_RESET);
+            return true;
+        }
+    }
+    false
 }
 
 /// This function allocates the stack chunk
 #[allow(unreachable_code)]
 #[no_mangle]
 pub extern "C" fn alloc_stack(multiboot_info_ptr: usize, pd_table_ptr: usize) -> usize {
-    init();
+    init_early();
+    log::info!("Initialized the logger");
+    init_cpu();
+
+    try_alloc_stack(multiboot_info_ptr, pd_table_ptr).unwrap_or_else(|err| error::fatal(err))
+}
 
-    use x86_64::structures::paging::{FrameAllocator, PageTable, PageTableFlags};
-    // use x86_64::PhysAddr;
+/// The fallible part of `alloc_stack`.
+fn try_alloc_stack(multiboot_info_ptr: usize, pd_table_ptr: usize) -> Result<usize, KernelError> {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::{FrameAllocator, Page, PageTable, PageTableFlags};
+    use x86_64::VirtAddr;
 
     log::info!("begin `alloc_stack()`");
     log::info!("multiboot_info_ptr: 0x{:x}", multiboot_info_ptr);
     log::info!("pd_table_ptr: 0x{:x}", pd_table_ptr);
 
-    let boot_info = unsafe { multiboot2::load(multiboot_info_ptr).unwrap() };
-    let pd_table = unsafe { &mut *(pd_table_ptr as *mut PageTable) };
-
-    log::info!("find kernel_start, kernel_end");
-    let elf_sections_tag = boot_info
-        .elf_sections_tag()
-        .expect("ELF-Symbols tag required");
-    let kernel_start = elf_sections_tag
-        .sections()
-        .map(|section| section.start_address())
-        .min()
-        .unwrap() as usize;
-    let kernel_end = elf_sections_tag
-        .sections()
-        .map(|section| section.start_address())
-        .max()
-        .unwrap() as usize;
+    let boot_info = unsafe { multiboot2::load(multiboot_info_ptr) }
+        .map_err(|_| KernelError::InvalidMultibootInfo)?;
+
+    log::info!("survey the memory");
+    let survey = mem::survey(&boot_info)?;
 
     log::info!("create bump_allocator");
-    let memory_map_tag = boot_info.memory_map_tag().expect("Memory Map tag required");
-    let mut bump_alloc = mem::BumpAllocator::new(
-        [
-            kernel_start..kernel_end,
-            boot_info.start_address()..boot_info.end_address(),
-        ],
-        memory_map_tag,
-    );
+    let mut bump_alloc = mem::BumpAllocator::new(survey.reserved(), &survey.usable);
 
     log::info!("allocate stack_frame");
-    let stack_frame = bump_alloc.allocate_frame().unwrap();
-    pd_table[1].set_addr(
-        stack_frame.start_address(),
-        PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-    );
+    let stack_frame = bump_alloc
+        .allocate_frame()
+        .ok_or(KernelError::FrameAllocExhausted)?;
+
+    // The boot page tables identity map the first 4GiB and link the PD table at `pd_table_ptr`
+    // where the stack goes. A missing table could only be created in a frame below 4GiB.
+    let mut table_alloc = mem::SubFrameAllocator::new(bump_alloc);
+    let stack_page = Page::from_start_address(VirtAddr::new(mem::KERNEL_STACK_VIRT as _)).unwrap();
+    unsafe {
+        let (pml4_frame, _) = Cr3::read();
+        mem::map_page(
+            &mut *(pml4_frame.start_address().as_u64() as *mut PageTable),
+            VirtAddr::zero(),
+            stack_page,
+            stack_frame,
+            PageTableFlags::WRITABLE,
+            || {
+                table_alloc
+                    .allocate_frame()
+                    .filter(|frame| frame.start_address().as_u64() < 1 << 32)
+            },
+        )
+    }
+    .map_err(KernelError::MapStack)?;
 
-    stack_frame.start_address().as_u64() as _
+    Ok(stack_frame.start_address().as_u64() as _)
 }
 
 /// The entry point of the kernel which starts everything.
@@ -111,56 +257,30 @@ pub extern "C" fn alloc_stack(multiboot_info_ptr: usize, pd_table_ptr: usize) ->
 pub extern "C" fn kernel_main(multiboot_info_ptr: usize, phys_stack_frame: usize) -> ! {
     log::info!("Kernel main START");
 
-    let boot_info = unsafe { multiboot2::load(multiboot_info_ptr).unwrap() };
-    log::info!("Loaded boot_info={:#?}", boot_info);
-    log::info!("Loaded phys_stack_frame={:#?}", phys_stack_frame);
-    let memory_map_tag = boot_info.memory_map_tag().expect("Memory Map tag required");
-    let elf_sections_tag = boot_info
-        .elf_sections_tag()
-        .expect("ELF-Symbols tag required");
-
-    log::info!("Memory areas: [");
-    for area in memory_map_tag.memory_areas() {
-        sprintln!(
-            "    memory_area(addr=0x{:x}, size=0x{:x}),",
-            area.start_address(),
-            area.size(),
-        );
-    }
-    sprintln!("]");
-
-    log::info!("ELF sections: [");
-    for section in elf_sections_tag.sections() {
-        sprintln!(
-            "    elf_section(addr=0x{:x}, size=0x{:x}, flags=0x{:x}),",
-            section.start_address(),
-            section.size(),
-            section.flags(),
-        )
+    if let Err(err) = init_kernel(multiboot_info_ptr, phys_stack_frame) {
+        error::fatal(err);
     }
-    sprintln!("]");
 
-    let kernel_start = elf_sections_tag
-        .sections()
-        .map(|section| section.start_address())
-        .min()
-        .unwrap();
-    let kernel_end = elf_sections_tag
-        .sections()
-        .map(|section| section.start_address())
-        .max()
-        .unwrap();
+    watchdog::set_phase(watchdog::BootPhase::Tasks);
+    {
+        let ping = core::pin::pin!(async {
+            for i in 0..3 {
+                log::info!("ping {}", i);
+                task::yield_now().await;
+            }
+        });
+        let pong = core::pin::pin!(async {
+            for i in 0..3 {
+                log::info!("pong {}", i);
+                task::yield_now().await;
+            }
+        });
 
-    // x86_64::instructions::interrupts::int3();
-
-    unsafe {
-        mem::init(
-            kernel_start as _,
-            kernel_end as _,
-            phys_stack_frame,
-            &boot_info,
-            memory_map_tag,
-        );
+        let mut executor = task::Executor::new();
+        assert!(executor.spawn(task::Task::new(ping)).is_ok());
+        assert!(executor.spawn(task::Task::new(pong)).is_ok());
+        executor.run();
+        log::info!("Ran the executor");
     }
 
     // let mut frame_allocator = BumpAllocator::new(
@@ -176,7 +296,37 @@ pub extern "C" fn kernel_main(multiboot_info_ptr: usize, phys_stack_frame: usize
     // }
 
     log::info!("Kernel main END");
-    loop {}
+    hlt_loop()
+}
+
+/// The fallible part of `kernel_main`, it loads the boot information and initializes the memory.
+fn init_kernel(multiboot_info_ptr: usize, phys_stack_frame: usize) -> Result<(), KernelError> {
+    let boot_info = unsafe { multiboot2::load(multiboot_info_ptr) }
+        .map_err(|_| KernelError::InvalidMultibootInfo)?;
+    log::info!("Loaded boot_info={:#?}", boot_info);
+    log::info!("Loaded phys_stack_frame={:#?}", phys_stack_frame);
+    let survey = mem::survey(&boot_info)?;
+
+    log::info!(
+        "Boot info (usable memory {}):",
+        serial::HumanSize(survey.total_ram as _)
+    );
+    serial::dump_boot_info(&boot_info);
+
+    log::info!(
+        "Kernel: 0x{:x}..0x{:x}",
+        survey.kernel.start,
+        survey.kernel.end
+    );
+
+    // x86_64::instructions::interrupts::int3();
+
+    watchdog::set_phase(watchdog::BootPhase::Memory);
+    unsafe {
+        mem::init(&survey, phys_stack_frame)?;
+    }
+
+    Ok(())
 }
 
 // #[allow(unconditional_recursion)]
@@ -197,15 +347,126 @@ pub extern "C" fn kernel_main(multiboot_info_ptr: usize, phys_stack_frame: usize
 // }
 
 /// The kernel panic handler.
+#[cfg(not(test))]
 #[panic_handler]
-pub fn panic(info: &PanicInfo) -> ! {
+pub fn panic(info: &core::panic::PanicInfo) -> ! {
     unsafe {
         serial::SERIAL_LOGGER.force_unlock();
-        sprintln!();
     }
+    // A failing sink mustn't panic again.
+    let _ = serial::SERIAL_LOGGER.try_flush();
+    sprintln!();
 
     log::error!("Kernel panic: `{}`", info);
+    backtrace::log_backtrace();
 
     // log::error!("PANIC: {}", info);
-    loop {}
+    hlt_loop()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    /// An interrupt flag that records every write to it.
+    struct MockInterruptFlag {
+        enabled: Cell<bool>,
+        writes: RefCell<Vec<bool>>,
+    }
+
+    impl MockInterruptFlag {
+        fn new(enabled: bool) -> Self {
+            Self {
+                enabled: Cell::new(enabled),
+                writes: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl InterruptFlag for MockInterruptFlag {
+        fn is_enabled(&self) -> bool {
+            self.enabled.get()
+        }
+
+        fn set(&self, enabled: bool) {
+            self.enabled.set(enabled);
+            self.writes.borrow_mut().push(enabled);
+        }
+    }
+
+    #[test]
+    fn pulse_reset_line_waits_for_the_input_buffer() {
+        // The input buffer is full for the first 3 reads, the other bits don't matter.
+        let mut statuses = [0x03, 0x02, 0xfe, 0x01].into_iter();
+        let mut commands = Vec::new();
+        assert!(pulse_reset_line(
+            || statuses.next().unwrap(),
+            |byte| commands.push(byte)
+        ));
+        assert_eq!(commands, [0xfe]);
+        assert_eq!(statuses.next(), None);
+    }
+
+    #[test]
+    fn pulse_reset_line_gives_up_on_a_stuck_controller() {
+        let mut reads = 0;
+        let mut commands = Vec::new();
+        assert!(!pulse_reset_line(
+            || {
+                reads += 1;
+                0xff
+            },
+            |byte| commands.push(byte)
+        ));
+        assert_eq!(reads, 0x10000);
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn masking_interrupts_clears_and_restores_the_flag() {
+        let flag = MockInterruptFlag::new(true);
+        let result = masking_interrupts(&flag, || {
+            assert!(!flag.is_enabled());
+            7
+        });
+        assert_eq!(result, 7);
+        assert!(flag.is_enabled());
+        assert_eq!(*flag.writes.borrow(), [false, true]);
+    }
+
+    #[test]
+    fn masking_interrupts_leaves_a_clear_flag_clear() {
+        let flag = MockInterruptFlag::new(false);
+        masking_interrupts(&flag, || assert!(!flag.is_enabled()));
+        assert!(!flag.is_enabled());
+        assert!(flag.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn masking_interrupts_nests() {
+        let flag = MockInterruptFlag::new(true);
+        masking_interrupts(&flag, || {
+            masking_interrupts(&flag, || assert!(!flag.is_enabled()));
+            // The inner call found the flag clear, so it's still clear.
+            assert!(!flag.is_enabled());
+        });
+        assert!(flag.is_enabled());
+        assert_eq!(*flag.writes.borrow(), [false, true]);
+    }
+
+    #[test]
+    fn init_early_alone_enables_logging() {
+        static CAPTURE: spin::Mutex<std::string::String> =
+            spin::Mutex::new(std::string::String::new());
+
+        let _lock = serial::LOGGER_LOCK.lock();
+        serial::SERIAL_LOGGER.set_sink(Some(&CAPTURE));
+        init_early();
+        assert_eq!(watchdog::phase(), watchdog::BootPhase::Early);
+        log::info!("logged after init_early");
+        assert!(CAPTURE.lock().contains("INFO: logged after init_early\n"));
+        serial::SERIAL_LOGGER.set_sink(None);
+    }
 }
@@ -10,7 +10,7 @@ use crate::{
 use core::borrow::Borrow;
 use core::cmp::Ordering;
 use core::marker::PhantomData;
-use core::{fmt, mem, ops, ptr, slice};
+use core::{fmt, iter, mem, ops, ptr, slice};
 
 const B: usize = 6;
 
@@ -19,7 +19,27 @@ const MAX_NUM_ELEMENTS: usize = 2 * B - 1;
 const MIN_NUM_CHILDREN: usize = B;
 const MAX_NUM_CHILDREN: usize = 2 * B;
 
-const MAX_DEPTH: usize = 20;
+/// The deepest a tree can get, the capacity of the path stacks. `len` is a `usize`, so a tree
+/// can't have more than `usize::MAX` entries.
+const MAX_DEPTH: usize = max_depth();
+
+/// Returns the largest depth a tree with at most `usize::MAX` entries can have. The root has at
+/// least 2 children and the other nodes at least `B`, so a tree of depth `d` has at least
+/// `2 * B^(d - 1) - 1` entries.
+const fn max_depth() -> usize {
+    let mut depth = 1;
+    // `B^(depth - 1)`
+    let mut pow: usize = 1;
+    loop {
+        match pow.checked_mul(2 * B) {
+            Some(_) => {
+                pow *= B;
+                depth += 1;
+            }
+            None => return depth,
+        }
+    }
+}
 
 trait OptionExt {
     fn assert_none(&self);
@@ -341,7 +361,7 @@ impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for NodeElements<K, V> {
 
 impl<K: Ord, V> Drop for NodeElements<K, V> {
     fn drop(&mut self) {
-        while let Some(_) = self.pop() {}
+        while self.pop().is_some() {}
     }
 }
 
@@ -440,14 +460,14 @@ impl<K: Ord, V> Child<K, V> {
     //     }
     // }
 
-    pub fn as_ref(&self) -> ChildRef<K, V> {
+    pub fn as_ref(&self) -> ChildRef<'_, K, V> {
         match self {
             Self::Node(node) => ChildRef::Node(node),
             Self::Leaf(leaf) => ChildRef::Leaf(leaf),
         }
     }
 
-    pub fn as_mut(&mut self) -> ChildRefMut<K, V> {
+    pub fn as_mut(&mut self) -> ChildRefMut<'_, K, V> {
         match self {
             Self::Node(node) => ChildRefMut::Node(node),
             Self::Leaf(leaf) => ChildRefMut::Leaf(leaf),
@@ -620,21 +640,21 @@ impl<'a, K: Ord, V> ChildRefMut<'a, K, V> {
     //     }
     // }
 
-    pub fn as_ref(self) -> ChildRef<'a, K, V> {
+    pub fn into_ref(self) -> ChildRef<'a, K, V> {
         match self {
             Self::Node(node) => ChildRef::Node(node),
             Self::Leaf(leaf) => ChildRef::Leaf(leaf),
         }
     }
 
-    pub fn borrow(&self) -> ChildRef<K, V> {
+    pub fn borrow(&self) -> ChildRef<'_, K, V> {
         match self {
             Self::Node(node) => ChildRef::Node(node),
             Self::Leaf(leaf) => ChildRef::Leaf(leaf),
         }
     }
 
-    pub fn borrow_mut(&mut self) -> ChildRefMut<K, V> {
+    pub fn borrow_mut(&mut self) -> ChildRefMut<'_, K, V> {
         match self {
             Self::Node(node) => ChildRefMut::Node(node),
             Self::Leaf(leaf) => ChildRefMut::Leaf(leaf),
@@ -750,21 +770,21 @@ impl<'a, K: Ord, V> IntoIterator for ChildrenSlice<'a, K, V> {
 
     fn into_iter(self) -> Self::IntoIter {
         match self {
-            Self::Nodes(nodes) => ChildrenIter::Nodes(nodes.into_iter()),
-            Self::Leafs(leafs) => ChildrenIter::Leafs(leafs.into_iter()),
+            Self::Nodes(nodes) => ChildrenIter::Nodes(nodes.iter()),
+            Self::Leafs(leafs) => ChildrenIter::Leafs(leafs.iter()),
         }
     }
 }
 
 impl<'a, K: Ord, V> ChildrenSliceMut<'a, K, V> {
-    pub fn get(&self, i: usize) -> Option<ChildRef<K, V>> {
+    pub fn get(&self, i: usize) -> Option<ChildRef<'_, K, V>> {
         match self {
             Self::Nodes(nodes) => Some(ChildRef::Node(nodes.get(i)?)),
             Self::Leafs(leafs) => Some(ChildRef::Leaf(leafs.get(i)?)),
         }
     }
 
-    pub fn get_mut(&mut self, i: usize) -> Option<ChildRefMut<K, V>> {
+    pub fn get_mut(&mut self, i: usize) -> Option<ChildRefMut<'_, K, V>> {
         match self {
             Self::Nodes(nodes) => Some(ChildRefMut::Node(nodes.get_mut(i)?)),
             Self::Leafs(leafs) => Some(ChildRefMut::Leaf(leafs.get_mut(i)?)),
@@ -785,7 +805,7 @@ impl<'a, K: Ord, V> ChildrenSliceMut<'a, K, V> {
         }
     }
 
-    pub fn slice<B: ops::RangeBounds<usize>>(&self, bounds: B) -> Option<ChildrenSlice<K, V>> {
+    pub fn slice<B: ops::RangeBounds<usize>>(&self, bounds: B) -> Option<ChildrenSlice<'_, K, V>> {
         let bounds = (
             BoundClonedExt::cloned(&bounds.start_bound()),
             BoundClonedExt::cloned(&bounds.end_bound()),
@@ -800,7 +820,7 @@ impl<'a, K: Ord, V> ChildrenSliceMut<'a, K, V> {
     pub fn slice_mut<B: ops::RangeBounds<usize>>(
         &mut self,
         bounds: B,
-    ) -> Option<ChildrenSliceMut<K, V>> {
+    ) -> Option<ChildrenSliceMut<'_, K, V>> {
         let bounds = (
             BoundClonedExt::cloned(&bounds.start_bound()),
             BoundClonedExt::cloned(&bounds.end_bound()),
@@ -838,14 +858,14 @@ impl<'a, K: Ord, V> ChildrenSliceMut<'a, K, V> {
         }
     }
 
-    pub fn iter(&self) -> ChildrenIter<K, V> {
+    pub fn iter(&self) -> ChildrenIter<'_, K, V> {
         match self {
             Self::Nodes(nodes) => ChildrenIter::Nodes(nodes.iter()),
             Self::Leafs(leafs) => ChildrenIter::Leafs(leafs.iter()),
         }
     }
 
-    pub fn iter_mut(&mut self) -> ChildrenIterMut<K, V> {
+    pub fn iter_mut(&mut self) -> ChildrenIterMut<'_, K, V> {
         match self {
             Self::Nodes(nodes) => ChildrenIterMut::Nodes(nodes.iter_mut()),
             Self::Leafs(leafs) => ChildrenIterMut::Leafs(leafs.iter_mut()),
@@ -870,8 +890,8 @@ impl<'a, K: Ord, V> IntoIterator for ChildrenSliceMut<'a, K, V> {
 
     fn into_iter(self) -> Self::IntoIter {
         match self {
-            Self::Nodes(nodes) => ChildrenIterMut::Nodes(nodes.into_iter()),
-            Self::Leafs(leafs) => ChildrenIterMut::Leafs(leafs.into_iter()),
+            Self::Nodes(nodes) => ChildrenIterMut::Nodes(nodes.iter_mut()),
+            Self::Leafs(leafs) => ChildrenIterMut::Leafs(leafs.iter_mut()),
         }
     }
 }
@@ -1086,7 +1106,7 @@ impl<K: Ord, V> OuterLenChildren<K, V> {
     }
 
     #[inline]
-    pub unsafe fn as_slice(&self, len: usize) -> ChildrenSlice<K, V> {
+    pub unsafe fn as_slice(&self, len: usize) -> ChildrenSlice<'_, K, V> {
         match self {
             Self::Nodes(nodes) => ChildrenSlice::Nodes(nodes.as_slice(len)),
             Self::Leafs(leafs) => ChildrenSlice::Leafs(leafs.as_slice(len)),
@@ -1094,7 +1114,7 @@ impl<K: Ord, V> OuterLenChildren<K, V> {
     }
 
     #[inline]
-    pub unsafe fn as_slice_mut(&mut self, len: usize) -> ChildrenSliceMut<K, V> {
+    pub unsafe fn as_slice_mut(&mut self, len: usize) -> ChildrenSliceMut<'_, K, V> {
         match self {
             Self::Nodes(nodes) => ChildrenSliceMut::Nodes(nodes.as_slice_mut(len)),
             Self::Leafs(leafs) => ChildrenSliceMut::Leafs(leafs.as_slice_mut(len)),
@@ -1204,7 +1224,7 @@ impl<K: Ord, V> Children<K, V> {
     }
 
     #[inline]
-    pub fn as_slice(&self) -> ChildrenSlice<K, V> {
+    pub fn as_slice(&self) -> ChildrenSlice<'_, K, V> {
         match &self._data {
             OuterLenChildren::Nodes(nodes) => {
                 ChildrenSlice::Nodes(unsafe { nodes.as_slice(self.len()) })
@@ -1216,7 +1236,7 @@ impl<K: Ord, V> Children<K, V> {
     }
 
     #[inline]
-    pub fn as_slice_mut(&mut self) -> ChildrenSliceMut<K, V> {
+    pub fn as_slice_mut(&mut self) -> ChildrenSliceMut<'_, K, V> {
         let len = self.len();
         match &mut self._data {
             OuterLenChildren::Nodes(nodes) => {
@@ -1266,7 +1286,7 @@ impl<K: Ord, V> IntoIterator for Children<K, V> {
 
 impl<K: Ord, V> Drop for Children<K, V> {
     fn drop(&mut self) {
-        while let Some(_) = self.pop() {}
+        while self.pop().is_some() {}
     }
 }
 
@@ -1282,11 +1302,11 @@ impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for Children<K, V> {
     }
 }
 
-impl<K: Ord, V> Into<ChildrenStackVec<K, V>> for Children<K, V> {
+impl<K: Ord, V> From<Children<K, V>> for ChildrenStackVec<K, V> {
     #[inline]
-    fn into(self) -> ChildrenStackVec<K, V> {
+    fn from(val: Children<K, V>) -> Self {
         unsafe {
-            let (data, len) = self.into_raw_parts();
+            let (data, len) = val.into_raw_parts();
 
             match data {
                 OuterLenChildren::Nodes(nodes) => {
@@ -1300,11 +1320,11 @@ impl<K: Ord, V> Into<ChildrenStackVec<K, V>> for Children<K, V> {
     }
 }
 
-impl<K: Ord, V> Into<Children<K, V>> for ChildrenStackVec<K, V> {
+impl<K: Ord, V> From<ChildrenStackVec<K, V>> for Children<K, V> {
     #[inline]
-    fn into(self) -> Children<K, V> {
+    fn from(val: ChildrenStackVec<K, V>) -> Self {
         unsafe {
-            match self {
+            match val {
                 ChildrenStackVec::Nodes(nodes) => {
                     let (data, len) = nodes.into_raw_parts();
                     Children::from_raw_parts(OuterLenChildren::Nodes(data), len)
@@ -1425,17 +1445,17 @@ impl<K: Ord, V> Node<K, V> {
     }
 
     #[inline]
-    pub fn children(&self) -> ChildrenSlice<K, V> {
+    pub fn children(&self) -> ChildrenSlice<'_, K, V> {
         unsafe { self._children.as_slice(self.num_children()) }
     }
 
     #[inline]
-    pub fn children_mut(&mut self) -> ChildrenSliceMut<K, V> {
+    pub fn children_mut(&mut self) -> ChildrenSliceMut<'_, K, V> {
         unsafe { self._children.as_slice_mut(self.num_children()) }
     }
 
     #[inline]
-    pub fn get_all_mut(&mut self) -> (&mut [K], &mut [V], ChildrenSliceMut<K, V>) {
+    pub fn get_all_mut(&mut self) -> (&mut [K], &mut [V], ChildrenSliceMut<'_, K, V>) {
         let children = unsafe { self._children.as_slice_mut(self.num_children()) };
         let (keys, values) = self._elements.get_all_mut();
         (keys, values, children)
@@ -1728,7 +1748,7 @@ impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for Node<K, V> {
 
 impl<K: Ord, V> Drop for Node<K, V> {
     fn drop(&mut self) {
-        while let Some(_) = self.pop() {}
+        while self.pop().is_some() {}
         unsafe {
             self._children.pop(&mut 1).unwrap();
         }
@@ -1778,6 +1798,36 @@ impl<K: Ord, V> Drop for Node<K, V> {
 //     Err(a.len())
 // }
 
+/// Searches `keys` linearly. Returns `Ok(i)` if `keys[i]` equals `key`, otherwise `Err(i)` where
+/// `i` is the index of the first key greater than `key`.
+#[inline]
+fn search_keys<K, Q>(keys: &[K], key: &Q) -> Result<usize, usize>
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    for (i, elem_k) in keys.iter().enumerate() {
+        match key.cmp(elem_k.borrow()) {
+            Ordering::Less => return Err(i),
+            Ordering::Equal => return Ok(i),
+            Ordering::Greater => {}
+        }
+    }
+    Err(keys.len())
+}
+
+/// Returns the slot of the value that was at `idx` of a node that overflowed and was split into
+/// `left` and `right`, or `None` if it became the separator. The overflowing node is seen as
+/// `MAX_NUM_ELEMENTS + 1` entries, the last one is the overflow.
+#[inline]
+fn split_slot<V>(idx: usize, left: &mut [V], right: &mut [V]) -> Option<ptr::NonNull<V>> {
+    match idx.cmp(&B) {
+        Ordering::Less => Some(ptr::NonNull::from(&mut left[idx])),
+        Ordering::Equal => None,
+        Ordering::Greater => Some(ptr::NonNull::from(&mut right[idx - B - 1])),
+    }
+}
+
 // #[derive(Debug, Clone)]
 #[derive(Debug)]
 pub struct BTree<K: Ord, V> {
@@ -1812,13 +1862,14 @@ impl<K: Ord, V> BTree<K, V> {
         // SPN = SN / (SN + SL) = S1N / (S1N + S1L * (B - 1))
         // SPL = SL / (SN + SL) = S1L * (B - 1) / (S1N + S1L * (B - 1))
 
+        let split_from = chunk.as_ptr() as usize;
         let (node_alloc_chunk, leaf_alloc_chunk) = chunk.split_at_mut(
             chunk.len() * mem::size_of::<Node<K, V>>()
                 / (mem::size_of::<Node<K, V>>() + (B - 1) * mem::size_of::<NodeElements<K, V>>()),
         );
 
-        let node_alloc = SlabAllocator::new(node_alloc_chunk);
-        let mut leaf_alloc = SlabAllocator::new(leaf_alloc_chunk);
+        let node_alloc = SlabAllocator::new_split(node_alloc_chunk, split_from);
+        let mut leaf_alloc = SlabAllocator::new_split(leaf_alloc_chunk, split_from);
 
         Self {
             // root: Child::Leaf(SlabBox::new(NodeElements::new(ptr::null_mut()))),
@@ -1836,21 +1887,80 @@ impl<K: Ord, V> BTree<K, V> {
         self.node_alloc.needs_new_chunk() || self.leaf_alloc.needs_new_chunk()
     }
 
+    /// Returns how many 2 MiB chunks have to be added with `add_chunk`, so the tree won't need a
+    /// new chunk. Chunks go to the node allocator first, so it's the sum of both allocators'.
+    #[inline]
+    pub fn chunks_needed(&self) -> usize {
+        self.node_alloc.chunks_needed() + self.leaf_alloc.chunks_needed()
+    }
+
+    /// Returns how many more entries can surely be inserted before the tree needs a new chunk.
+    ///
+    /// It's a lower bound: an insertion allocates at most a leaf, and a node for every level it
+    /// splits plus a new root, so at most `MAX_DEPTH` nodes. Most insertions allocate nothing.
+    pub fn capacity(&self) -> usize {
+        self.leaf_alloc
+            .spare_slabs()
+            .min(self.node_alloc.spare_slabs() / MAX_DEPTH)
+    }
+
     pub fn add_chunk(&mut self, chunk: &'static mut [u8]) {
         if self.node_alloc.needs_new_chunk() {
             self.node_alloc.add_chunk(chunk);
         } else if self.leaf_alloc.needs_new_chunk() {
             self.leaf_alloc.add_chunk(chunk);
         } else {
+            let split_from = chunk.as_ptr() as usize;
             let (node_alloc_chunk, leaf_alloc_chunk) = chunk.split_at_mut(
                 chunk.len() * mem::size_of::<Node<K, V>>()
                     / (mem::size_of::<Node<K, V>>()
                         + (B - 1) * mem::size_of::<NodeElements<K, V>>()),
             );
 
-            self.node_alloc.add_chunk(node_alloc_chunk);
-            self.leaf_alloc.add_chunk(leaf_alloc_chunk);
-        }
+            self.node_alloc
+                .add_split_chunk(node_alloc_chunk, split_from);
+            self.leaf_alloc
+                .add_split_chunk(leaf_alloc_chunk, split_from);
+        }
+    }
+
+    /// Detaches a chunk that has no entries in it and returns it for reuse, as it was given to
+    /// `new` or `add_chunk`. Only chunks whose removal doesn't make the tree need a new chunk are
+    /// reclaimed.
+    pub fn try_reclaim_chunk(&mut self) -> Option<&'static mut [u8]> {
+        // A chunk is either held entirely by one allocator, or split between the node allocator
+        // (its start) and the leaf allocator (its end). Both parts of a split chunk record the
+        // chunk's address, and it's reclaimed only when both parts are free.
+        let leaf_chunk = self
+            .leaf_alloc
+            .free_chunks()
+            .find(|chunk| self.leaf_alloc.split_from(chunk).is_none());
+        if let Some(chunk) = leaf_chunk {
+            return self.leaf_alloc.reclaim_chunk(chunk);
+        }
+        let node_chunk = self
+            .node_alloc
+            .free_chunks()
+            .find(|chunk| self.node_alloc.split_from(chunk).is_none());
+        if let Some(chunk) = node_chunk {
+            return self.node_alloc.reclaim_chunk(chunk);
+        }
+
+        let (split_from, node_chunk, leaf_chunk) =
+            self.node_alloc.free_chunks().find_map(|node_chunk| {
+                let split_from = self.node_alloc.split_from(&node_chunk)?;
+                let leaf_chunk = self
+                    .leaf_alloc
+                    .free_chunks()
+                    .find(|chunk| self.leaf_alloc.split_from(chunk) == Some(split_from))?;
+                Some((split_from, node_chunk, leaf_chunk))
+            })?;
+
+        let node_chunk = self.node_alloc.reclaim_chunk(node_chunk).unwrap();
+        let leaf_chunk = self.leaf_alloc.reclaim_chunk(leaf_chunk).unwrap();
+        Some(unsafe {
+            slice::from_raw_parts_mut(split_from as *mut u8, node_chunk.len() + leaf_chunk.len())
+        })
     }
 
     #[inline]
@@ -1905,7 +2015,11 @@ impl<K: Ord, V> BTree<K, V> {
         }
     }
 
-    pub fn get_entry<Q>(&self, key: &Q) -> Result<BTreeEntry<K, V>, BTreeEntry<K, V>>
+    /// Returns `Ok` with a cursor at the entry of `key`, or `Err` with a cursor at an entry next
+    /// to where `key` would be, on either side of it. The tree mustn't be empty.
+    // The entries are cursors, they're as large on both sides.
+    #[allow(clippy::result_large_err)]
+    pub fn get_entry<Q>(&self, key: &Q) -> Result<BTreeEntry<'_, K, V>, BTreeEntry<'_, K, V>>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
@@ -1943,12 +2057,15 @@ impl<K: Ord, V> BTree<K, V> {
                             Ordering::Greater => {}
                         }
                     }
+                    // Every key of the leaf is smaller, the cursor is at the last one.
                     entry.index_stack.push(leaf.len() - 1).assert_none();
-                    return Ok(entry);
+                    return Err(entry);
                 }
                 None => {
                     let node = *entry.node_stack.last().unwrap();
-                    let mut idx = node.num_elements() - 1;
+                    // Every key of the node is smaller, unless the loop finds a greater one, so
+                    // the key is in the last child.
+                    let mut idx = node.num_elements();
 
                     for (i, elem_k) in node.keys().iter().enumerate() {
                         let ord = key.cmp(elem_k.borrow());
@@ -1978,6 +2095,84 @@ impl<K: Ord, V> BTree<K, V> {
         }
     }
 
+    /// Returns the entry with the smallest key that is greater than or equal to `key`.
+    pub fn ceil_entry<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut best = None;
+        let mut child = self.root.as_ref();
+        loop {
+            // The keys of the `i`th child are between the keys `i - 1` and `i`.
+            let i = child.keys().partition_point(|k| k.borrow() < key);
+            if let Some(k) = child.keys().get(i) {
+                best = Some((k, &child.values()[i]));
+                if k.borrow() == key {
+                    return best;
+                }
+            }
+            match child {
+                ChildRef::Node(node) => child = node.children().get(i).unwrap(),
+                ChildRef::Leaf(_) => return best,
+            }
+        }
+    }
+
+    /// Returns the entry with the largest key that is less than or equal to `key`.
+    pub fn floor_entry<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut best = None;
+        let mut child = self.root.as_ref();
+        loop {
+            let i = child.keys().partition_point(|k| k.borrow() <= key);
+            if 0 < i {
+                let k = &child.keys()[i - 1];
+                best = Some((k, &child.values()[i - 1]));
+                if k.borrow() == key {
+                    return best;
+                }
+            }
+            match child {
+                ChildRef::Node(node) => child = node.children().get(i).unwrap(),
+                ChildRef::Leaf(_) => return best,
+            }
+        }
+    }
+
+    /// Same as `get_entry`, but the returned cursor can also edit the tree.
+    #[allow(clippy::result_large_err)]
+    pub fn get_entry_mut<Q>(
+        &mut self,
+        key: &Q,
+    ) -> Result<BTreeEntryMut<'_, K, V>, BTreeEntryMut<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (found, index_stack) = match self.get_entry(key) {
+            Ok(entry) => (true, entry.index_stack),
+            Err(entry) => (false, entry.index_stack),
+        };
+        let entry = BTreeEntryMut {
+            tree: self,
+            index_stack,
+        };
+        if found {
+            Ok(entry)
+        } else {
+            Err(entry)
+        }
+    }
+
+    /// Returns a mutable reference to the value of `key`, or `None` if it isn't in the tree.
+    ///
+    /// The descent moves the borrow down the path (`drop_get_mut` consumes the parent's
+    /// borrow), so the reference's lifetime is tied only to `&mut self`. Like any `&mut self`
+    /// borrow, it has to end before the tree is used again, even by `&self` methods like `len`.
     pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
     where
         K: Borrow<Q>,
@@ -2019,30 +2214,99 @@ impl<K: Ord, V> BTree<K, V> {
     }
 
     pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        self.insert_by(key, || value, true, |key, keys| search_keys(keys, key))
+            .1
+    }
+
+    /// Same as `insert`, but returns a reference to the inserted value instead of the replaced
+    /// entry.
+    pub fn insert_mut(&mut self, key: K, value: V) -> &mut V {
+        let (slot, _) = self.insert_by(key, || value, true, |key, keys| search_keys(keys, key));
+        unsafe { &mut *slot.as_ptr() }
+    }
+
+    /// Returns a mutable reference to the value of `key`, inserting `f()` first if it isn't in the
+    /// tree.
+    pub fn get_or_insert_with(&mut self, key: K, f: impl FnOnce() -> V) -> &mut V {
+        let (slot, _) = self.insert_by(key, f, false, |key, keys| search_keys(keys, key));
+        unsafe { &mut *slot.as_ptr() }
+    }
+
+    /// Returns a mutable reference to the value of `key`, inserting `V::default()` first if it
+    /// isn't in the tree.
+    pub fn entry_or_default(&mut self, key: K) -> &mut V
+    where
+        V: Default,
+    {
+        self.get_or_insert_with(key, V::default)
+    }
+
+    /// Inserts every pair of `iter`, replacing the values of the keys that are already in the
+    /// tree. Before every insertion the tree is topped up with chunks from `alloc_chunk` as long
+    /// as it needs a new chunk.
+    pub fn extend<I>(&mut self, iter: I, mut alloc_chunk: impl FnMut() -> &'static mut [u8])
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in iter {
+            while self.needs_new_chunk() {
+                self.add_chunk(alloc_chunk());
+            }
+            self.insert(key, value);
+        }
+    }
+
+    /// Inserts an entry at the position found by `search`, which is called with the inserted key
+    /// and the keys of each node on the way down. It returns `Ok(i)` to stop at the `i`th entry,
+    /// or `Err(i)` to descend to the `i`th child (or to insert at `i` in a leaf).
+    ///
+    /// If `search` stops at an entry, its value is replaced by `value()` if `replace` is true, and
+    /// kept otherwise. Returns the slot of the entry's value, which stays valid until the tree is
+    /// modified, and the replaced entry.
+    ///
+    /// The position must keep the keys sorted.
+    fn insert_by<F>(
+        &mut self,
+        key: K,
+        value: impl FnOnce() -> V,
+        replace: bool,
+        mut search: F,
+    ) -> (ptr::NonNull<V>, Option<(K, V)>)
+    where
+        F: FnMut(&K, &[K]) -> Result<usize, usize>,
+    {
+        /// Stops at the `i`th entry of `values`.
+        fn found<K, V>(
+            values: &mut [V],
+            i: usize,
+            key: K,
+            value: impl FnOnce() -> V,
+            replace: bool,
+        ) -> (ptr::NonNull<V>, Option<(K, V)>) {
+            let replaced = replace.then(|| (key, mem::replace(&mut values[i], value())));
+            (ptr::NonNull::from(&mut values[i]), replaced)
+        }
+
         self.len += 1;
         match &mut self.root {
             Child::Leaf(root) => {
-                let (overflow_k, overflow_value) = 'root_search_and_insert: loop {
-                    for (i, elem_k) in root.keys().iter().enumerate() {
-                        match key.cmp(elem_k) {
-                            Ordering::Less => {
-                                break 'root_search_and_insert root.insert(i, key, value)?;
-                            }
-                            Ordering::Equal => {
-                                self.len -= 1;
-                                return Some((key, mem::replace(&mut root.values_mut()[i], value)));
-                            }
-                            Ordering::Greater => {}
-                        }
+                let i = match search(&key, root.keys()) {
+                    Ok(i) => {
+                        self.len -= 1;
+                        return found(root.values_mut(), i, key, value, replace);
                     }
-
-                    break 'root_search_and_insert root.push(key, value)?;
+                    Err(i) => i,
+                };
+                let (overflow_k, overflow_value) = match root.insert(i, key, value()) {
+                    Some(overflow) => overflow,
+                    None => return (ptr::NonNull::from(&mut root.values_mut()[i]), None),
                 };
 
                 self.depth += 1;
 
                 let mut right = SlabBox::new(&mut self.leaf_alloc, NodeElements::new());
                 let (sep_k, sep_value) = root.split(overflow_k, overflow_value, right.as_mut());
+                let slot = split_slot(i, root.values_mut(), right.values_mut());
 
                 let left = mem::replace(root, right);
                 let new_root = Node::new(&mut self.node_alloc, Child::Leaf(left));
@@ -2050,36 +2314,30 @@ impl<K: Ord, V> BTree<K, V> {
                     .try_into_leaf()
                     .unwrap();
 
-                self.root
-                    .try_as_node_mut()
-                    .unwrap()
-                    .push(sep_k, sep_value, Child::Leaf(right))
+                let root = self.root.try_as_node_mut().unwrap();
+                root.push(sep_k, sep_value, Child::Leaf(right))
                     .assert_none();
 
-                return None;
+                (
+                    slot.unwrap_or_else(|| ptr::NonNull::from(&mut root.values_mut()[0])),
+                    None,
+                )
             }
             Child::Node(root) => {
                 let mut ref_stack = OnStackRefMutStack::<Node<K, V>, MAX_DEPTH>::new();
                 let mut children_indices_stack = StackVec::<usize, MAX_DEPTH>::new(); // ;
                 ref_stack.push_root(root.as_mut());
 
-                let (mut overflow_k, mut overflow_value) = 'search_and_insert: loop {
+                let ((mut overflow_k, mut overflow_value), inserted_idx) = 'search_and_insert: loop {
                     let node = ref_stack.peek_mut().unwrap();
 
-                    let mut child_idx = node.num_elements();
-                    for (i, elem_k) in node.keys().iter().enumerate() {
-                        match key.cmp(elem_k) {
-                            Ordering::Less => {
-                                child_idx = i;
-                                break;
-                            }
-                            Ordering::Equal => {
-                                self.len -= 1;
-                                return Some((key, mem::replace(&mut node.values_mut()[i], value)));
-                            }
-                            Ordering::Greater => {}
+                    let child_idx = match search(&key, node.keys()) {
+                        Ok(i) => {
+                            self.len -= 1;
+                            return found(node.values_mut(), i, key, value, replace);
                         }
-                    }
+                        Err(i) => i,
+                    };
 
                     children_indices_stack.push(child_idx).assert_none();
                     match node.children_mut() {
@@ -2090,54 +2348,29 @@ impl<K: Ord, V> BTree<K, V> {
                         ChildrenSliceMut::Leafs(leafs) => {
                             let leaf = leafs[child_idx].as_mut();
 
-                            for (i, elem_k) in leaf.keys().iter().enumerate() {
-                                match key.cmp(elem_k) {
-                                    Ordering::Less => {
-                                        break 'search_and_insert leaf.insert(i, key, value)?;
-                                    }
-                                    Ordering::Equal => {
-                                        self.len -= 1;
-                                        return Some((
-                                            key,
-                                            mem::replace(&mut leaf.values_mut()[i], value),
-                                        ));
-                                    }
-                                    Ordering::Greater => {}
+                            match search(&key, leaf.keys()) {
+                                Ok(i) => {
+                                    self.len -= 1;
+                                    return found(leaf.values_mut(), i, key, value, replace);
                                 }
+                                Err(i) => match leaf.insert(i, key, value()) {
+                                    Some(overflow) => break 'search_and_insert (overflow, i),
+                                    None => {
+                                        return (
+                                            ptr::NonNull::from(&mut leaf.values_mut()[i]),
+                                            None,
+                                        )
+                                    }
+                                },
                             }
-
-                            break 'search_and_insert leaf.push(key, value)?;
                         }
                     }
-                    // match ref_stack.try_push(|node| match node.children_mut() {
-                    //     ChildrenSliceMut::Nodes(children_nodes) => {
-                    //         Ok(children_nodes[child_idx].as_mut())
-                    //     }
-                    //     ChildrenSliceMut::Leafs(leafs) => Err(leafs[child_idx].as_mut()),
-                    // }) {
-                    //     Ok(check) => assert!(check),
-                    //     Err(leaf) => {
-                    //         for (i, elem_k) in leaf.keys().iter().enumerate() {
-                    //             match key.cmp(elem_k) {
-                    //                 Ordering::Less => {
-                    //                     break 'search_and_insert leaf.insert(i, key, value)?;
-                    //                 }
-                    //                 Ordering::Equal => {
-                    //                     self.len -= 1;
-                    //                     return Some((
-                    //                         key,
-                    //                         mem::replace(&mut leaf.values_mut()[i], value),
-                    //                     ));
-                    //                 }
-                    //                 Ordering::Greater => {}
-                    //             }
-                    //         }
-
-                    //         break 'search_and_insert leaf.push(key, value)?;
-                    //     }
-                    // }
                 };
                 let mut overflow_child;
+                // The slot of the inserted value, or `None` while it's the separator that's
+                // inserted to the node at the top of `ref_stack` at `sep_idx`.
+                let mut slot;
+                let mut sep_idx;
 
                 // Leaf Overflow
                 {
@@ -2149,13 +2382,23 @@ impl<K: Ord, V> BTree<K, V> {
                     let mut right = SlabBox::new(&mut self.leaf_alloc, NodeElements::new());
                     let (sep_k, sep_value) =
                         child.split(overflow_k, overflow_value, right.as_mut());
+                    slot = split_slot(inserted_idx, child.values_mut(), right.values_mut());
 
                     let (rightmost_k, rightmost_value, rightmost_child) =
-                        node.insert(child_idx, sep_k, sep_value, Child::Leaf(right))?;
+                        match node.insert(child_idx, sep_k, sep_value, Child::Leaf(right)) {
+                            Some(overflow) => overflow,
+                            None => {
+                                let slot = slot.unwrap_or_else(|| {
+                                    ptr::NonNull::from(&mut node.values_mut()[child_idx])
+                                });
+                                return (slot, None);
+                            }
+                        };
 
                     overflow_k = rightmost_k;
                     overflow_value = rightmost_value;
                     overflow_child = rightmost_child;
+                    sep_idx = child_idx;
                 }
 
                 loop {
@@ -2165,12 +2408,15 @@ impl<K: Ord, V> BTree<K, V> {
 
                             self.depth += 1;
 
-                            let (sep_k, sep_value, right) = root.split(
+                            let (sep_k, sep_value, mut right) = root.split(
                                 &mut self.node_alloc,
                                 overflow_k,
                                 overflow_value,
                                 overflow_child,
                             );
+                            if slot.is_none() {
+                                slot = split_slot(sep_idx, root.values_mut(), right.values_mut());
+                            }
 
                             let left = mem::replace(root, right);
 
@@ -2180,7 +2426,9 @@ impl<K: Ord, V> BTree<K, V> {
                             root.push(sep_k, sep_value, Child::Node(right))
                                 .assert_none();
 
-                            return None;
+                            let slot = slot
+                                .unwrap_or_else(|| ptr::NonNull::from(&mut root.values_mut()[0]));
+                            return (slot, None);
                         }
                         None => {
                             let node = ref_stack.peek_mut().unwrap();
@@ -2189,19 +2437,35 @@ impl<K: Ord, V> BTree<K, V> {
                             let child =
                                 node.children_mut().try_into_nodes().unwrap()[child_idx].as_mut();
 
-                            let (sep_k, sep_value, right) = child.split(
+                            let (sep_k, sep_value, mut right) = child.split(
                                 &mut self.node_alloc,
                                 overflow_k,
                                 overflow_value,
                                 overflow_child,
                             );
+                            if slot.is_none() {
+                                slot = split_slot(sep_idx, child.values_mut(), right.values_mut());
+                            }
 
-                            let (rightmost_k, rightmost_value, rightmost_child) =
-                                node.insert(child_idx, sep_k, sep_value, Child::Node(right))?;
+                            let (rightmost_k, rightmost_value, rightmost_child) = match node.insert(
+                                child_idx,
+                                sep_k,
+                                sep_value,
+                                Child::Node(right),
+                            ) {
+                                Some(overflow) => overflow,
+                                None => {
+                                    let slot = slot.unwrap_or_else(|| {
+                                        ptr::NonNull::from(&mut node.values_mut()[child_idx])
+                                    });
+                                    return (slot, None);
+                                }
+                            };
 
                             overflow_k = rightmost_k;
                             overflow_value = rightmost_value;
                             overflow_child = rightmost_child;
+                            sep_idx = child_idx;
                         }
                     }
                 }
@@ -2209,10 +2473,131 @@ impl<K: Ord, V> BTree<K, V> {
         }
     }
 
+    /// Returns the index path to the first entry that isn't before the start bound `bound`, in
+    /// the format of `BTreeEntryMut`'s index stack.
+    fn lower_bound_path<Q>(&self, bound: ops::Bound<&Q>) -> Option<StackVec<usize, MAX_DEPTH>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let before_bound = |k: &K| match bound {
+            ops::Bound::Included(bound) => k.borrow() < bound,
+            ops::Bound::Excluded(bound) => k.borrow() <= bound,
+            ops::Bound::Unbounded => false,
+        };
+
+        // The path to the best entry so far, and the path to the current child.
+        let mut best = None;
+        let mut path = StackVec::<usize, MAX_DEPTH>::new();
+        let mut child = self.root.as_ref();
+        loop {
+            let i = child.keys().partition_point(|k| before_bound(k));
+            if i < child.keys().len() {
+                let mut index_stack = path.clone();
+                index_stack.push(i).assert_none();
+                best = Some(index_stack);
+            }
+            match child {
+                ChildRef::Node(node) => {
+                    path.push(i).assert_none();
+                    child = node.children().get(i).unwrap();
+                }
+                ChildRef::Leaf(_) => return best,
+            }
+        }
+    }
+
+    /// Returns a cursor at the entry of the index path `index_stack`.
+    fn entry_at(&self, index_stack: StackVec<usize, MAX_DEPTH>) -> BTreeEntry<'_, K, V> {
+        let mut entry = BTreeEntry {
+            node_stack: StackVec::new(),
+            index_stack,
+            leaf: None,
+        };
+
+        let mut child = self.root.as_ref();
+        for &idx in &entry.index_stack[..entry.index_stack.len() - 1] {
+            let node = child.try_into_node().unwrap();
+            entry.node_stack.push(node).assert_none();
+            child = node.children().get(idx).unwrap();
+        }
+        match child {
+            ChildRef::Node(node) => entry.node_stack.push(node).assert_none(),
+            ChildRef::Leaf(leaf) => entry.leaf = Some(leaf),
+        }
+
+        entry
+    }
+
+    /// Returns a cursor at the first entry that isn't before the start bound `bound`.
+    fn lower_bound_mut<Q>(&mut self, bound: ops::Bound<&Q>) -> Option<BTreeEntryMut<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let index_stack = self.lower_bound_path(bound)?;
+        Some(BTreeEntryMut {
+            tree: self,
+            index_stack,
+        })
+    }
+
+    /// Returns an iterator over the entries whose keys are greater than or equal to `key`, in
+    /// order. Since a key is enough to resume from, a long walk can be paused by saving the next
+    /// key, without holding a borrow of the tree.
+    pub fn iter_from<Q>(&self, key: &Q) -> impl Iterator<Item = (&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut entry = self
+            .lower_bound_path(ops::Bound::Included(key))
+            .map(|index_stack| self.entry_at(index_stack));
+        iter::from_fn(move || {
+            let current = entry.as_mut()?;
+            let item = (current.key(), current.value());
+            if !current.next() {
+                entry = None;
+            }
+            Some(item)
+        })
+    }
+
+    /// Removes all the entries whose keys are in `range`, and returns how many were removed.
+    ///
+    /// Every removal starts from a single descent to the first key in the range, instead of
+    /// searching for each key.
+    pub fn remove_range<Q, R>(&mut self, range: R) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: ops::RangeBounds<Q>,
+    {
+        let mut removed = 0;
+        while let Some(entry) = self.lower_bound_mut(range.start_bound()) {
+            if !range.contains(entry.key().borrow()) {
+                break;
+            }
+            entry.remove_current();
+            removed += 1;
+        }
+        removed
+    }
+
     pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
     where
         K: Borrow<Q>,
         Q: Ord + ?Sized,
+    {
+        self.remove_by(|keys| search_keys(keys, key))
+    }
+
+    /// Removes the entry found by `search`, which is called with the keys of each node on the way
+    /// down. It returns `Ok(i)` if the entry is the `i`th entry, or `Err(i)` to descend to the
+    /// `i`th child. Returns `None` if `search` returns `Err` in a leaf.
+    fn remove_by<F>(&mut self, mut search: F) -> Option<(K, V)>
+    where
+        F: FnMut(&[K]) -> Result<usize, usize>,
     {
         fn resolve_underflow<K: Ord, V>(
             leaf_alloc: &mut SlabAllocator<NodeElements<K, V>>,
@@ -2315,22 +2700,13 @@ impl<K: Ord, V> BTree<K, V> {
         }
 
         match &mut self.root {
-            Child::Leaf(root) => {
-                for (i, elem_k) in root.keys().iter().enumerate() {
-                    match key.cmp(elem_k.borrow()) {
-                        Ordering::Less => {
-                            return None;
-                        }
-                        Ordering::Equal => {
-                            self.len -= 1;
-                            return Some(root.remove(i));
-                        }
-                        Ordering::Greater => {}
-                    }
+            Child::Leaf(root) => match search(root.keys()) {
+                Ok(i) => {
+                    self.len -= 1;
+                    Some(root.remove(i))
                 }
-
-                None
-            }
+                Err(_) => None,
+            },
             Child::Node(root) => {
                 let mut ref_stack = OnStackRefMutStack::<_, MAX_DEPTH>::new();
                 let mut children_indices_stack = StackVec::<_, MAX_DEPTH>::new(); // ;
@@ -2339,115 +2715,105 @@ impl<K: Ord, V> BTree<K, V> {
                 let (removed_k, removed_value) = 'search_and_remove: loop {
                     let node = ref_stack.peek_mut().unwrap();
 
-                    let mut child_idx = node.num_elements();
-                    for (i, elem_k) in node.keys().iter().enumerate() {
-                        match key.cmp(elem_k.borrow()) {
-                            Ordering::Less => {
-                                child_idx = i;
-                                break;
-                            }
-                            Ordering::Equal => {
-                                children_indices_stack.push(i).assert_none();
-
-                                let (replacement_k, replacement_value) = match node.children_mut() {
-                                    ChildrenSliceMut::Nodes(_) => {
-                                        assert!(ref_stack.push(|node| unsafe {
-                                            node.children_mut().try_into_nodes().unwrap_unchecked()
-                                                [i]
-                                                .as_mut()
-                                        }));
-                                        loop {
-                                            match ref_stack.peek_mut().unwrap().children_mut() {
-                                                ChildrenSliceMut::Nodes(_) => assert!(ref_stack
-                                                    .push(|node| unsafe {
-                                                        node.children_mut()
-                                                            .try_into_nodes()
-                                                            .unwrap_unchecked()
-                                                            .last_mut()
-                                                            .unwrap_unchecked()
-                                                            .as_mut()
-                                                    })),
-                                                ChildrenSliceMut::Leafs(leafs) => {
-                                                    break leafs.last_mut().unwrap().pop().unwrap();
-                                                }
+                    let child_idx = match search(node.keys()) {
+                        Ok(i) => {
+                            children_indices_stack.push(i).assert_none();
+
+                            let (replacement_k, replacement_value) = match node.children_mut() {
+                                ChildrenSliceMut::Nodes(_) => {
+                                    assert!(ref_stack.push(|node| unsafe {
+                                        node.children_mut().try_into_nodes().unwrap_unchecked()[i]
+                                            .as_mut()
+                                    }));
+                                    loop {
+                                        match ref_stack.peek_mut().unwrap().children_mut() {
+                                            ChildrenSliceMut::Nodes(_) => {
+                                                assert!(ref_stack.push(|node| unsafe {
+                                                    node.children_mut()
+                                                        .try_into_nodes()
+                                                        .unwrap_unchecked()
+                                                        .last_mut()
+                                                        .unwrap_unchecked()
+                                                        .as_mut()
+                                                }))
+                                            }
+                                            ChildrenSliceMut::Leafs(leafs) => {
+                                                break leafs.last_mut().unwrap().pop().unwrap();
                                             }
                                         }
                                     }
-                                    ChildrenSliceMut::Leafs(leafs) => leafs[i].pop().unwrap(),
-                                };
-                                // match ref_stack.try_push(|node| match node.children_mut() {
-                                //     ChildrenSliceMut::Nodes(nodes) => Ok(nodes[i].as_mut()),
-                                //     ChildrenSliceMut::Leafs(leafs) => Err(leafs[i].as_mut()),
-                                // }) {
-                                //     Ok(check) => {
-                                //         assert!(check);
-                                //         loop {
-                                //             match ref_stack.try_push(|node| {
-                                //                 match node.children_mut() {
-                                //                     ChildrenSliceMut::Nodes(nodes) => {
-                                //                         Ok(nodes.last_mut().unwrap().as_mut())
-                                //                     }
-                                //                     ChildrenSliceMut::Leafs(leafs) => {
-                                //                         Err(leafs.last_mut().unwrap().as_mut())
-                                //                     }
-                                //                 }
-                                //             }) {
-                                //                 Ok(success) => assert!(success),
-                                //                 Err(leaf) => {
-                                //                     break leaf.pop().unwrap();
-                                //                 }
-                                //             }
-                                //         }
-                                //     }
-                                //     Err(leaf) => leaf.pop().unwrap(),
-                                // };
-
-                                while children_indices_stack.len() < ref_stack.len() {
-                                    let node = ref_stack.peek_mut().unwrap();
+                                }
+                                ChildrenSliceMut::Leafs(leafs) => leafs[i].pop().unwrap(),
+                            };
+                            // match ref_stack.try_push(|node| match node.children_mut() {
+                            //     ChildrenSliceMut::Nodes(nodes) => Ok(nodes[i].as_mut()),
+                            //     ChildrenSliceMut::Leafs(leafs) => Err(leafs[i].as_mut()),
+                            // }) {
+                            //     Ok(check) => {
+                            //         assert!(check);
+                            //         loop {
+                            //             match ref_stack.try_push(|node| {
+                            //                 match node.children_mut() {
+                            //                     ChildrenSliceMut::Nodes(nodes) => {
+                            //                         Ok(nodes.last_mut().unwrap().as_mut())
+                            //                     }
+                            //                     ChildrenSliceMut::Leafs(leafs) => {
+                            //                         Err(leafs.last_mut().unwrap().as_mut())
+                            //                     }
+                            //                 }
+                            //             }) {
+                            //                 Ok(success) => assert!(success),
+                            //                 Err(leaf) => {
+                            //                     break leaf.pop().unwrap();
+                            //                 }
+                            //             }
+                            //         }
+                            //     }
+                            //     Err(leaf) => leaf.pop().unwrap(),
+                            // };
+
+                            while children_indices_stack.len() < ref_stack.len() {
+                                let node = ref_stack.peek_mut().unwrap();
 
-                                    if node
-                                        .children()
-                                        .get(node.num_elements())
-                                        .unwrap()
-                                        .num_elements()
-                                        < MIN_NUM_ELEMENTS
-                                    {
-                                        resolve_underflow(
-                                            &mut self.leaf_alloc,
-                                            &mut self.node_alloc,
-                                            node,
-                                            node.num_elements(),
-                                        );
+                                if node
+                                    .children()
+                                    .get(node.num_elements())
+                                    .unwrap()
+                                    .num_elements()
+                                    < MIN_NUM_ELEMENTS
+                                {
+                                    resolve_underflow(
+                                        &mut self.leaf_alloc,
+                                        &mut self.node_alloc,
+                                        node,
+                                        node.num_elements(),
+                                    );
+                                    ref_stack.pop().assert_none();
+                                } else {
+                                    while children_indices_stack.len() < ref_stack.len() {
                                         ref_stack.pop().assert_none();
-                                    } else {
-                                        while children_indices_stack.len() < ref_stack.len() {
-                                            ref_stack.pop().assert_none();
-                                        }
+                                    }
 
-                                        let node = ref_stack.peek_mut().unwrap();
-                                        let removed_k =
-                                            mem::replace(&mut node.keys_mut()[i], replacement_k);
-                                        let removed_value = mem::replace(
-                                            &mut node.values_mut()[i],
-                                            replacement_value,
-                                        );
+                                    let node = ref_stack.peek_mut().unwrap();
+                                    let removed_k =
+                                        mem::replace(&mut node.keys_mut()[i], replacement_k);
+                                    let removed_value =
+                                        mem::replace(&mut node.values_mut()[i], replacement_value);
 
-                                        self.len -= 1;
-                                        return Some((removed_k, removed_value));
-                                    }
+                                    self.len -= 1;
+                                    return Some((removed_k, removed_value));
                                 }
+                            }
 
-                                let node = ref_stack.peek_mut().unwrap();
-                                let removed_k =
-                                    mem::replace(&mut node.keys_mut()[i], replacement_k);
-                                let removed_value =
-                                    mem::replace(&mut node.values_mut()[i], replacement_value);
+                            let node = ref_stack.peek_mut().unwrap();
+                            let removed_k = mem::replace(&mut node.keys_mut()[i], replacement_k);
+                            let removed_value =
+                                mem::replace(&mut node.values_mut()[i], replacement_value);
 
-                                break 'search_and_remove (removed_k, removed_value);
-                            }
-                            Ordering::Greater => {}
+                            break 'search_and_remove (removed_k, removed_value);
                         }
-                    }
+                        Err(i) => i,
+                    };
 
                     children_indices_stack.push(child_idx).assert_none();
                     match node.children_mut() {
@@ -2458,23 +2824,17 @@ impl<K: Ord, V> BTree<K, V> {
                         ChildrenSliceMut::Leafs(leafs) => {
                             let leaf = leafs[child_idx].as_mut();
 
-                            for (i, elem_k) in leaf.keys().iter().enumerate() {
-                                match key.cmp(elem_k.borrow()) {
-                                    Ordering::Less => {
-                                        return None;
-                                    }
-                                    Ordering::Equal => {
-                                        if leaf.len() == MIN_NUM_ELEMENTS {
-                                            break 'search_and_remove leaf.remove(i);
-                                        } else {
-                                            self.len -= 1;
-                                            return Some(leaf.remove(i));
-                                        }
+                            match search(leaf.keys()) {
+                                Ok(i) => {
+                                    if leaf.len() == MIN_NUM_ELEMENTS {
+                                        break 'search_and_remove leaf.remove(i);
+                                    } else {
+                                        self.len -= 1;
+                                        return Some(leaf.remove(i));
                                     }
-                                    Ordering::Greater => {}
                                 }
+                                Err(_) => return None,
                             }
-                            return None;
                         }
                     }
                     // match ref_stack.try_push(|node| match node.children_mut() {
@@ -2535,7 +2895,7 @@ impl<K: Ord, V> BTree<K, V> {
         }
     }
 
-    pub fn iter(&self) -> BTreeIter<K, V> {
+    pub fn iter(&self) -> BTreeIter<'_, K, V> {
         let mut left = StackVec::new();
         left.push((self.root.as_ref(), 0)).assert_none();
         while let Some(&(ChildRef::Node(node), _)) = left.last() {
@@ -2559,7 +2919,7 @@ impl<K: Ord, V> BTree<K, V> {
         }
     }
 
-    pub fn iter_mut(&mut self) -> BTreeIterMut<K, V> {
+    pub fn iter_mut(&mut self) -> BTreeIterMut<'_, K, V> {
         unsafe {
             let mut left = StackVec::new();
             left.push((
@@ -2615,6 +2975,54 @@ impl<K: Ord, V> BTree<K, V> {
             }
         }
     }
+
+    /// Returns an iterator over the keys, in order.
+    #[inline]
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    /// Returns an iterator over the values, in the order of their keys.
+    #[inline]
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Returns a mutable iterator over the values, in the order of their keys.
+    #[inline]
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.iter_mut().map(|(_, value)| value)
+    }
+
+    /// Returns a mutable iterator over the values whose keys are in `range`, in the order of their
+    /// keys. It starts from a single descent to the start of the range.
+    pub fn range_values_mut<Q, R>(&mut self, range: R) -> impl Iterator<Item = &mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: ops::RangeBounds<Q>,
+    {
+        let mut entry = self.lower_bound_mut(range.start_bound());
+        iter::from_fn(move || {
+            let current = entry.as_mut()?;
+            let before_end = match range.end_bound() {
+                ops::Bound::Included(end) => current.key().borrow() <= end,
+                ops::Bound::Excluded(end) => current.key().borrow() < end,
+                ops::Bound::Unbounded => true,
+            };
+            if !before_end {
+                entry = None;
+                return None;
+            }
+
+            // Every value is yielded once, and the tree isn't changed while it's borrowed.
+            let value: *mut V = current.value_mut();
+            if !current.next() {
+                entry = None;
+            }
+            Some(unsafe { &mut *value })
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -2755,6 +3163,125 @@ impl<'a, K: Ord, V> BTreeEntry<'a, K, V> {
     }
 }
 
+/// A cursor like `BTreeEntry`, that can also edit the tree at its position. It keeps only the
+/// path of indices to its entry, and walks it again on every access.
+///
+/// The edits may restructure the tree, so they consume the cursor: no path of indices stays valid
+/// after them. To go on from where an edit was, look its key up again with `get_entry_mut`.
+#[derive(Debug)]
+pub struct BTreeEntryMut<'a, K: Ord, V> {
+    tree: &'a mut BTree<K, V>,
+    index_stack: StackVec<usize, MAX_DEPTH>,
+}
+
+impl<'a, K: Ord, V> BTreeEntryMut<'a, K, V> {
+    /// Returns an immutable cursor at the same entry.
+    pub fn as_entry(&self) -> BTreeEntry<'_, K, V> {
+        self.tree.entry_at(self.index_stack.clone())
+    }
+
+    pub fn key(&self) -> &K {
+        self.as_entry().key()
+    }
+
+    pub fn value(&self) -> &V {
+        self.as_entry().value()
+    }
+
+    pub fn value_mut(&mut self) -> &mut V {
+        let (&idx, path) = self.index_stack.split_last().unwrap();
+        let mut child = self.tree.root.as_mut();
+        for &i in path {
+            child = child
+                .try_into_node()
+                .unwrap()
+                .children_mut()
+                .drop_get_mut(i)
+                .unwrap();
+        }
+        match child {
+            ChildRefMut::Node(node) => &mut node.values_mut()[idx],
+            ChildRefMut::Leaf(leaf) => &mut leaf.values_mut()[idx],
+        }
+    }
+
+    /// Returns `true` if successfully moved to the next entry, `false` otherwise (you're on the last entry).
+    pub fn next(&mut self) -> bool {
+        let (moved, index_stack) = {
+            let mut entry = self.as_entry();
+            (entry.next(), entry.index_stack)
+        };
+        self.index_stack = index_stack;
+        moved
+    }
+
+    /// Returns `true` if successfully moved to the previous entry, `false` otherwise (you're on the first entry).
+    pub fn prev(&mut self) -> bool {
+        let (moved, index_stack) = {
+            let mut entry = self.as_entry();
+            (entry.prev(), entry.index_stack)
+        };
+        self.index_stack = index_stack;
+        moved
+    }
+
+    /// Removes the current entry and returns it, without searching for its key.
+    ///
+    /// The cursor is consumed. `get_entry_mut` with the removed key returns an `Err` cursor next to
+    /// where it was, on either side of it.
+    pub fn remove_current(self) -> (K, V) {
+        let Self { tree, index_stack } = self;
+
+        let mut depth = 0;
+        tree.remove_by(|_| {
+            let idx = index_stack[depth];
+            depth += 1;
+            if depth == index_stack.len() {
+                Ok(idx)
+            } else {
+                Err(idx)
+            }
+        })
+        .unwrap()
+    }
+
+    /// Inserts an entry right after the current entry, without searching for its position.
+    /// `key` must be greater than the current key, and smaller than the next key.
+    ///
+    /// The cursor is consumed, `get_entry_mut` with `key` returns an `Ok` cursor at the new entry.
+    pub fn insert_after(self, key: K, value: V) {
+        debug_assert!(*self.key() < key);
+        debug_assert!({
+            let mut next = self.as_entry();
+            !next.next() || key < *next.key()
+        });
+
+        let Self { tree, index_stack } = self;
+
+        // The entry after an entry in a leaf is next to it, and the entry after an entry in a node
+        // is the first entry of the leftmost leaf of its right child.
+        let mut depth = 0;
+        tree.insert_by(
+            key,
+            || value,
+            true,
+            |_, _| {
+                let idx = if depth + 1 < index_stack.len() {
+                    index_stack[depth]
+                } else if depth + 1 == index_stack.len() {
+                    index_stack[depth] + 1
+                } else {
+                    0
+                };
+                depth += 1;
+                Err(idx)
+            },
+        )
+        .1
+        .assert_none();
+    }
+}
+
 // impl<K: Ord, V> Default for BTree<K, V> {
 //     fn default() -> Self {
 //         Self::new()
@@ -2788,9 +3315,10 @@ impl<'a, K: Ord, V> Iterator for BTreeIter<'a, K, V> {
                 }
                 ChildRef::Leaf(_) => {
                     while let Some(&(child, elem_idx)) = self.left.last() {
-                        if child.num_elements() <= elem_idx {
-                            self.left.pop();
+                        if elem_idx < child.num_elements() {
+                            break;
                         }
+                        self.left.pop();
                     }
                 }
             }
@@ -2827,9 +3355,10 @@ impl<'a, K: Ord, V> DoubleEndedIterator for BTreeIter<'a, K, V> {
                 }
                 ChildRef::Leaf(_) => {
                     while let Some(&(_, elem_idx)) = self.right.last() {
-                        if elem_idx == 0 {
-                            self.right.pop();
+                        if elem_idx != 0 {
+                            break;
                         }
+                        self.right.pop();
                     }
                 }
             }
@@ -2863,12 +3392,13 @@ impl<'a, K: Ord, V> Iterator for BTreeIterMut<'a, K, V> {
             self.len -= 1;
             let (child, elem_idx) = self.left.last_mut().unwrap();
 
+            let item_idx = *elem_idx;
             *elem_idx += 1;
             match *child {
                 ChildPtrMut::Node(node) => unsafe {
                     let node = &mut *node;
                     let (keys, values, _) = node.get_all_mut();
-                    let item = (&keys[*elem_idx], &mut values[*elem_idx]);
+                    let item = (&keys[item_idx], &mut values[item_idx]);
 
                     while let Some(&(ChildPtrMut::Node(node), child_idx)) = self.left.last() {
                         self.left
@@ -2890,16 +3420,18 @@ impl<'a, K: Ord, V> Iterator for BTreeIterMut<'a, K, V> {
                 ChildPtrMut::Leaf(leaf) => unsafe {
                     let leaf = &mut *leaf;
                     let (keys, values) = leaf.get_all_mut();
-                    let item = (&keys[*elem_idx], &mut values[*elem_idx]);
+                    let item = (&keys[item_idx], &mut values[item_idx]);
 
                     while let Some(&(ref child, elem_idx)) = self.left.last() {
-                        if match child {
-                            ChildPtrMut::Leaf(leaf) => (**leaf).len(),
-                            ChildPtrMut::Node(node) => (**node).num_elements(),
-                        } <= elem_idx
+                        if elem_idx
+                            < match child {
+                                ChildPtrMut::Leaf(leaf) => (**leaf).len(),
+                                ChildPtrMut::Node(node) => (**node).num_elements(),
+                            }
                         {
-                            self.left.pop();
+                            break;
                         }
+                        self.left.pop();
                     }
                     Some(item)
                 },
@@ -2953,9 +3485,10 @@ impl<'a, K: Ord, V> DoubleEndedIterator for BTreeIterMut<'a, K, V> {
                     let item = (&keys[*elem_idx], &mut values[*elem_idx]);
 
                     while let Some(&(_, elem_idx)) = self.right.last() {
-                        if elem_idx == 0 {
-                            self.right.pop();
+                        if elem_idx != 0 {
+                            break;
                         }
+                        self.right.pop();
                     }
                     Some(item)
                 },
@@ -2971,3 +3504,627 @@ impl<'a, K: Ord, V> ExactSizeIterator for BTreeIterMut<'a, K, V> {
         self.len
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Returns `n` leaked 2MiB chunks, one after the other in memory.
+    fn adjacent_chunks(n: usize) -> Vec<&'static mut [u8]> {
+        std::vec![0; n * 0x200000]
+            .leak()
+            .chunks_exact_mut(0x200000)
+            .collect()
+    }
+
+    fn chunk_range(chunk: &[u8]) -> ops::Range<usize> {
+        chunk.as_ptr() as usize..chunk.as_ptr() as usize + chunk.len()
+    }
+
+    #[test]
+    fn reclaims_chunks_after_removing_everything() {
+        const N: u64 = 100_000;
+
+        let mut chunks = adjacent_chunks(32).into_iter();
+        let first_chunk = chunks.next().unwrap();
+        let mut added = std::vec![chunk_range(first_chunk)];
+        let mut tree = BTree::new(first_chunk);
+        for i in 0..N {
+            while tree.needs_new_chunk() {
+                let chunk = chunks.next().unwrap();
+                added.push(chunk_range(chunk));
+                tree.add_chunk(chunk);
+            }
+            assert!(tree.insert(i, i).is_none());
+        }
+        assert!(2 < added.len());
+
+        for i in 0..N {
+            assert_eq!(tree.remove(&i), Some((i, i)));
+        }
+        assert_eq!(tree.len(), 0);
+
+        // The chunks are adjacent, but only the parts of a split chunk are reclaimed together.
+        let mut reclaimed = Vec::new();
+        while let Some(chunk) = tree.try_reclaim_chunk() {
+            let range = chunk_range(chunk);
+            assert!(added.contains(&range), "{:x?} wasn't added", range);
+            assert!(!reclaimed.contains(&range));
+            reclaimed.push(range);
+        }
+        assert!(!reclaimed.is_empty());
+        assert!(!tree.needs_new_chunk());
+
+        assert!(tree.insert(N, N).is_none());
+        assert_eq!(tree.get(&N), Some(&N));
+        mem::forget(tree);
+    }
+
+    /// A key that isn't `Clone`.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Key(u64);
+
+    /// Asserts that `tree` is a valid B-tree: the keys are strictly ascending, every node but the
+    /// root has between `MIN_NUM_ELEMENTS` and `MAX_NUM_ELEMENTS` entries and one more child, all
+    /// the leaves are at `depth()`, and there are `len()` entries.
+    fn assert_invariants<K: Ord + fmt::Debug, V>(tree: &BTree<K, V>) {
+        /// Checks the subtree of `child` at `depth`, whose keys are between `lower` and `upper`,
+        /// and returns its number of entries.
+        fn check<'a, K: Ord + fmt::Debug, V>(
+            tree: &BTree<K, V>,
+            child: ChildRef<'a, K, V>,
+            depth: usize,
+            lower: Option<&'a K>,
+            upper: Option<&'a K>,
+        ) -> usize {
+            let keys = match child {
+                ChildRef::Node(node) => node.keys(),
+                ChildRef::Leaf(leaf) => leaf.keys(),
+            };
+            if depth != 1 {
+                assert!(
+                    (MIN_NUM_ELEMENTS..=MAX_NUM_ELEMENTS).contains(&keys.len()),
+                    "{} entries at depth {}",
+                    keys.len(),
+                    depth
+                );
+            }
+            assert!(
+                lower
+                    .into_iter()
+                    .chain(keys)
+                    .chain(upper)
+                    .zip(lower.into_iter().chain(keys).chain(upper).skip(1))
+                    .all(|(a, b)| a < b),
+                "{:?} aren't between {:?} and {:?}",
+                keys,
+                lower,
+                upper
+            );
+
+            match child {
+                ChildRef::Leaf(_) => {
+                    assert_eq!(depth, tree.depth());
+                    keys.len()
+                }
+                ChildRef::Node(node) => {
+                    assert_eq!(node.num_children(), keys.len() + 1);
+                    let bound = |i: usize| keys.get(i);
+                    let children: Vec<_> = match node.children() {
+                        ChildrenSlice::Nodes(nodes) => {
+                            nodes.iter().map(|node| ChildRef::Node(node)).collect()
+                        }
+                        ChildrenSlice::Leafs(leafs) => {
+                            leafs.iter().map(|leaf| ChildRef::Leaf(leaf)).collect()
+                        }
+                    };
+                    keys.len()
+                        + children
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, child)| {
+                                let lower = if i == 0 { lower } else { bound(i - 1) };
+                                let upper = bound(i).or(upper);
+                                check(tree, child, depth + 1, lower, upper)
+                            })
+                            .sum::<usize>()
+                }
+            }
+        }
+
+        assert_eq!(check(tree, tree.root.as_ref(), 1, None, None), tree.len());
+    }
+
+    fn grow<K: Ord, V>(tree: &mut BTree<K, V>) {
+        while tree.needs_new_chunk() {
+            tree.add_chunk(std::vec![0; 0x200000].leak());
+        }
+    }
+
+    /// The keys `0..n` ascending, descending, and shuffled, so the insertions hit every position
+    /// of the splits.
+    fn orders(n: u64) -> [Vec<u64>; 3] {
+        [
+            (0..n).collect(),
+            (0..n).rev().collect(),
+            (0..n).map(|i| i * 7919 % n).collect(),
+        ]
+    }
+
+    #[test]
+    fn insert_mut_returns_the_inserted_value() {
+        const N: u64 = 5000;
+
+        for keys in orders(N) {
+            let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+            for &k in &keys {
+                grow(&mut tree);
+                let value = tree.insert_mut(Key(k), 0);
+                assert_eq!(*value, 0);
+                *value = k + 1;
+            }
+            for k in 0..N {
+                assert_eq!(tree.get(&Key(k)), Some(&(k + 1)));
+            }
+
+            // Replacing a value returns its slot too.
+            *tree.insert_mut(Key(N / 2), 0) += 1;
+            assert_eq!(tree.get(&Key(N / 2)), Some(&1));
+            assert_eq!(tree.len(), N as usize);
+            mem::forget(tree);
+        }
+    }
+
+    #[test]
+    fn get_or_insert_with_inserts_only_missing_keys() {
+        const N: u64 = 5000;
+
+        for keys in orders(N) {
+            let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+            let mut inserted = 0;
+            for &k in keys.iter().chain(keys.iter().step_by(3)) {
+                grow(&mut tree);
+                *tree.get_or_insert_with(Key(k), || {
+                    inserted += 1;
+                    0
+                }) += k;
+            }
+            assert_eq!(inserted, N);
+            for k in 0..N {
+                let expected = if keys.iter().position(|&key| key == k).unwrap() % 3 == 0 {
+                    2 * k
+                } else {
+                    k
+                };
+                assert_eq!(tree.get(&Key(k)), Some(&expected));
+            }
+            mem::forget(tree);
+        }
+    }
+
+    #[test]
+    fn range_values_mut_visits_only_the_range() {
+        const N: u64 = 5000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        for k in (0..N).map(|i| i * 7919 % N) {
+            grow(&mut tree);
+            tree.insert(2 * k, 0);
+        }
+
+        for value in tree.range_values_mut(1001..=3000) {
+            *value += 1;
+        }
+        for value in tree.range_values_mut((ops::Bound::Excluded(3000), ops::Bound::Excluded(4000)))
+        {
+            *value += 2;
+        }
+        assert_eq!(tree.range_values_mut(9999..).count(), 0);
+        assert_eq!(tree.range_values_mut(..).count(), N as usize);
+        assert_eq!(tree.range_values_mut(4001..4001).count(), 0);
+        for value in tree.range_values_mut(2 * N - 2..) {
+            *value += 4;
+        }
+
+        for k in 0..N {
+            let expected = match 2 * k {
+                1001..=3000 => 1,
+                3001..=3999 => 2,
+                key if key == 2 * N - 2 => 4,
+                _ => 0,
+            };
+            assert_eq!(tree.get(&(2 * k)), Some(&expected), "key {}", 2 * k);
+        }
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn iterators_visit_every_entry_in_order() {
+        for n in [0, 1, 2, B as u64, 5000] {
+            let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+            for k in (0..n).map(|i| i * 7919 % n.max(1)) {
+                grow(&mut tree);
+                tree.insert(k, k);
+            }
+
+            assert!(tree
+                .iter()
+                .map(|(&k, &v)| (k, v))
+                .eq((0..n).map(|k| (k, k))));
+            assert!(tree.iter().rev().map(|(&k, _)| k).eq((0..n).rev()));
+            for (&k, value) in tree.iter_mut() {
+                *value += k;
+            }
+            assert!(tree
+                .iter_mut()
+                .rev()
+                .map(|(&k, &mut v)| (k, v))
+                .eq((0..n).rev().map(|k| (k, 2 * k))));
+            mem::forget(tree);
+        }
+    }
+
+    #[test]
+    fn remove_current_mid_iteration() {
+        const N: u64 = 3000;
+
+        for keys in orders(N) {
+            let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+            for &k in &keys {
+                grow(&mut tree);
+                tree.insert(k, k);
+            }
+
+            // Walks the tree with a cursor and removes the multiples of 3 through it.
+            let mut entry = tree.get_entry_mut(&0).unwrap();
+            let mut len = N as usize;
+            loop {
+                let key = *entry.key();
+                if key % 3 != 0 {
+                    if !entry.next() {
+                        break;
+                    }
+                    continue;
+                }
+
+                assert_eq!(entry.remove_current(), (key, key));
+                len -= 1;
+                assert_eq!(tree.len(), len);
+                assert_invariants(&tree);
+
+                // The cursor is gone, look the removed key up to go on.
+                entry = match tree.get_entry_mut(&key) {
+                    Ok(_) => panic!("{} wasn't removed", key),
+                    Err(entry) => entry,
+                };
+                if *entry.key() < key && !entry.next() {
+                    break;
+                }
+            }
+
+            assert_eq!(tree.len(), (N - N.div_ceil(3)) as usize);
+            assert!(tree
+                .iter()
+                .map(|(&k, _)| k)
+                .eq((0..N).filter(|k| k % 3 != 0)));
+            mem::forget(tree);
+        }
+    }
+
+    #[test]
+    fn insert_after_fills_the_gaps() {
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        for k in (0..2000).map(|i| 2 * i) {
+            grow(&mut tree);
+            tree.insert(k, k);
+        }
+
+        for k in (0..1999).map(|i| 2 * i) {
+            grow(&mut tree);
+            tree.get_entry_mut(&k).unwrap().insert_after(k + 1, k + 1);
+            assert_eq!(
+                tree.get_entry_mut(&(k + 1)).ok().map(|e| *e.value()),
+                Some(k + 1)
+            );
+        }
+        assert_invariants(&tree);
+        assert_eq!(tree.len(), 3999);
+        assert!(tree
+            .iter()
+            .map(|(&k, &v)| (k, v))
+            .eq((0..3999).map(|k| (k, k))));
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn get_entry_finds_every_key() {
+        const N: u64 = 2000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        for k in (0..N).map(|i| 2 * i) {
+            grow(&mut tree);
+            tree.insert(k, k);
+        }
+        assert!(2 < tree.depth());
+
+        for k in 0..2 * N {
+            match tree.get_entry(&k) {
+                Ok(entry) => {
+                    assert_eq!(k % 2, 0);
+                    assert_eq!((*entry.key(), *entry.value()), (k, k));
+                }
+                Err(entry) => {
+                    assert_eq!(k % 2, 1);
+                    assert!(
+                        entry.key().abs_diff(k) == 1,
+                        "{} is far from {}",
+                        entry.key(),
+                        k
+                    );
+                }
+            }
+        }
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn get_mut_borrow_ends_before_len() {
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        for k in 0..1000u64 {
+            grow(&mut tree);
+            tree.insert(k, k);
+        }
+
+        let value = tree.get_mut(&500).unwrap();
+        *value += 1;
+        assert_eq!(tree.len(), 1000);
+        assert_eq!(tree.get(&500), Some(&501));
+        assert!(tree.get_mut(&1000).is_none());
+        assert_eq!(tree.len(), 1000);
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn max_depth_bounds_every_tree() {
+        // A tree one level deeper than `MAX_DEPTH` would need more than `usize::MAX` entries, but
+        // a tree of depth `MAX_DEPTH` wouldn't.
+        assert!(B.pow(MAX_DEPTH as u32 - 1).checked_mul(2 * B).is_none());
+        assert!(B.pow(MAX_DEPTH as u32 - 2).checked_mul(2 * B).is_some());
+    }
+
+    #[test]
+    fn deep_tree_fits_the_path_stacks() {
+        const N: u64 = 300_000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        for k in (0..N).map(|i| i * 7919 % N) {
+            grow(&mut tree);
+            assert!(tree.insert(k, k).is_none());
+        }
+        assert!(5 < tree.depth(), "depth {}", tree.depth());
+        assert_invariants(&tree);
+
+        for k in (0..N).filter(|k| k % 4 != 0) {
+            assert_eq!(tree.remove(&k), Some((k, k)));
+        }
+        assert_eq!(tree.len(), (N / 4) as usize);
+        assert_invariants(&tree);
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn chunks_needed_counts_the_missing_chunks() {
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        assert_eq!(tree.chunks_needed(), 0);
+
+        let mut k = 0u64;
+        while !tree.needs_new_chunk() {
+            tree.insert(k, k);
+            k += 1;
+        }
+        for _ in 0..100 {
+            tree.insert(k, k);
+            k += 1;
+        }
+        let needed = tree.chunks_needed();
+        assert!(0 < needed);
+
+        for _ in 0..needed {
+            tree.add_chunk(std::vec![0; 0x200000].leak());
+        }
+        assert_eq!(tree.chunks_needed(), 0);
+        assert!(!tree.needs_new_chunk());
+        assert_eq!(tree.len(), k as usize);
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn capacity_shrinks_with_inserts_and_grows_with_chunks() {
+        const N: u64 = 100_000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        let mut added = 0;
+        let mut capacity = tree.capacity();
+        assert!(0 < capacity);
+        for k in (0..N).map(|i| i * 7919 % N) {
+            if tree.capacity() == 0 {
+                tree.add_chunk(std::vec![0; 0x200000].leak());
+                added += 1;
+                assert!(capacity < tree.capacity());
+                capacity = tree.capacity();
+            }
+            tree.insert(k, k);
+            assert!(tree.capacity() <= capacity);
+            capacity = tree.capacity();
+        }
+        assert!(0 < added);
+        assert_eq!(tree.len(), N as usize);
+        assert_invariants(&tree);
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn ceil_and_floor_entry_at_between_and_outside_keys() {
+        const N: u64 = 3000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        assert_eq!(tree.ceil_entry(&0), None);
+        assert_eq!(tree.floor_entry(&0), None);
+        for k in (0..N).map(|i| i * 7919 % N) {
+            grow(&mut tree);
+            tree.insert(10 * k + 10, k);
+        }
+
+        for k in 0..N {
+            let key = 10 * k + 10;
+            assert_eq!(tree.ceil_entry(&key), Some((&key, &k)));
+            assert_eq!(tree.floor_entry(&key), Some((&key, &k)));
+            assert_eq!(tree.ceil_entry(&(key - 5)), Some((&key, &k)));
+            assert_eq!(tree.floor_entry(&(key + 5)), Some((&key, &k)));
+        }
+        assert_eq!(tree.floor_entry(&9), None);
+        assert_eq!(tree.ceil_entry(&0), Some((&10, &0)));
+        assert_eq!(tree.ceil_entry(&(10 * N + 1)), None);
+        assert_eq!(tree.floor_entry(&u64::MAX), Some((&(10 * N), &(N - 1))));
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn remove_range_removes_only_the_range() {
+        const N: u64 = 5000;
+
+        for keys in orders(N) {
+            let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+            for &k in &keys {
+                grow(&mut tree);
+                tree.insert(k, k);
+            }
+
+            assert_eq!(tree.remove_range(1000..=1999), 1000);
+            assert_eq!(tree.len(), 4000);
+            assert_invariants(&tree);
+            assert_eq!(tree.remove_range(3000..4000), 1000);
+            assert_eq!(tree.len(), 3000);
+            assert_invariants(&tree);
+            assert_eq!(
+                tree.remove_range((ops::Bound::Excluded(4500), ops::Bound::Unbounded)),
+                499
+            );
+            assert_invariants(&tree);
+            assert_eq!(tree.remove_range(1000..2000), 0);
+            assert_eq!(tree.remove_range(4500..4500), 0);
+
+            let left =
+                |k: &u64| !(1000..2000).contains(k) && !(3000..4000).contains(k) && *k <= 4500;
+            assert_eq!(tree.len(), (0..N).filter(left).count());
+            assert!(tree.keys().copied().eq((0..N).filter(left)));
+
+            let len = tree.len();
+            assert_eq!(tree.remove_range(..), len);
+            assert_eq!(tree.len(), 0);
+            assert_invariants(&tree);
+            mem::forget(tree);
+        }
+    }
+
+    #[test]
+    fn iter_from_yields_the_tail_of_iter() {
+        const N: u64 = 3000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        assert_eq!(tree.iter_from(&0).count(), 0);
+        for k in (0..N).map(|i| i * 7919 % N) {
+            grow(&mut tree);
+            tree.insert(2 * k, k);
+        }
+
+        for key in 0..2 * N + 1 {
+            let tail = tree.iter().skip_while(|(&k, _)| k < key);
+            assert!(tree.iter_from(&key).eq(tail), "from {}", key);
+        }
+
+        // Resuming from the saved next key walks the whole tree in pages.
+        let mut resumed = Vec::new();
+        let mut next = Some(0);
+        while let Some(key) = next {
+            resumed.extend(tree.iter_from(&key).take(100).map(|(&k, _)| k));
+            next = tree
+                .iter_from(&(resumed.last().unwrap() + 1))
+                .next()
+                .map(|(&k, _)| k);
+        }
+        assert!(resumed.into_iter().eq(tree.keys().copied()));
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn extend_with_sorted_and_shuffled_pairs() {
+        const N: u64 = 50_000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        let mut added = 0;
+        let mut alloc_chunk = || {
+            added += 1;
+            std::vec![0; 0x200000].leak()
+        };
+        tree.extend((0..N).map(|k| (2 * k, k)), &mut alloc_chunk);
+        assert_eq!(tree.len(), N as usize);
+        assert_invariants(&tree);
+
+        // The odd keys are new, and the even ones replace the values.
+        tree.extend(
+            (0..2 * N).map(|i| (i * 7919 % (2 * N), 0)),
+            &mut alloc_chunk,
+        );
+        assert_eq!(tree.len(), 2 * N as usize);
+        assert_invariants(&tree);
+        assert!(tree
+            .iter()
+            .map(|(&k, &v)| (k, v))
+            .eq((0..2 * N).map(|k| (k, 0))));
+        assert!(0 < added);
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn entry_or_default_returns_the_same_value() {
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        for k in 0..1000u64 {
+            grow(&mut tree);
+            *tree.entry_or_default(k % 10) += 1;
+        }
+        assert_eq!(tree.len(), 10);
+
+        let first: *mut u64 = tree.entry_or_default(3);
+        assert_eq!(unsafe { *first }, 100);
+        *tree.entry_or_default(3) += 1;
+        let second: *mut u64 = tree.entry_or_default(3);
+        assert_eq!(first, second);
+        assert_eq!(tree.get(&3), Some(&101));
+        assert_eq!(tree.len(), 10);
+        mem::forget(tree);
+    }
+
+    #[test]
+    fn keys_and_values_in_order() {
+        const N: u64 = 3000;
+
+        let mut tree = BTree::new(std::vec![0; 0x200000].leak());
+        for k in (0..N).map(|i| i * 7919 % N) {
+            grow(&mut tree);
+            tree.insert(k, 2 * k);
+        }
+
+        assert!(tree.keys().copied().eq(0..N));
+        assert!(tree.values().copied().eq((0..N).map(|k| 2 * k)));
+        for (i, value) in tree.values_mut().enumerate() {
+            *value += i as u64;
+        }
+        assert!(tree.values().copied().eq((0..N).map(|k| 3 * k)));
+        for k in 0..N {
+            assert_eq!(tree.get(&k), Some(&(3 * k)));
+        }
+        mem::forget(tree);
+    }
+}
@@ -1,47 +1,105 @@
 use core::ops::Range;
-use multiboot2::{MemoryArea, MemoryMapTag};
+use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size2MiB};
 use x86_64::PhysAddr;
 
-/// A very simple frame allocator, it can't deallocate any frames.
+/// A very simple frame allocator, it can't deallocate any frames except for rolling back the last
+/// one with `dealloc_last`.
 /// It will be used for setup of the main frame allocator.
 #[derive(Debug)]
 pub struct BumpAllocator<'a, const N: usize> {
     current_frame: usize,
+    /// The frame the last allocation returned, if it was a single frame that wasn't rolled back.
+    last_frame: Option<usize>,
     taken_areas: [Range<usize>; N],
-    current_area: Option<&'a MemoryArea>,
+    current_area: Option<&'a Range<usize>>,
     memory_area_index: usize,
-    memory_map_tag: &'a MemoryMapTag,
+    memory_areas: &'a [Range<usize>],
 }
 
 impl<'a, const N: usize> BumpAllocator<'a, N> {
-    /// Create a new BasicFrameAllocator. Taken areas are addresses that are taken by either the
-    /// kernel or the Multiboot2 information structure.
-    pub fn new(taken_areas: [Range<usize>; N], memory_map_tag: &'a MemoryMapTag) -> Self {
+    /// Create a new BumpAllocator over the usable `memory_areas`, in the memory map's order, like
+    /// `MemorySurvey::usable`. Taken areas are addresses that are taken by either the kernel or
+    /// the Multiboot2 information structure.
+    pub fn new(taken_areas: [Range<usize>; N], memory_areas: &'a [Range<usize>]) -> Self {
         Self {
             current_frame: 0x200000,
-            current_area: memory_map_tag.memory_areas().next(),
+            last_frame: None,
+            current_area: memory_areas.first(),
             memory_area_index: 0,
-            memory_map_tag,
+            memory_areas,
             taken_areas,
         }
     }
+
+    /// Allocates `num` physically contiguous frames from a single memory area, avoiding the taken
+    /// areas. Returns `None` if there's no such run left, it never panics, even if `num` is too
+    /// large for the address space.
+    ///
+    /// Like every allocation of this allocator, the frames skipped while searching for the run
+    /// are lost.
+    pub fn allocate_contiguous(&mut self, num: usize) -> Option<PhysFrameRange<Size2MiB>> {
+        loop {
+            let current_area = self.current_area?;
+
+            if self.current_frame < current_area.start {
+                self.current_frame = current_area.start + 0x1fffff & !0x1fffff;
+            }
+
+            let run_end = num
+                .checked_mul(0x200000)
+                .and_then(|size| self.current_frame.checked_add(size))?;
+            if current_area.end < run_end {
+                self.memory_area_index += 1;
+                self.current_area = self.memory_areas.get(self.memory_area_index);
+                continue;
+            }
+            if let Some(area) = self
+                .taken_areas
+                .iter()
+                .find(|area| area.start < run_end && self.current_frame < area.end)
+            {
+                self.current_frame = area.end + 0x1fffff & !0x1fffff;
+                continue;
+            }
+
+            let start =
+                PhysFrame::from_start_address(PhysAddr::new(self.current_frame as _)).unwrap();
+            self.current_frame = run_end;
+            self.last_frame = None;
+
+            return Some(PhysFrame::range(start, start + num as u64));
+        }
+    }
+
+    /// Rolls back the last allocation, so the next `allocate_frame` returns the same frame again.
+    /// Returns `false` and does nothing if it can't be rolled back.
+    ///
+    /// Only the frame of the last `allocate_frame` can be rolled back, and only once: the rollbacks
+    /// are strictly LIFO with a depth of one. The frames skipped before it stay lost, and runs of
+    /// `allocate_contiguous` can't be rolled back at all.
+    pub fn dealloc_last(&mut self) -> bool {
+        match self.last_frame.take() {
+            Some(frame) => {
+                self.current_frame = frame;
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 unsafe impl<'a, const N: usize> FrameAllocator<Size2MiB> for BumpAllocator<'a, N> {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
         let current_area = self.current_area?;
 
-        if self.current_frame < current_area.start_address() as usize {
-            self.current_frame = current_area.start_address() as usize + 0x1fffff & !0x1fffff;
+        if self.current_frame < current_area.start {
+            self.current_frame = current_area.start + 0x1fffff & !0x1fffff;
         }
 
-        if (current_area.end_address() as usize) < self.current_frame + 0x200000 {
+        if current_area.end < self.current_frame + 0x200000 {
             self.memory_area_index += 1;
-            self.current_area = self
-                .memory_map_tag
-                .memory_areas()
-                .nth(self.memory_area_index);
+            self.current_area = self.memory_areas.get(self.memory_area_index);
             return self.allocate_frame();
         }
         for area in &self.taken_areas {
@@ -52,8 +110,78 @@ unsafe impl<'a, const N: usize> FrameAllocator<Size2MiB> for BumpAllocator<'a, N
         }
         let frame = PhysFrame::from_start_address(PhysAddr::new(self.current_frame as _)).unwrap();
 
+        self.last_frame = Some(self.current_frame);
         self.current_frame += 0x200000;
 
         Some(frame)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starts(range: PhysFrameRange<Size2MiB>) -> std::vec::Vec<u64> {
+        range.map(|frame| frame.start_address().as_u64()).collect()
+    }
+
+    #[test]
+    fn allocate_contiguous_over_a_fragmented_map() {
+        // The first area holds 2 frames, and a taken area splits the second one, so the frames
+        // that a naive bump would return next aren't contiguous.
+        let areas = [0x200000..0x600000, 0x800000..0x1400000];
+        let mut bump = BumpAllocator::new([0..0x100000, 0xa00000..0xa00001], &areas);
+
+        let run = bump.allocate_contiguous(3).unwrap();
+        assert_eq!(starts(run), [0xc00000, 0xe00000, 0x1000000]);
+
+        // The skipped frames are lost, the next frame is the one after the run.
+        assert_eq!(
+            bump.allocate_frame().unwrap().start_address().as_u64(),
+            0x1200000
+        );
+        assert_eq!(bump.allocate_frame(), None);
+    }
+
+    #[test]
+    fn allocate_contiguous_without_a_long_enough_run() {
+        // No area has 4 free frames in a row, though there are 6 free frames in total.
+        let areas = [0x200000..0x800000, 0xa00000..0x1200000];
+        let mut bump = BumpAllocator::new([0..0x100000, 0xe00000..0xe00001], &areas);
+        assert_eq!(bump.allocate_contiguous(4), None);
+        assert_eq!(bump.allocate_frame(), None);
+
+        // Sizes that overflow the address space don't panic.
+        let mut bump = BumpAllocator::new([0..0x100000, 0xe00000..0xe00001], &areas);
+        assert_eq!(bump.allocate_contiguous(usize::MAX), None);
+        assert_eq!(bump.allocate_contiguous(usize::MAX / 0x200000), None);
+    }
+
+    #[test]
+    fn dealloc_last_rolls_back_a_single_frame() {
+        let areas = [0x200000..0x1000000, 0x2000000..0x2200000];
+        let mut bump = BumpAllocator::new([0..0x100000, 0x600000..0xa00000], &areas);
+        let address = |frame: Option<PhysFrame<Size2MiB>>| frame.unwrap().start_address().as_u64();
+
+        assert!(!bump.dealloc_last());
+        assert_eq!(address(bump.allocate_frame()), 0x200000);
+        assert!(bump.dealloc_last());
+        assert_eq!(address(bump.allocate_frame()), 0x200000);
+        assert_eq!(address(bump.allocate_frame()), 0x400000);
+
+        // The rollback is only one deep.
+        assert!(bump.dealloc_last());
+        assert!(!bump.dealloc_last());
+        assert_eq!(address(bump.allocate_frame()), 0x400000);
+
+        // The next frame skips the taken area, and the rollback doesn't go back past the skip.
+        assert_eq!(address(bump.allocate_frame()), 0xa00000);
+        assert!(bump.dealloc_last());
+        assert_eq!(address(bump.allocate_frame()), 0xa00000);
+
+        // Runs can't be rolled back.
+        bump.allocate_contiguous(1).unwrap();
+        assert!(!bump.dealloc_last());
+        assert_eq!(address(bump.allocate_frame()), 0xe00000);
+    }
+}
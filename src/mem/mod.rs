@@ -1,20 +1,35 @@
 //! This module contains a lot of the structures and algorithms related to memory allocation.
 //!
 
+// The tree and the virtual memory allocator have more API than the allocators use so far.
+#[allow(dead_code)]
 mod btree;
 mod bump;
+mod recycling;
 mod slab;
+mod sub_frame;
+mod survey;
+#[allow(dead_code)]
 mod vma;
+mod walker;
 
 pub use slab::{SlabAllocator, SlabBox};
 
 use btree::BTree;
 pub use bump::BumpAllocator;
-
-use core::mem::MaybeUninit;
+pub use recycling::RecyclingFrameAllocator;
+pub use sub_frame::SubFrameAllocator;
+pub use survey::{survey, MemorySurvey, MAX_MEMORY_REGIONS};
+use vma::VirtualMemoryAllocator;
+pub use walker::{map_page, MapError, PageTableWalker};
+
+use core::alloc::Layout;
+use core::iter;
+use core::ops::Range;
 use core::ptr;
 use core::slice;
-use multiboot2::{BootInformation, MemoryMapTag};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::tlb;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::{
     FrameAllocator, PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB,
@@ -43,8 +58,6 @@ struct BuddyAllocator<const N: usize> {
 
 impl<const N: usize> BuddyAllocator<N> {
     pub fn malloc(&mut self, order: usize) -> Option<usize> {
-        let order_buddy_size = self.base_size << order;
-
         while let Some(free_list) = self.buddies[order].free_list.take() {
             let BuddyFreeList { ptr, next } = free_list.free_move(&mut self.free_list_alloc);
             self.buddies[order].free_list = next;
@@ -62,32 +75,58 @@ impl<const N: usize> BuddyAllocator<N> {
             None
         } else {
             let ptr = self.malloc(order + 1)?;
+            self.free_upper_half(order, ptr);
 
-            let chunk_ptr = (ptr - self.offset) / order_buddy_size;
+            Some(ptr)
+        }
+    }
 
-            self.set_unused(order, chunk_ptr + 1);
-            self.buddies[order].free_list = Some(SlabBox::new(
-                &mut self.free_list_alloc,
-                BuddyFreeList {
-                    ptr: ptr - self.offset + order_buddy_size,
-                    next: self.buddies[order].free_list.take(),
-                },
-            ));
+    /// Allocates a block of order `order` whose offset is aligned to `base_size << align_order`.
+    /// It allocates a block of order `align_order`, and returns all of it but its first block of
+    /// order `order` to the free lists.
+    pub fn malloc_aligned(&mut self, order: usize, align_order: usize) -> Option<usize> {
+        if align_order <= order {
+            return self.malloc(order);
+        }
 
-            Some(ptr)
+        let ptr = self.malloc(align_order)?;
+        for order in (order..align_order).rev() {
+            self.free_upper_half(order, ptr);
         }
+
+        Some(ptr)
+    }
+
+    /// Splits the block of order `order + 1` at `ptr`, and marks its upper half as a free block of
+    /// order `order`.
+    fn free_upper_half(&mut self, order: usize, ptr: usize) {
+        let order_buddy_size = self.base_size << order;
+        let chunk_ptr = (ptr - self.offset) / order_buddy_size;
+
+        self.set_unused(order, chunk_ptr + 1);
+        self.buddies[order].free_list = Some(SlabBox::new(
+            &mut self.free_list_alloc,
+            BuddyFreeList {
+                ptr: ptr - self.offset + order_buddy_size,
+                next: self.buddies[order].free_list.take(),
+            },
+        ));
     }
 
     pub fn free(&mut self, ptr: usize, order: usize) {
+        let chunk_ptr = (ptr - self.offset) / (self.base_size << order);
         assert!(
-            self.is_unused(order, ptr),
+            self.is_used(order, chunk_ptr),
             "Double free detected, consider yourself lucky."
         );
 
-        let chunk_ptr = (ptr - self.offset) / (self.base_size << order);
         if order < self.buddies.len() - 1 && self.is_unused(order, chunk_ptr ^ 1) {
+            // The buddy's free list entry becomes stale, `malloc` skips it.
             self.set_used(order, chunk_ptr ^ 1);
-            self.free(ptr, order + 1);
+            self.free(
+                self.offset + (chunk_ptr & !1) * (self.base_size << order),
+                order + 1,
+            );
         } else {
             self.set_unused(order, chunk_ptr);
             self.buddies[order].free_list = Some(SlabBox::new(
@@ -120,35 +159,60 @@ impl<const N: usize> BuddyAllocator<N> {
         self.buddies[order].bitmap[chunk_ptr >> 6] |= 1 << (chunk_ptr & 63);
     }
 
+    /// Marks the base chunks in `start_address..end_address` as used, the range is rounded out to
+    /// whole base chunks. Already used chunks are left as they are, so the range may overlap
+    /// previously marked ranges.
     pub unsafe fn mark_as_used(&mut self, mut start_address: usize, mut end_address: usize) {
+        /// A chunk is free if it or one of its ancestors is unused. If the chunk is inside a free
+        /// ancestor, the ancestor is split down to the chunk. Otherwise the chunk is already used
+        /// or split, and only its free descendants have to be marked.
         fn order_mark_as_used<const N: usize>(
             buddy_alloc: &mut BuddyAllocator<N>,
-            mut order: usize,
-            mut chunk_ptr: usize,
+            order: usize,
+            chunk_ptr: usize,
         ) {
-            while order < N {
-                if order < N - 1 && buddy_alloc.is_used(order, chunk_ptr) {
-                    if buddy_alloc.is_used(order, chunk_ptr ^ 1) {
-                        buddy_alloc.set_unused(order, chunk_ptr ^ 1);
-                        buddy_alloc.buddies[order].free_list = Some(SlabBox::new(
-                            &mut buddy_alloc.free_list_alloc,
-                            BuddyFreeList {
-                                ptr: (chunk_ptr ^ 1) * (buddy_alloc.base_size << order),
-                                next: buddy_alloc.buddies[order].free_list.take(),
-                            },
-                        ));
-                    }
-                    order += 1;
-                    chunk_ptr /= 2;
+            let mut free_order = order;
+            while buddy_alloc.is_used(free_order, chunk_ptr >> free_order - order) {
+                if free_order == N - 1 {
+                    mark_descendants_as_used(buddy_alloc, order, chunk_ptr);
+                    return;
+                }
+                free_order += 1;
+            }
+
+            for i in order..free_order {
+                let buddy_ptr = chunk_ptr >> i - order ^ 1;
+                buddy_alloc.set_unused(i, buddy_ptr);
+                buddy_alloc.buddies[i].free_list = Some(SlabBox::new(
+                    &mut buddy_alloc.free_list_alloc,
+                    BuddyFreeList {
+                        ptr: buddy_ptr * (buddy_alloc.base_size << i),
+                        next: buddy_alloc.buddies[i].free_list.take(),
+                    },
+                ));
+            }
+            buddy_alloc.set_used(free_order, chunk_ptr >> free_order - order);
+        }
+
+        fn mark_descendants_as_used<const N: usize>(
+            buddy_alloc: &mut BuddyAllocator<N>,
+            order: usize,
+            chunk_ptr: usize,
+        ) {
+            if order == 0 {
+                return;
+            }
+            for child_ptr in [2 * chunk_ptr, 2 * chunk_ptr + 1] {
+                if buddy_alloc.is_unused(order - 1, child_ptr) {
+                    buddy_alloc.set_used(order - 1, child_ptr);
                 } else {
-                    buddy_alloc.set_used(order, chunk_ptr);
-                    break;
+                    mark_descendants_as_used(buddy_alloc, order - 1, child_ptr);
                 }
             }
         }
 
         start_address = (start_address - self.offset) / self.base_size;
-        end_address = (end_address - self.offset + self.base_size - 1) / self.base_size;
+        end_address = (end_address - self.offset).div_ceil(self.base_size);
 
         let mut order = 0;
         while order < N - 1 && start_address < end_address {
@@ -159,7 +223,7 @@ impl<const N: usize> BuddyAllocator<N> {
                 order_mark_as_used(self, order, end_address - 1);
             }
 
-            start_address = (start_address + 1) / 2;
+            start_address = start_address.div_ceil(2);
             end_address /= 2;
 
             order += 1;
@@ -167,7 +231,60 @@ impl<const N: usize> BuddyAllocator<N> {
 
         if order == N - 1 {
             for i in start_address..end_address {
-                self.set_used(order, i);
+                order_mark_as_used(self, order, i);
+            }
+        }
+    }
+
+    /// Returns the number of free blocks of the base size. A block is free if it's unused, the
+    /// parent of an unused block is always used.
+    pub fn free_chunk_count(&self) -> usize {
+        (0..N)
+            .map(|order| {
+                let unused = (0..self.buddies[order].num_buddies)
+                    .filter(|&chunk_ptr| self.is_unused(order, chunk_ptr))
+                    .count();
+                unused << order
+            })
+            .sum()
+    }
+
+    /// Checks that the bitmaps and the free lists agree, and panics if they don't.
+    ///
+    /// Every unused block must be in its order's free list, and its parent must be used (split).
+    /// The free lists are cleaned lazily, `malloc` skips the entries of used blocks, so such
+    /// entries are allowed, but they must still be aligned blocks inside the allocator.
+    pub fn verify_consistency(&self) {
+        for (order, buddies) in self.buddies.iter().enumerate() {
+            let block_size = self.base_size << order;
+            let free_list =
+                || iter::successors(buddies.free_list.as_deref(), |entry| entry.next.as_deref());
+
+            for entry in free_list() {
+                assert!(
+                    entry.ptr % block_size == 0 && entry.ptr / block_size < buddies.num_buddies,
+                    "Free list entry 0x{:x} of order {} isn't a block",
+                    entry.ptr,
+                    order,
+                );
+            }
+
+            for chunk_ptr in 0..buddies.num_buddies {
+                if self.is_used(order, chunk_ptr) {
+                    continue;
+                }
+                assert!(
+                    order == N - 1 || self.is_used(order + 1, chunk_ptr / 2),
+                    "Unused block 0x{:x} of order {} has an unused parent",
+                    chunk_ptr * block_size,
+                    order,
+                );
+                assert!(
+                    free_list().any(|entry| entry.ptr == chunk_ptr * block_size),
+                    "Unused block 0x{:x} of order {} is missing from the free list",
+                    chunk_ptr * block_size,
+                    order,
+                );
             }
         }
     }
@@ -175,111 +292,384 @@ impl<const N: usize> BuddyAllocator<N> {
 
 const GLOBAL_BUDDY_DEPTH: usize = 8;
 
+/// The base of the 1GiB virtual region of the last PDP entry of the last PML4 entry, where the PD
+/// tables are mapped. The PD tables of the PML4 entry `p4` are mapped as a single 2MiB page at
+/// `RECURSIVE_BASE + p4 * 2MiB`, so the PD tables of the last PML4 entry map the region itself.
+const RECURSIVE_BASE: usize = (0xffff << 48) | (511 << 39) | (511 << 30);
+
+/// The virtual address of the 2MiB kernel stack page, the boot code maps it in the PDP entry 510
+/// of the last PML4 entry (see `long_mode_start.asm`).
+pub const KERNEL_STACK_VIRT: usize = (0xffff << 48) | (511 << 39) | (510 << 30) | (1 << 21);
+
+/// The virtual addresses that are never given to the virtual address tree: the 1GiB of the kernel
+/// stack and the 1GiB of `RECURSIVE_BASE` right after it. Like the tree's addresses, they're
+/// truncated to 48 bits.
+const RESERVED_VIRT_RANGE: Range<usize> = KERNEL_STACK_VIRT & !0x3fffffff & (1 << 48) - 1..1 << 48;
+
+const _: () = assert!(
+    RESERVED_VIRT_RANGE.start <= KERNEL_STACK_VIRT & (1 << 48) - 1
+        && RESERVED_VIRT_RANGE.start <= RECURSIVE_BASE & (1 << 48) - 1
+);
+
+/// Returns the virtual address of the 2MiB page with the PD tables of the PML4 entry `p4`.
+#[inline]
+const fn pd_tables_addr(p4: u16) -> usize {
+    RECURSIVE_BASE | (p4 as usize) << 21
+}
+
+/// Returns the virtual address of the PD table of the PML4 entry `p4` and the PDP entry `p3`.
+#[inline]
+const fn pd_table_addr(p4: u16, p3: u16) -> *mut PageTable {
+    (pd_tables_addr(p4) | (p3 as usize) << 12) as *mut _
+}
+
+/// A segment of memory, ordered by its address first. The key of merge trees.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, PartialOrd, Ord)]
 struct MemSegment {
     pub ptr: usize,
     pub size: usize,
 }
 
+/// A segment of memory, ordered by its size first. The key of best-fit trees.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, PartialOrd, Ord)]
 struct MemSegmentOrdBySize {
     pub size: usize,
     pub ptr: usize,
 }
 
+impl From<MemSegmentOrdBySize> for MemSegment {
+    #[inline]
+    fn from(MemSegmentOrdBySize { size, ptr }: MemSegmentOrdBySize) -> Self {
+        Self { ptr, size }
+    }
+}
+
+impl From<MemSegment> for MemSegmentOrdBySize {
+    #[inline]
+    fn from(MemSegment { ptr, size }: MemSegment) -> Self {
+        Self { size, ptr }
+    }
+}
+
 /// The global allocator struct
 pub struct GlobalChunkAllocator {
     buddy_alloc: BuddyAllocator<GLOBAL_BUDDY_DEPTH>,
-    virt_addr_alloc: BTree<(usize, usize), ()>,
+    virt_addr_alloc: VirtualMemoryAllocator,
     pml4_table: PageTable,
     pdp_tables: &'static mut [PageTable; 512],
-    chunk_checks: bool,
+    /// True while the first 2MiB are marked as used only to keep them away from the allocations,
+    /// see `unmap_low_identity`.
+    low_chunk_reserved: bool,
+    /// Where the PD tables are mapped, `RECURSIVE_BASE` except in the host tests, see `pd_table`.
+    recursive_base: usize,
 }
 
 /// The global allocator static
 pub static GLOBAL_CHUNK_ALLOCATOR: spin::Mutex<Option<GlobalChunkAllocator>> =
     spin::Mutex::new(None);
 
-/// Initialize the global allocator static
-pub unsafe fn init(
-    kernel_start: usize,
-    kernel_end: usize,
-    phys_stack_frame: usize,
-    boot_info: &BootInformation,
-    memory_map_tag: &MemoryMapTag,
-) {
+/// The message of the panic when the global chunk allocator is used before `init`.
+const NOT_INITIALIZED: &str =
+    "The global chunk allocator isn't initialized, call `mem::init` first";
+
+/// The OOM handler as a `fn(Layout) -> bool`, or 0 if there's none.
+static OOM_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets a handler that is called when `kmalloc` runs out of memory, with the layout of the failed
+/// allocation. If it frees memory and returns true the allocation is retried, otherwise it fails.
+///
+/// The handler runs without the allocator's lock, so it may free chunks with `kfree`.
+pub fn set_oom_handler(handler: fn(Layout) -> bool) {
+    OOM_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+/// Removes the OOM handler, so `kmalloc` fails right away when it runs out of memory.
+pub fn clear_oom_handler() {
+    OOM_HANDLER.store(0, Ordering::Release);
+}
+
+/// Calls the OOM handler, returns true if the allocation should be retried.
+fn handle_oom(layout: Layout) -> bool {
+    match OOM_HANDLER.load(Ordering::Acquire) {
+        0 => false,
+        handler => {
+            let handler: fn(Layout) -> bool = unsafe { core::mem::transmute(handler) };
+            handler(layout)
+        }
+    }
+}
+
+/// Allocates a chunk of size `2MiB * 2^order` from `GLOBAL_CHUNK_ALLOCATOR`, see
+/// `GlobalChunkAllocator::try_malloc`. On failure the OOM handler is consulted, see
+/// `set_oom_handler`.
+///
+/// # Safety
+/// The page tables that `init` loaded must still be the loaded ones.
+///
+/// # Panics
+/// Panics if `init` wasn't called yet.
+pub unsafe fn kmalloc(order: usize) -> Option<&'static mut [u8]> {
+    let layout = Layout::from_size_align(
+        GlobalChunkAllocator::order_size(order),
+        GlobalChunkAllocator::BASE_SIZE,
+    )
+    .unwrap();
+    retry_on_oom(layout, || {
+        // The lock is released when this returns, so the handler can free chunks.
+        crate::without_interrupts(|| {
+            GLOBAL_CHUNK_ALLOCATOR
+                .lock()
+                .as_mut()
+                .expect(NOT_INITIALIZED)
+                .try_malloc(order)
+        })
+    })
+}
+
+/// Calls `try_alloc` until it succeeds, and calls the OOM handler with `layout` after every
+/// failure. Returns `None` once the handler doesn't ask for a retry.
+fn retry_on_oom<T>(layout: Layout, mut try_alloc: impl FnMut() -> Option<T>) -> Option<T> {
+    loop {
+        if let Some(x) = try_alloc() {
+            return Some(x);
+        }
+        if !handle_oom(layout) {
+            return None;
+        }
+    }
+}
+
+/// Frees a chunk of order `order` that was allocated from `GLOBAL_CHUNK_ALLOCATOR`, see
+/// `GlobalChunkAllocator::free`.
+///
+/// # Safety
+/// Same as `kmalloc`, and nothing may use `chunk` once it's freed.
+///
+/// # Panics
+/// Panics if `init` wasn't called yet.
+pub unsafe fn kfree(chunk: &'static mut [u8], order: usize) {
+    crate::without_interrupts(|| {
+        GLOBAL_CHUNK_ALLOCATOR
+            .lock()
+            .as_mut()
+            .expect(NOT_INITIALIZED)
+            .free(chunk, order)
+    })
+}
+
+/// Returns the number of free 2MiB chunks of `GLOBAL_CHUNK_ALLOCATOR`, or `None` if it's locked
+/// or isn't initialized. It never blocks, so it's safe in interrupt handlers.
+pub fn try_free_chunk_count() -> Option<usize> {
+    Some(
+        GLOBAL_CHUNK_ALLOCATOR
+            .try_lock()?
+            .as_ref()?
+            .buddy_alloc
+            .free_chunk_count(),
+    )
+}
+
+/// The reasons `init` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemInitError {
+    /// `GLOBAL_CHUNK_ALLOCATOR` was already initialized.
+    AlreadyInitialized,
+    /// The memory map has no memory areas.
+    EmptyMemoryMap,
+    /// The memory is too large for the buddies' bitmaps to fit in a single chunk.
+    MemoryTooLarge,
+    /// The kernel and the Multiboot2 information structure aren't in the same or adjacent chunks.
+    KernelAndBootInfoApart,
+    /// The bump allocator ran out of frames.
+    OutOfFrames,
+    /// The buddy allocator ran out of chunks.
+    OutOfChunks,
+    /// Two of the initial mappings map `virt_addr` to different frames.
+    ConflictingMappings {
+        /// The virtual address that is mapped twice.
+        virt_addr: VirtAddr,
+    },
+    /// The initial mappings, with the chunks of their PD tables, don't fit in the queue of the
+    /// initial page tables.
+    TooManyMappings,
+    /// A range of virtual addresses that is kept from the virtual address allocator ends before
+    /// it starts.
+    InvalidUsedRange {
+        /// The start of the range.
+        start: usize,
+        /// The end of the range.
+        end: usize,
+    },
+}
+
+/// Initialize the global allocator static.
+///
+/// Everything is built in locals first, `GLOBAL_CHUNK_ALLOCATOR` and CR3 are written only at the
+/// very end, so on failure both are left untouched.
+///
+/// # Safety
+/// `survey` must describe this machine, and `phys_stack_frame` must be the frame of the kernel stack. The new page tables map only the kernel, the Multiboot2
+/// information structure, the stack and the allocator's own chunks, nothing else may be in use.
+pub unsafe fn init(survey: &MemorySurvey, phys_stack_frame: usize) -> Result<(), MemInitError> {
     log::info!("Entered mem::init()");
     let mut global_chunk_allocator_lock = GLOBAL_CHUNK_ALLOCATOR.lock();
-    assert!(global_chunk_allocator_lock.is_none());
+    if global_chunk_allocator_lock.is_some() {
+        return Err(MemInitError::AlreadyInitialized);
+    }
 
-    let mem_size = memory_map_tag
-        .memory_areas()
-        .map(|area| area.end_address())
-        .max()
-        .unwrap();
+    let (mut buddy_alloc, [kernel_chunks, buddies_chunk, free_list_alloc_chunk]) =
+        build_buddy_allocator(survey, phys_stack_frame, 0)?;
+    // Otherwise the first 2MiB are a part of the kernel's chunks.
+    let low_chunk_reserved = 0x200000 <= kernel_chunks.start;
+
+    let virt_addr_alloc_chunk = buddy_alloc.malloc(0).ok_or(MemInitError::OutOfChunks)?;
+    log::info!(
+        "Allocated chunk=0x{:x} for virtual address allocator",
+        virt_addr_alloc_chunk
+    );
+    let virt_addr_alloc_chunk =
+        virt_addr_alloc_chunk..virt_addr_alloc_chunk + buddy_alloc.base_size;
+
+    let (pml4_table, pdp_tables) = build_initial_page_tables(
+        &mut buddy_alloc,
+        [
+            kernel_chunks.clone(),
+            buddies_chunk.clone(),
+            free_list_alloc_chunk.clone(),
+            virt_addr_alloc_chunk.clone(),
+        ],
+        phys_stack_frame,
+    )?;
+    let pdp_tables_addr = pdp_tables.as_ptr() as usize;
+
+    let virt_addr_alloc = seed_virtual_allocator(
+        slice::from_raw_parts_mut(
+            virt_addr_alloc_chunk.start as _,
+            virt_addr_alloc_chunk.len(),
+        ),
+        [
+            kernel_chunks,
+            buddies_chunk,
+            free_list_alloc_chunk,
+            virt_addr_alloc_chunk,
+            pdp_tables_addr..pdp_tables_addr + 0x200000,
+            RESERVED_VIRT_RANGE,
+        ],
+    )?;
+
+    let global_chunk_allocator = global_chunk_allocator_lock.insert(GlobalChunkAllocator {
+        buddy_alloc,
+        virt_addr_alloc,
+        pml4_table,
+        pdp_tables,
+        low_chunk_reserved,
+        recursive_base: RECURSIVE_BASE,
+    });
+
+    log::info!("Initialized GLOBAL_CHUNK_ALLOCATOR");
+
+    let (_, cr3_flags) = Cr3::read();
+    Cr3::write(
+        PhysFrame::from_start_address(PhysAddr::new(
+            &global_chunk_allocator.pml4_table as *const PageTable as _,
+        ))
+        .unwrap(),
+        cr3_flags,
+    );
+
+    log::info!("Initialized allocator paging");
+
+    Ok(())
+}
+
+/// Builds the buddy allocator of `init`, with its bitmaps and free lists in two frames taken from
+/// a bump allocator, and with the kernel, the Multiboot2 information structure, the stack, and
+/// those two frames marked as used.
+///
+/// Returns the allocator and the physical chunks that must be identity mapped: the 2MiB aligned
+/// chunks of the kernel and the Multiboot2 information structure, the bitmaps' frame and the free
+/// lists' frame.
+///
+/// The two frames are accessed at their physical address plus `phys_offset`, which is 0 except in
+/// the host tests.
+unsafe fn build_buddy_allocator(
+    survey: &MemorySurvey,
+    phys_stack_frame: usize,
+    phys_offset: usize,
+) -> Result<(BuddyAllocator<GLOBAL_BUDDY_DEPTH>, [Range<usize>; 3]), MemInitError> {
+    if survey.usable.is_empty() {
+        return Err(MemInitError::EmptyMemoryMap);
+    }
+    let (kernel_start, kernel_end) = (survey.kernel.start, survey.kernel.end);
+    let (boot_info_start, boot_info_end) = (survey.boot_info.start, survey.boot_info.end);
     const TOP_BLOCK_SIZE: usize = 1 << 20 + GLOBAL_BUDDY_DEPTH;
 
-    let mem_size = mem_size as usize & !(TOP_BLOCK_SIZE - 1);
-    assert!(mem_size as u64 / Size2MiB::SIZE / 8 <= Size2MiB::SIZE / 2);
+    let mem_size = survey.mem_end & !(TOP_BLOCK_SIZE - 1);
+    if Size2MiB::SIZE / 2 < mem_size as u64 / Size2MiB::SIZE / 8 {
+        return Err(MemInitError::MemoryTooLarge);
+    }
 
     log::info!("Creating bump_allocator");
     let mut bump_allocator = BumpAllocator::new(
         [
-            kernel_start..kernel_end,
-            boot_info.start_address()..boot_info.end_address(),
+            survey.kernel.clone(),
+            survey.boot_info.clone(),
             phys_stack_frame..phys_stack_frame + 0x200000,
         ],
-        memory_map_tag,
+        &survey.usable,
     );
 
     let buddies_frame = bump_allocator
         .allocate_frame()
-        .expect("Couldn't allocate a frame for the buddies");
+        .ok_or(MemInitError::OutOfFrames)?;
     log::info!(
         "Allocated chunk=0x{:x} for buddy allocator",
         buddies_frame.start_address().as_u64()
     );
     let free_list_alloc_frame = bump_allocator
         .allocate_frame()
-        .expect("Couldn't allocate a frame for the buddies' free list slab allocator");
+        .ok_or(MemInitError::OutOfFrames)?;
     log::info!(
         "Allocated chunk=0x{:x} for free list allocator",
         free_list_alloc_frame.start_address().as_u64()
     );
 
     let free_list_alloc = SlabAllocator::new(slice::from_raw_parts_mut(
-        free_list_alloc_frame.start_address().as_u64() as _,
+        (phys_offset + free_list_alloc_frame.start_address().as_u64() as usize) as _,
         Size2MiB::SIZE as _,
     ));
 
     log::info!("Creating buddy_alloc");
+    let buddies_addr = (phys_offset + buddies_frame.start_address().as_u64() as usize) as *mut u64;
     let mut buddy_alloc = BuddyAllocator::<GLOBAL_BUDDY_DEPTH> {
-        buddies: MaybeUninit::uninit().assume_init(),
+        // Every element is fully built before it's placed in the array, so there's never an
+        // uninitialized `Buddies` (its bitmap is a reference).
+        buddies: core::array::from_fn(|i| {
+            let num_buddies = mem_size >> 21 + i;
+            // The bitmap of the order `i` starts at the byte `2MiB - 2MiB / 2^i` of the frame, so
+            // each order has half the room of the one below it.
+            let bitmap = slice::from_raw_parts_mut(
+                buddies_addr.add((0x200000 - (0x200000 >> i)) / 8),
+                num_buddies.div_ceil(64),
+            );
+            if i == GLOBAL_BUDDY_DEPTH - 1 {
+                bitmap.fill(0);
+            } else {
+                bitmap.fill(!0);
+            }
+            Buddies {
+                num_buddies,
+                bitmap,
+                free_list: None,
+            }
+        }),
         free_list_alloc,
-        base_size: 0x200000,
+        base_size: GlobalChunkAllocator::BASE_SIZE,
         offset: 0,
     };
 
-    let buddies_addr = buddies_frame.start_address().as_u64() as *mut u64;
-    for (i, buddies) in buddy_alloc.buddies.iter_mut().enumerate() {
-        let num_buddies = mem_size as usize >> 21 + i;
-        *buddies = Buddies {
-            num_buddies,
-            bitmap: slice::from_raw_parts_mut(
-                buddies_addr.add(0x200000 - (0x200000 >> i)),
-                (num_buddies + 63) / 64,
-            ),
-            free_list: None,
-        };
-        if i == GLOBAL_BUDDY_DEPTH - 1 {
-            buddies.bitmap.fill(0);
-        } else {
-            buddies.bitmap.fill(!0);
-        }
-    }
-
     let top_buddies = &mut buddy_alloc.buddies[GLOBAL_BUDDY_DEPTH - 1];
-    for i in (0..top_buddies.bitmap.len() * 8).rev() {
+    for i in (0..top_buddies.num_buddies).rev() {
         top_buddies.free_list = Some(SlabBox::new(
             &mut buddy_alloc.free_list_alloc,
             BuddyFreeList {
@@ -288,13 +678,14 @@ pub unsafe fn init(
             },
         ));
     }
-    assert!(
-        kernel_start & !0x1fffff < boot_info.end_address() + 0x1fffff & !0x1fffff
-            && boot_info.start_address() & !0x1fffff < kernel_end + 0x1fffff & !0x1fffff
-    );
+    if !(kernel_start & !0x1fffff < boot_info_end + 0x1fffff & !0x1fffff
+        && boot_info_start & !0x1fffff < kernel_end + 0x1fffff & !0x1fffff)
+    {
+        return Err(MemInitError::KernelAndBootInfoApart);
+    }
     buddy_alloc.mark_as_used(
-        kernel_start.min(boot_info.start_address()),
-        kernel_end.max(boot_info.end_address()),
+        kernel_start.min(boot_info_start),
+        kernel_end.max(boot_info_end),
     );
     buddy_alloc.mark_as_used(
         buddies_frame.start_address().as_u64() as _,
@@ -309,21 +700,49 @@ pub unsafe fn init(
         buddy_alloc.mark_as_used(0, 0x200000);
     }
 
-    let virt_addr_alloc_chunk = buddy_alloc.malloc(0).unwrap();
-    log::info!(
-        "Allocated chunk=0x{:x} for virtual address allocator",
-        virt_addr_alloc_chunk
-    );
+    if cfg!(debug_assertions) {
+        buddy_alloc.verify_consistency();
+    }
+
+    let frame_range = |frame: PhysFrame<Size2MiB>| {
+        frame.start_address().as_u64() as usize
+            ..(frame.start_address().as_u64() + frame.size()) as usize
+    };
+    Ok((
+        buddy_alloc,
+        [
+            boot_info_start.min(kernel_start) & !0x1fffff
+                ..boot_info_end.max(kernel_end) + 0x1fffff & !0x1fffff,
+            frame_range(buddies_frame),
+            frame_range(free_list_alloc_frame),
+        ],
+    ))
+}
+
+/// Builds the PML4 table of `init` and its 512 PDP tables, which are in a chunk taken from
+/// `buddy_alloc`. The chunks `identity_mapped` and the PDP tables' chunk are identity mapped, and
+/// the stack frame is mapped at `KERNEL_STACK_VIRT`. The PD tables are taken from `buddy_alloc` too,
+/// and they're mapped at `pd_tables_addr`.
+unsafe fn build_initial_page_tables<const N: usize>(
+    buddy_alloc: &mut BuddyAllocator<GLOBAL_BUDDY_DEPTH>,
+    identity_mapped: [Range<usize>; N],
+    phys_stack_frame: usize,
+) -> Result<(PageTable, &'static mut [PageTable; 512]), MemInitError> {
+    // A queue of `(phys_addr, size, virt_addr)`, the PD tables' chunks are pushed to it as they're
+    // allocated.
+    let mut map_addresses = [(0, 0, 0); 12];
+    if map_addresses.len() <= N + 2 {
+        return Err(MemInitError::TooManyMappings);
+    }
 
     log::info!("Creating pml4_table");
     let mut pml4_table = PageTable::new();
-    let pdp_tables_addr = buddy_alloc.malloc(0).unwrap();
+    let pdp_tables_addr = buddy_alloc.malloc(0).ok_or(MemInitError::OutOfChunks)?;
     let pdp_tables = &mut *(pdp_tables_addr as *mut [PageTable; 512]);
 
     for i in 0..512 {
         ptr::write((pdp_tables_addr as *mut PageTable).add(i), PageTable::new());
     }
-    // let pdp_tables = &mut *(pdp_tables_phys_addr as *mut [PageTable; 512]);
     for (i, entry) in pml4_table.iter_mut().enumerate() {
         entry.set_addr(
             PhysAddr::new((pdp_tables_addr + 4096 * i) as _),
@@ -331,261 +750,1062 @@ pub unsafe fn init(
         );
     }
 
-    {
-        let mut map_addresses = [
-            (
-                boot_info.start_address().min(kernel_start) & !0x1fffff,
-                (boot_info.end_address().max(kernel_end) + 0x1fffff & !0x1fffff)
-                    - (boot_info.start_address().min(kernel_start) & !0x1fffff),
-                boot_info.start_address().min(kernel_start) & !0x1fffff,
-            ),
-            (
-                buddies_frame.start_address().as_u64() as usize,
-                buddies_frame.size() as usize,
-                buddies_frame.start_address().as_u64() as usize,
-            ),
-            (
-                free_list_alloc_frame.start_address().as_u64() as usize,
-                free_list_alloc_frame.size() as usize,
-                free_list_alloc_frame.start_address().as_u64() as usize,
-            ),
-            (virt_addr_alloc_chunk, 0x200000, virt_addr_alloc_chunk),
-            (pdp_tables_addr, 0x200000, pdp_tables_addr),
-            (
-                phys_stack_frame,
-                0x200000,
-                (511 << 39) | (510 << 30) | (1 << 21) | (0xffff << 48),
-            ),
-            (0, 0, 0),
-            (0, 0, 0),
-            (0, 0, 0),
-            (0, 0, 0),
-            (0, 0, 0),
-            (0, 0, 0),
-        ];
-        let mut ptr = 0;
-        let mut end = 6;
-
-        while ptr != end {
-            let (phys_addr, size, virt_addr) = map_addresses[ptr];
-            ptr = (ptr + 1) % map_addresses.len();
-
-            for i in (0..size).step_by(0x200000) {
-                let phys_addr = PhysAddr::new((phys_addr + i) as _);
-                let virt_addr = VirtAddr::new((virt_addr + i) as _);
-
-                let pdp_table = &mut pdp_tables[usize::from(virt_addr.p4_index())];
-
-                let pd_addr = if pdp_table[virt_addr.p3_index()].is_unused() {
-                    let pd_addr = if pdp_table[0].is_unused() {
-                        let pd0_addr = buddy_alloc.malloc(0).unwrap() as u64;
-                        map_addresses[end] = (
-                            pd0_addr as _,
-                            0x200000,
-                            (511 << 39)
-                                | (511 << 30)
-                                | (usize::from(virt_addr.p4_index()) << 21)
-                                | (0xffff << 48),
-                        );
-                        end = (end + 1) % map_addresses.len();
-
-                        pdp_table[0].set_addr(PhysAddr::new(pd0_addr), PageTableFlags::WRITABLE);
-                        PhysAddr::new(pd0_addr + 4096 * u64::from(virt_addr.p3_index()))
-                    } else {
-                        let pd0_addr = pdp_table[0].addr();
-
-                        pd0_addr + 4096 * u64::from(virt_addr.p3_index())
-                    };
-
-                    ptr::write(pd_addr.as_u64() as *mut _, PageTable::new());
-
-                    pdp_table[virt_addr.p3_index()]
-                        .set_addr(pd_addr, PageTableFlags::WRITABLE | PageTableFlags::PRESENT);
-
-                    pd_addr.as_u64() as usize
+    for (i, chunk) in identity_mapped.into_iter().enumerate() {
+        map_addresses[i] = (chunk.start, chunk.len(), chunk.start);
+    }
+    map_addresses[N] = (pdp_tables_addr, 0x200000, pdp_tables_addr);
+    map_addresses[N + 1] = (phys_stack_frame, 0x200000, KERNEL_STACK_VIRT);
+    let mut ptr = 0;
+    let mut end = N + 2;
+
+    while ptr != end {
+        let (phys_addr, size, virt_addr) = map_addresses[ptr];
+        ptr = (ptr + 1) % map_addresses.len();
+
+        for i in (0..size).step_by(0x200000) {
+            let phys_addr = PhysAddr::new((phys_addr + i) as _);
+            let virt_addr = VirtAddr::new((virt_addr + i) as _);
+
+            let pdp_table = &mut pdp_tables[usize::from(virt_addr.p4_index())];
+
+            let pd_addr = if pdp_table[virt_addr.p3_index()].is_unused() {
+                let pd_addr = if pdp_table[0].is_unused() {
+                    let pd0_addr = buddy_alloc.malloc(0).ok_or(MemInitError::OutOfChunks)? as u64;
+                    map_addresses[end] = (
+                        pd0_addr as _,
+                        0x200000,
+                        pd_tables_addr(u16::from(virt_addr.p4_index())),
+                    );
+                    end = (end + 1) % map_addresses.len();
+                    // Otherwise the queue would look empty.
+                    if end == ptr {
+                        return Err(MemInitError::TooManyMappings);
+                    }
+
+                    pdp_table[0].set_addr(PhysAddr::new(pd0_addr), PageTableFlags::WRITABLE);
+                    PhysAddr::new(pd0_addr + 4096 * u64::from(virt_addr.p3_index()))
                 } else {
-                    pdp_table[virt_addr.p3_index()].addr().as_u64() as usize
+                    let pd0_addr = pdp_table[0].addr();
+
+                    pd0_addr + 4096 * u64::from(virt_addr.p3_index())
                 };
 
-                let pd_table = &mut *(pd_addr as *mut PageTable);
+                ptr::write(pd_addr.as_u64() as *mut _, PageTable::new());
 
-                assert!(pd_table[virt_addr.p2_index()].is_unused());
+                pdp_table[virt_addr.p3_index()]
+                    .set_addr(pd_addr, PageTableFlags::WRITABLE | PageTableFlags::PRESENT);
 
-                pd_table[virt_addr.p2_index()].set_addr(
-                    phys_addr,
-                    PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                );
+                pd_addr.as_u64() as usize
+            } else {
+                pdp_table[virt_addr.p3_index()].addr().as_u64() as usize
+            };
+
+            let pd_table = &mut *(pd_addr as *mut PageTable);
+            let pd_entry = &mut pd_table[virt_addr.p2_index()];
+
+            // The mapped ranges may share pages (e.g. the kernel and the boot info), so
+            // mapping a page again to the same frame is fine.
+            if !pd_entry.is_unused() {
+                if pd_entry.addr() != phys_addr {
+                    return Err(MemInitError::ConflictingMappings { virt_addr });
+                }
+                continue;
             }
+
+            pd_entry.set_addr(
+                phys_addr,
+                PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
+            );
         }
     }
 
-    let mut virt_addr_alloc = BTree::new(slice::from_raw_parts_mut(
-        virt_addr_alloc_chunk as _,
-        buddy_alloc.base_size,
-    ));
+    Ok((pml4_table, pdp_tables))
+}
 
-    {
-        let mut virt_start_addresses = [
-            boot_info.start_address().min(kernel_start) & !0x1fffff,
-            buddies_frame.start_address().as_u64() as usize,
-            free_list_alloc_frame.start_address().as_u64() as usize,
-            virt_addr_alloc_chunk,
-            pdp_tables_addr,
-            (1 << 48) - (2 << 30),
-        ];
-        let mut virt_end_addresses = [
-            boot_info.end_address().max(kernel_end) + 0x1fffff & !0x1fffff,
-            (buddies_frame.start_address().as_u64() + buddies_frame.size()) as usize,
-            (free_list_alloc_frame.start_address().as_u64() + free_list_alloc_frame.size())
-                as usize,
-            virt_addr_alloc_chunk + buddy_alloc.base_size,
-            pdp_tables_addr + 0x200000,
-            (1 << 48),
-        ];
-        virt_start_addresses.sort_unstable();
-        virt_end_addresses.sort_unstable();
-
-        let mut i = 0;
-        let mut j = 0;
-        let mut depth = 0;
-        let mut last_end = 0x200000;
-
-        while i < virt_start_addresses.len() && j < virt_end_addresses.len() {
-            if virt_start_addresses[i] < virt_end_addresses[j] {
-                if depth == 0 && last_end < virt_start_addresses[i] {
-                    assert!(virt_addr_alloc
-                        .insert(
-                            (virt_start_addresses[i] - last_end, virt_start_addresses[i]),
-                            ()
-                        )
-                        .is_none());
-                }
-                depth += 1;
-                i += 1;
-            } else if virt_end_addresses[j] < virt_start_addresses[i] {
-                last_end = virt_end_addresses[j];
+/// Builds the virtual address allocator of `init` in `chunk`, with the gaps between the `used`
+/// ranges above the first 2MiB. The ranges may overlap and touch, and the addresses must be
+/// truncated to 48 bits.
+fn seed_virtual_allocator<const N: usize>(
+    chunk: &'static mut [u8],
+    used: [Range<usize>; N],
+) -> Result<VirtualMemoryAllocator, MemInitError> {
+    if let Some(range) = used.iter().find(|range| range.end < range.start) {
+        return Err(MemInitError::InvalidUsedRange {
+            start: range.start,
+            end: range.end,
+        });
+    }
 
-                depth -= 1;
-                j += 1;
-            } else {
-                i += 1;
-                j += 1;
+    // There's at most a gap before every range, the unused entries stay empty.
+    let mut gaps = [(ptr::NonNull::dangling(), 0); N];
+    let mut num_gaps = 0;
+
+    let mut virt_start_addresses = used.clone().map(|range| range.start);
+    let mut virt_end_addresses = used.map(|range| range.end);
+    virt_start_addresses.sort_unstable();
+    virt_end_addresses.sort_unstable();
+
+    // A sweep over the sorted ends of the ranges, `depth` is the number of ranges that contain the
+    // sweep position. A gap starts wherever it drops to 0.
+    let mut i = 0;
+    let mut j = 0;
+    let mut depth = 0;
+    let mut last_end = 0x200000;
+
+    while i < virt_start_addresses.len() && j < virt_end_addresses.len() {
+        if virt_start_addresses[i] < virt_end_addresses[j] {
+            if depth == 0 && last_end < virt_start_addresses[i] {
+                gaps[num_gaps] = (
+                    ptr::NonNull::new(last_end as *mut u8).unwrap(),
+                    virt_start_addresses[i] - last_end,
+                );
+                num_gaps += 1;
             }
+            depth += 1;
+            i += 1;
+        } else if virt_end_addresses[j] < virt_start_addresses[i] {
+            // A range that ends below the first 2MiB doesn't move the gaps' start down.
+            last_end = last_end.max(virt_end_addresses[j]);
+
+            depth -= 1;
+            j += 1;
+        } else {
+            i += 1;
+            j += 1;
         }
     }
 
-    let global_chunk_allocator = global_chunk_allocator_lock.insert(GlobalChunkAllocator {
-        buddy_alloc,
-        virt_addr_alloc,
-        pml4_table,
-        pdp_tables,
-        chunk_checks: true,
-    });
-
-    log::info!("Initialized GLOBAL_CHUNK_ALLOCATOR");
-
-    let (_, cr3_flags) = Cr3::read();
-    Cr3::write(
-        PhysFrame::from_start_address(PhysAddr::new(
-            &global_chunk_allocator.pml4_table as *const PageTable as _,
-        ))
-        .unwrap(),
-        cr3_flags,
-    );
+    Ok(VirtualMemoryAllocator::new(&mut gaps[..num_gaps], chunk))
+}
 
-    log::info!("Initialized allocator paging");
+/// Returns `virt_addr` truncated to 48 bits, the form of the virtual address allocator's addresses.
+#[inline]
+fn truncate_virt_addr(virt_addr: VirtAddr) -> usize {
+    virt_addr.as_u64() as usize & (1 << 48) - 1
 }
 
 impl GlobalChunkAllocator {
-    const SUPER_PD_TABLE: *mut PageTable =
-        ((511 << 39) | (511 << 30) | (511 << 21) | (511 << 12) | (0xffff << 48)) as *mut _;
+    /// The size of an order 0 chunk, 2MiB.
+    pub const BASE_SIZE: usize = 0x200000;
+    /// The number of orders of the buddy allocator.
+    pub const DEPTH: usize = GLOBAL_BUDDY_DEPTH;
+
+    /// Returns the size of a chunk of order `order`, `2MiB * 2^order`.
+    pub const fn order_size(order: usize) -> usize {
+        Self::BASE_SIZE << order
+    }
 
-    fn virt_alloc(&mut self, size: usize) -> usize {
-        let mut entry = self.virt_addr_alloc.get_entry(&(size, 0)).unwrap_err();
-        if entry.key().0 < size {
-            assert!(entry.next());
+    /// Returns the largest order `malloc` accepts, the largest chunk is `order_size(max_order())`.
+    pub const fn max_order() -> usize {
+        Self::DEPTH - 1
+    }
+
+    /// Returns the PD table of the PML4 entry `p4` and the PDP entry `p3`, like `pd_table_addr`
+    /// but relative to `recursive_base`.
+    #[inline]
+    fn pd_table(&self, p4: u16, p3: u16) -> *mut PageTable {
+        (pd_table_addr(p4, p3) as usize - RECURSIVE_BASE + self.recursive_base) as *mut _
+    }
+
+    /// Allocates `size` bytes of virtual address space from the smallest free range that fits.
+    /// Returns `None` if there's no such range.
+    fn virt_alloc(&mut self, size: usize) -> Option<usize> {
+        let (ptr, _) = self.virt_addr_alloc.try_alloc(size)?;
+        Some(ptr.as_ptr() as usize)
+    }
+
+    /// Gives back the `size` bytes of virtual address space at `virt_addr`, they're merged with
+    /// the adjacent free ranges.
+    fn virt_free(&mut self, virt_addr: VirtAddr, size: usize) {
+        let ptr = ptr::NonNull::new(truncate_virt_addr(virt_addr) as *mut u8).unwrap();
+        self.virt_addr_alloc.free(ptr, size);
+    }
+
+    /// Tops up the virtual address tree and the buddies' free list slab allocator.
+    ///
+    /// The chunks are allocated with `malloc_no_refill`, this can't recurse. It relies on the
+    /// slack that `needs_new_chunk` keeps (at least 64 free slabs), which is far more than the
+    /// few slabs a single `malloc_no_refill` may consume.
+    ///
+    /// Returns `None` if it ran out of memory.
+    unsafe fn refill_chunks(&mut self) -> Option<()> {
+        while self.virt_addr_alloc.needs_new_chunk() {
+            let chunk = self.malloc_no_refill(0)?;
+            self.virt_addr_alloc.add_chunk(chunk);
+        }
+        while self.buddy_alloc.free_list_alloc.needs_new_chunk() {
+            let chunk = self.malloc_no_refill(0)?;
+            self.buddy_alloc.free_list_alloc.add_chunk(chunk);
         }
+        Some(())
+    }
+
+    /// Allocates a chunk of size `2MiB * 2^order`. `order` has to be at most `max_order()`. The
+    /// function returns the chunk.
+    ///
+    /// # Safety
+    /// The allocator's page tables must be the loaded ones, like `GLOBAL_CHUNK_ALLOCATOR`'s after
+    /// `init`.
+    ///
+    /// # Panics
+    /// Panics if it ran out of memory, use `try_malloc` to handle that.
+    pub unsafe fn malloc(&mut self, order: usize) -> &'static mut [u8] {
+        self.try_malloc(order).expect("Out of memory")
+    }
+
+    /// Same as `malloc`, but returns `None` if there's not enough physical memory or virtual
+    /// address space.
+    ///
+    /// # Safety
+    /// Same as `malloc`.
+    pub unsafe fn try_malloc(&mut self, order: usize) -> Option<&'static mut [u8]> {
+        self.malloc_phys(order).map(|(chunk, _)| chunk)
+    }
 
-        let key = *entry.key();
+    /// Same as `try_malloc`, but also returns the physical address the chunk is mapped to, e.g.
+    /// for programming DMA. The chunk is physically contiguous, and its physical address is the
+    /// start of a buddy block, so it's aligned to the chunk's size.
+    ///
+    /// # Safety
+    /// Same as `malloc`.
+    pub unsafe fn malloc_phys(&mut self, order: usize) -> Option<(&'static mut [u8], PhysAddr)> {
+        debug_assert!(order <= Self::max_order(), "Order {} is too large", order);
+        self.refill_chunks()?;
+        self.malloc_aligned_no_refill(order, order)
+    }
+
+    /// Same as `try_malloc`, but the chunk's physical address is aligned to `phys_align`, a power
+    /// of two that is at least `BASE_SIZE` and at most `order_size(max_order())`. It's for
+    /// mappings with large pages, like a 1GiB page.
+    ///
+    /// # Safety
+    /// Same as `malloc`.
+    ///
+    /// # Panics
+    /// Panics if `phys_align` isn't a valid alignment.
+    pub unsafe fn malloc_aligned(
+        &mut self,
+        order: usize,
+        phys_align: usize,
+    ) -> Option<&'static mut [u8]> {
+        debug_assert!(order <= Self::max_order(), "Order {} is too large", order);
+        assert!(
+            phys_align.is_power_of_two()
+                && Self::BASE_SIZE <= phys_align
+                && phys_align <= Self::order_size(Self::max_order()),
+            "Invalid physical alignment 0x{:x}",
+            phys_align,
+        );
+        let align_order = (phys_align / Self::BASE_SIZE).trailing_zeros() as usize;
+
+        self.refill_chunks()?;
+        self.malloc_aligned_no_refill(order, align_order)
+            .map(|(chunk, _)| chunk)
+    }
 
-        drop(entry);
-        assert!(size <= key.0);
-        self.virt_addr_alloc.remove(&key);
-        if size < key.0 {
-            self.virt_addr_alloc
-                .insert((key.0 - size, key.1 + size), ());
+    /// Does the same thing as `try_malloc` but without topping up the internal allocators first.
+    unsafe fn malloc_no_refill(&mut self, order: usize) -> Option<&'static mut [u8]> {
+        self.malloc_aligned_no_refill(order, order)
+            .map(|(chunk, _)| chunk)
+    }
+
+    /// Allocates a chunk of order `order`, whose physical address is aligned to the size of order
+    /// `align_order`, without topping up the internal allocators first. Returns the chunk and its
+    /// physical address, or `None` if it ran out of virtual address space or memory, and then
+    /// whatever it took is given back.
+    unsafe fn malloc_aligned_no_refill(
+        &mut self,
+        order: usize,
+        align_order: usize,
+    ) -> Option<(&'static mut [u8], PhysAddr)> {
+        let virt_addr = VirtAddr::new_truncate(self.virt_alloc(0x200000 << order)? as _);
+        let phys_addr = match self.buddy_alloc.malloc_aligned(order, align_order) {
+            Some(phys_addr) => PhysAddr::new(phys_addr as _),
+            None => {
+                self.virt_free(virt_addr, 0x200000 << order);
+                return None;
+            }
+        };
+
+        if self
+            .map_chunk(phys_addr, virt_addr, 0x200000 << order)
+            .is_none()
+        {
+            self.buddy_alloc.free(phys_addr.as_u64() as _, order);
+            self.virt_free(virt_addr, 0x200000 << order);
+            return None;
         }
 
-        key.1
+        Some((
+            slice::from_raw_parts_mut(virt_addr.as_u64() as _, 0x200000 << order),
+            phys_addr,
+        ))
     }
 
-    /// Allocates a chunk of size `2MiB * 2^order`. `order` has to be smaller than 8. The function
-    /// returns the chunk.
-    pub unsafe fn malloc(&mut self, order: usize) -> &'static mut [u8] {
-        if self.chunk_checks {
-            self.chunk_checks = false;
-            while self.virt_addr_alloc.needs_new_chunk() {
-                let chunk = self.malloc(0);
-                self.virt_addr_alloc.add_chunk(chunk);
+    /// Does the same thing as `try_malloc`, but leaves the 2MiB pages right before and right after
+    /// the chunk unmapped, so out of bounds accesses page fault instead of corrupting the
+    /// neighbors. The guard pages take only virtual address space, no physical memory. The chunk
+    /// has to be freed with `free_guarded`.
+    ///
+    /// # Safety
+    /// Same as `malloc`.
+    pub unsafe fn malloc_guarded(&mut self, order: usize) -> Option<&'static mut [u8]> {
+        debug_assert!(order <= Self::max_order(), "Order {} is too large", order);
+        let size = Self::order_size(order);
+        let guarded_size = size + 2 * Self::BASE_SIZE;
+        self.refill_chunks()?;
+
+        let guarded_virt_addr = VirtAddr::new_truncate(self.virt_alloc(guarded_size)? as _);
+        let virt_addr = guarded_virt_addr + Self::BASE_SIZE;
+        let phys_addr = match self.buddy_alloc.malloc(order) {
+            Some(phys_addr) => PhysAddr::new(phys_addr as _),
+            None => {
+                self.virt_free(guarded_virt_addr, guarded_size);
+                return None;
             }
-            while self.buddy_alloc.free_list_alloc.needs_new_chunk() {
-                let chunk = self.malloc(0);
-                self.buddy_alloc.free_list_alloc.add_chunk(chunk);
+        };
+
+        if self.map_chunk(phys_addr, virt_addr, size).is_none() {
+            self.buddy_alloc.free(phys_addr.as_u64() as _, order);
+            self.virt_free(guarded_virt_addr, guarded_size);
+            return None;
+        }
+
+        Some(slice::from_raw_parts_mut(virt_addr.as_u64() as _, size))
+    }
+
+    /// Gives the first 2MiB of physical memory to the buddy allocator, once early boot doesn't need
+    /// them anymore. Returns false and does nothing if they hold the kernel or the Multiboot2
+    /// information structure, or if it was already done.
+    ///
+    /// If the first 2MiB are still identity mapped, the mapping is removed and flushed from the
+    /// TLB, so when this succeeds a null pointer dereference page faults. The freed frame is mapped
+    /// only where `malloc` maps it, never at 0.
+    ///
+    /// # Safety
+    /// The first 2MiB must be usable RAM in the memory map, and nothing may still use them, like
+    /// the BIOS data areas.
+    ///
+    /// # Panics
+    /// Panics if it ran out of memory for the bookkeeping.
+    pub unsafe fn unmap_low_identity(&mut self) -> bool {
+        if !self.low_chunk_reserved {
+            return false;
+        }
+        self.low_chunk_reserved = false;
+
+        // The PDP entry 0 may hold the PD tables' address without being present.
+        if self.pdp_tables[0][0]
+            .flags()
+            .contains(PageTableFlags::PRESENT)
+        {
+            let entry = &mut (&mut *self.pd_table(0, 0))[0];
+            if !entry.is_unused() {
+                entry.set_unused();
+                // The host tests run in user mode, where `invlpg` faults.
+                if !cfg!(test) {
+                    tlb::flush(VirtAddr::zero());
+                }
             }
-            self.chunk_checks = true;
         }
+        self.buddy_alloc.free(0, 0);
+        self.refill_chunks().expect("Out of memory");
+        true
+    }
+
+    /// Frees a chunk of order `order` that was returned by `malloc`, `try_malloc` or
+    /// `malloc_aligned`. It's unmapped, and its physical memory and virtual address range are
+    /// given back.
+    ///
+    /// # Safety
+    /// Same as `malloc`, and nothing may use `chunk` once it's freed.
+    ///
+    /// # Panics
+    /// Panics if `chunk` isn't a mapped chunk of order `order`, or if it ran out of memory for
+    /// the bookkeeping.
+    pub unsafe fn free(&mut self, chunk: &'static mut [u8], order: usize) {
+        let virt_addr = self.release_chunk(chunk, order);
+        self.virt_free(virt_addr, Self::order_size(order));
+    }
+
+    /// Frees a chunk of order `order` that was returned by `malloc_guarded`. Like `free`, but the
+    /// virtual address range that is given back includes the guard pages.
+    ///
+    /// # Safety
+    /// Same as `free`.
+    ///
+    /// # Panics
+    /// Panics if `chunk` isn't a mapped chunk of order `order`, or if it ran out of memory for
+    /// the bookkeeping.
+    pub unsafe fn free_guarded(&mut self, chunk: &'static mut [u8], order: usize) {
+        let virt_addr = self.release_chunk(chunk, order);
+        self.virt_free(
+            virt_addr - Self::BASE_SIZE,
+            Self::order_size(order) + 2 * Self::BASE_SIZE,
+        );
+    }
+
+    /// Unmaps `chunk` and gives its physical memory back, its virtual address range is left to
+    /// the caller. Returns the chunk's virtual address.
+    unsafe fn release_chunk(&mut self, chunk: &'static mut [u8], order: usize) -> VirtAddr {
+        debug_assert!(order <= Self::max_order(), "Order {} is too large", order);
+        let size = Self::order_size(order);
+        assert_eq!(
+            chunk.len(),
+            size,
+            "The chunk's size doesn't match the order {}",
+            order
+        );
+        let virt_addr = VirtAddr::from_ptr(chunk.as_ptr());
+
+        let phys_addr = self.unmap_chunk(virt_addr, size);
+        self.buddy_alloc.free(phys_addr.as_u64() as _, order);
 
-        let phys_addr = PhysAddr::new(self.buddy_alloc.malloc(order).unwrap() as _);
-        let virt_addr = VirtAddr::new_truncate(self.virt_alloc(0x200000 << order) as _);
+        self.refill_chunks().expect("Out of memory");
+        virt_addr
+    }
 
-        for i in (0..0x200000usize << order).step_by(0x200000) {
-            let phys_addr = phys_addr + i;
+    /// Unmaps the `size` bytes that `map_chunk` mapped at `virt_addr`, and returns the physical
+    /// address they were mapped to.
+    unsafe fn unmap_chunk(&mut self, virt_addr: VirtAddr, size: usize) -> PhysAddr {
+        let mut chunk_phys_addr = None;
+        for i in (0..size).step_by(0x200000) {
             let virt_addr = virt_addr + i;
 
-            let pdp_table = &mut self.pdp_tables[usize::from(virt_addr.p4_index())];
+            let pdp_table = &self.pdp_tables[usize::from(virt_addr.p4_index())];
+            assert!(
+                !pdp_table[virt_addr.p3_index()].is_unused(),
+                "{:?} isn't mapped",
+                virt_addr
+            );
+            let pd_table = &mut *self.pd_table(
+                u16::from(virt_addr.p4_index()),
+                u16::from(virt_addr.p3_index()),
+            );
 
-            let pd_addr = (511 << 39)
-                | (511 << 30)
-                | (usize::from(virt_addr.p4_index()) << 21)
-                | (usize::from(virt_addr.p3_index()) << 12);
+            let entry = &mut pd_table[virt_addr.p2_index()];
+            assert!(
+                entry
+                    .flags()
+                    .contains(PageTableFlags::HUGE_PAGE | PageTableFlags::PRESENT),
+                "{:?} isn't mapped with a 2MiB page",
+                virt_addr
+            );
+            let phys_addr = *chunk_phys_addr.get_or_insert(entry.addr()) + i;
+            assert_eq!(
+                entry.addr(),
+                phys_addr,
+                "{:?} isn't physically contiguous",
+                virt_addr
+            );
 
-            if pdp_table[virt_addr.p3_index()].is_unused() {
-                let phys_pd_addr = if pdp_table[0].is_unused() {
-                    let pd0_addr = self.buddy_alloc.malloc(0).unwrap() as u64;
+            entry.set_unused();
+            // The host tests run in user mode, where `invlpg` faults.
+            if !cfg!(test) {
+                tlb::flush(virt_addr);
+            }
+        }
+        chunk_phys_addr.unwrap()
+    }
 
-                    (*Self::SUPER_PD_TABLE)[virt_addr.p4_index()].set_addr(
-                        PhysAddr::new(pd0_addr),
-                        PageTableFlags::HUGE_PAGE
-                            | PageTableFlags::WRITABLE
-                            | PageTableFlags::PRESENT,
-                    );
+    /// Maps `size` bytes of physical memory at `phys_addr` to `virt_addr` with 2MiB pages.
+    ///
+    /// Returns `None` if it ran out of memory for the PD tables, nothing of the chunk stays mapped
+    /// then.
+    #[must_use]
+    unsafe fn map_chunk(
+        &mut self,
+        phys_addr: PhysAddr,
+        virt_addr: VirtAddr,
+        size: usize,
+    ) -> Option<()> {
+        for i in (0..size).step_by(0x200000) {
+            if self.map_huge_page(phys_addr + i, virt_addr + i).is_none() {
+                if i != 0 {
+                    self.unmap_chunk(virt_addr, i);
+                }
+                return None;
+            }
+        }
+        Some(())
+    }
 
-                    pdp_table[0].set_addr(PhysAddr::new(pd0_addr), PageTableFlags::WRITABLE);
-                    PhysAddr::new(pd0_addr + 4096 * u64::from(virt_addr.p3_index()))
-                } else {
-                    let pd0_addr = pdp_table[0].addr();
+    /// Maps the 2MiB page at `virt_addr` to `phys_addr`. Returns `None` if the PML4 entry has no
+    /// PD tables yet, and there's no chunk for them.
+    unsafe fn map_huge_page(&mut self, phys_addr: PhysAddr, virt_addr: VirtAddr) -> Option<()> {
+        let pd_table_ptr = self.pd_table(
+            u16::from(virt_addr.p4_index()),
+            u16::from(virt_addr.p3_index()),
+        );
+        // The PD table that maps the PD tables.
+        let super_pd_table = self.pd_table(511, 511);
 
-                    pd0_addr + 4096 * u64::from(virt_addr.p3_index())
-                };
+        let pdp_table = &mut self.pdp_tables[usize::from(virt_addr.p4_index())];
 
-                ptr::write(pd_addr as *mut _, PageTable::new());
+        if pdp_table[virt_addr.p3_index()].is_unused() {
+            let phys_pd_addr = if pdp_table[0].is_unused() {
+                let pd0_addr = self.buddy_alloc.malloc(0)? as u64;
 
-                pdp_table[virt_addr.p3_index()].set_addr(
-                    phys_pd_addr,
-                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
+                (&mut *super_pd_table)[virt_addr.p4_index()].set_addr(
+                    PhysAddr::new(pd0_addr),
+                    PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
                 );
-            }
 
-            let pd_table = &mut *(pd_addr as *mut PageTable);
+                pdp_table[0].set_addr(PhysAddr::new(pd0_addr), PageTableFlags::WRITABLE);
+                PhysAddr::new(pd0_addr + 4096 * u64::from(virt_addr.p3_index()))
+            } else {
+                let pd0_addr = pdp_table[0].addr();
 
-            assert!(pd_table[virt_addr.p2_index()].is_unused());
+                pd0_addr + 4096 * u64::from(virt_addr.p3_index())
+            };
 
-            pd_table[virt_addr.p2_index()].set_addr(
-                phys_addr,
-                PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
+            ptr::write(pd_table_ptr, PageTable::new());
+
+            pdp_table[virt_addr.p3_index()].set_addr(
+                phys_pd_addr,
+                PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
+            );
+        }
+
+        let pd_table = &mut *pd_table_ptr;
+
+        assert!(pd_table[virt_addr.p2_index()].is_unused());
+
+        pd_table[virt_addr.p2_index()].set_addr(
+            phys_addr,
+            PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
+        );
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem;
+
+    /// Returns a leaked 2MiB chunk for a tree.
+    fn chunk() -> &'static mut [u8] {
+        std::vec![0; 0x200000].leak()
+    }
+
+    /// Returns the free ranges of `virt_addr_alloc` as `(address, size)` pairs.
+    fn free_list(virt_addr_alloc: &VirtualMemoryAllocator) -> std::vec::Vec<(usize, usize)> {
+        virt_addr_alloc
+            .free_list()
+            .map(|(ptr, size)| (ptr.as_ptr() as usize, size))
+            .collect()
+    }
+
+    #[test]
+    fn seed_virtual_allocator_merges_used_ranges() {
+        let virt_addr_alloc = seed_virtual_allocator(
+            chunk(),
+            [
+                0x400000..0x800000,
+                // Overlaps the previous range.
+                0x600000..0xa00000,
+                // Touches the previous range.
+                0xa00000..0xc00000,
+                0x1000000..0x1200000,
+                0x2000000..1 << 48,
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            free_list(&virt_addr_alloc),
+            [
+                (0x200000, 0x200000),
+                (0xc00000, 0x400000),
+                (0x1200000, 0xe00000)
+            ]
+        );
+        mem::forget(virt_addr_alloc);
+    }
+
+    #[test]
+    fn seed_virtual_allocator_skips_the_first_2mib() {
+        let virt_addr_alloc =
+            seed_virtual_allocator(chunk(), [0..0x1000, 0x100000..0x300000, 0x600000..1 << 48])
+                .unwrap();
+        assert_eq!(free_list(&virt_addr_alloc), [(0x300000, 0x300000)]);
+        mem::forget(virt_addr_alloc);
+
+        let virt_addr_alloc =
+            seed_virtual_allocator(chunk(), [0x800000..0xa00000, 0xa00000..1 << 48]).unwrap();
+        assert_eq!(free_list(&virt_addr_alloc), [(0x200000, 0x600000)]);
+        mem::forget(virt_addr_alloc);
+    }
+
+    /// Returns an allocator whose physical memory is all used, except for the first `free_chunks`
+    /// order 0 chunks, with the virtual address space `free_areas`. Its PD tables are at
+    /// `RECURSIVE_BASE`, so what touches them can't run on the host, see `mock_pd_tables`.
+    fn allocator(free_chunks: usize, free_areas: &[(usize, usize)]) -> GlobalChunkAllocator {
+        let mut buddy_alloc = BuddyAllocator {
+            buddies: core::array::from_fn(|_| Buddies {
+                bitmap: std::vec![!0; 1].leak(),
+                free_list: None,
+                num_buddies: 64,
+            }),
+            free_list_alloc: SlabAllocator::new(chunk()),
+            base_size: GlobalChunkAllocator::BASE_SIZE,
+            offset: 0,
+        };
+        for i in 0..free_chunks {
+            buddy_alloc.free(i * GlobalChunkAllocator::BASE_SIZE, 0);
+        }
+
+        let mut free_areas: std::vec::Vec<_> = free_areas
+            .iter()
+            .map(|&(ptr, size)| (ptr::NonNull::new(ptr as *mut u8).unwrap(), size))
+            .collect();
+        GlobalChunkAllocator {
+            buddy_alloc,
+            virt_addr_alloc: VirtualMemoryAllocator::new(&mut free_areas, chunk()),
+            pml4_table: PageTable::new(),
+            pdp_tables: std::vec![PageTable::new(); 512].leak().try_into().unwrap(),
+            low_chunk_reserved: false,
+            recursive_base: RECURSIVE_BASE,
+        }
+    }
+
+    /// Moves the PD tables of `allocator` to a zeroed buffer, which is mapped nowhere, so the page
+    /// tables can be inspected but the chunks can't be accessed.
+    fn mock_pd_tables(allocator: &mut GlobalChunkAllocator) {
+        let layout = Layout::from_size_align(1 << 30, 0x200000).unwrap();
+        allocator.recursive_base = unsafe { std::alloc::alloc_zeroed(layout) } as usize;
+    }
+
+    #[test]
+    fn malloc_fails_once_the_virtual_space_is_exhausted() {
+        let mut allocator = allocator(1, &[(0x200000, 0x200000), (0x800000, 0x400000)]);
+
+        unsafe {
+            // 4MiB of physical memory, but only 2MiB ranges of virtual address space.
+            assert!(allocator.virt_alloc(0x400000).is_some());
+            assert!(allocator.malloc_aligned_no_refill(1, 1).is_none());
+            assert!(allocator.virt_alloc(0x200000).is_some());
+            assert!(allocator.malloc_no_refill(0).is_none());
+        }
+        // The physical chunk is still free.
+        assert_eq!(allocator.buddy_alloc.free_chunk_count(), 1);
+        allocator.buddy_alloc.verify_consistency();
+        mem::forget(allocator);
+    }
+
+    #[test]
+    fn malloc_gives_the_virtual_range_back_when_out_of_memory() {
+        let mut allocator = allocator(0, &[(0x200000, 0x200000), (0x800000, 0x400000)]);
+
+        unsafe {
+            assert!(allocator.malloc_no_refill(0).is_none());
+            assert!(allocator.malloc_aligned_no_refill(1, 1).is_none());
+        }
+        assert_eq!(
+            free_list(&allocator.virt_addr_alloc),
+            [(0x200000, 0x200000), (0x800000, 0x400000)]
+        );
+        mem::forget(allocator);
+    }
+
+    /// Returns the physical address `chunk` is mapped to, for an allocator whose virtual address
+    /// space is `0x40000000..0x80000000`, which is the PD table of the PDP entry 1.
+    fn phys_addr(allocator: &GlobalChunkAllocator, chunk: &[u8]) -> PhysAddr {
+        let pd_table = unsafe { &*allocator.pd_table(0, 1) };
+        pd_table[(chunk.as_ptr() as usize - 0x40000000) >> 21].addr()
+    }
+
+    #[test]
+    fn malloc_aligned_aligns_the_physical_address() {
+        // With chunk 0 taken, the first free chunk is only 2MiB aligned.
+        let mut allocator = allocator(16, &[(0x40000000, 0x40000000)]);
+        mock_pd_tables(&mut allocator);
+        assert_eq!(allocator.buddy_alloc.malloc(0), Some(0));
+
+        for phys_align in [0x400000, 0x1000000] {
+            let chunk = unsafe { allocator.malloc_aligned(0, phys_align) }.unwrap();
+            let phys_addr = phys_addr(&allocator, chunk);
+            assert_ne!(phys_addr.as_u64(), 0);
+            assert_eq!(phys_addr.as_u64() % phys_align as u64, 0);
+            assert_eq!(chunk.len(), 0x200000);
+        }
+        let chunk = unsafe { allocator.malloc_aligned(1, 0x800000) }.unwrap();
+        assert_eq!(phys_addr(&allocator, chunk).as_u64() % 0x800000, 0);
+        assert_eq!(chunk.len(), 0x400000);
+
+        allocator.buddy_alloc.verify_consistency();
+        mem::forget(allocator);
+    }
+
+    #[test]
+    fn malloc_aligned_fails_when_no_chunk_is_aligned() {
+        let mut allocator = allocator(4, &[(0x40000000, 0x40000000)]);
+        assert_eq!(allocator.buddy_alloc.malloc(0), Some(0));
+
+        // The chunks 1 to 3 are free, and none of them is 16MiB aligned.
+        assert!(unsafe { allocator.malloc_aligned(0, 0x1000000) }.is_none());
+        // Whatever it took is given back.
+        assert_eq!(allocator.buddy_alloc.free_chunk_count(), 3);
+        assert_eq!(
+            free_list(&allocator.virt_addr_alloc),
+            [(0x40000000, 0x40000000)]
+        );
+        allocator.buddy_alloc.verify_consistency();
+        mem::forget(allocator);
+    }
+
+    #[test]
+    fn malloc_guarded_leaves_the_guard_pages_unmapped() {
+        // An order 1 chunk and an order 0 chunk for the PD tables.
+        let mut allocator = allocator(3, &[(0x40000000, 0x40000000)]);
+        mock_pd_tables(&mut allocator);
+
+        let chunk = unsafe { allocator.malloc_guarded(1) }.unwrap();
+        assert_eq!(chunk.as_ptr() as usize, 0x40200000);
+        assert_eq!(chunk.len(), 0x400000);
+        assert_eq!(
+            free_list(&allocator.virt_addr_alloc),
+            [(0x40800000, 0x3f800000)]
+        );
+
+        // The PD table of the PML4 entry 0 and the PDP entry 1.
+        let pd_table = unsafe { &*allocator.pd_table(0, 1) };
+        assert!(pd_table[0].is_unused());
+        for entry in pd_table.iter().skip(1).take(2) {
+            assert_eq!(
+                entry.flags(),
+                PageTableFlags::HUGE_PAGE | PageTableFlags::WRITABLE | PageTableFlags::PRESENT
+            );
+        }
+        assert_eq!(pd_table[1].addr(), PhysAddr::new(0));
+        assert_eq!(pd_table[2].addr(), PhysAddr::new(0x200000));
+        assert!(pd_table[3].is_unused());
+
+        unsafe { allocator.free_guarded(chunk, 1) };
+        let pd_table = unsafe { &*allocator.pd_table(0, 1) };
+        assert!(pd_table.iter().all(|entry| entry.is_unused()));
+        // The guard pages' range is given back too.
+        assert_eq!(
+            free_list(&allocator.virt_addr_alloc),
+            [(0x40000000, 0x40000000)]
+        );
+        // Only the PD tables' chunk stays allocated.
+        assert_eq!(allocator.buddy_alloc.free_chunk_count(), 2);
+        allocator.buddy_alloc.verify_consistency();
+        mem::forget(allocator);
+    }
+
+    #[test]
+    fn malloc_guarded_gives_the_virtual_range_back_when_out_of_memory() {
+        let mut allocator = allocator(1, &[(0x40000000, 0x40000000)]);
+
+        assert!(unsafe { allocator.malloc_guarded(1) }.is_none());
+        assert_eq!(
+            free_list(&allocator.virt_addr_alloc),
+            [(0x40000000, 0x40000000)]
+        );
+        mem::forget(allocator);
+    }
+
+    #[test]
+    fn unmap_low_identity_unmaps_and_frees_the_first_2mib() {
+        let mut allocator = allocator(0, &[(0x40000000, 0x40000000)]);
+        mock_pd_tables(&mut allocator);
+        allocator.low_chunk_reserved = true;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::PRESENT;
+        allocator.pdp_tables[0][0].set_addr(PhysAddr::new(0x200000), flags);
+        unsafe {
+            (&mut *allocator.pd_table(0, 0))[0]
+                .set_addr(PhysAddr::new(0), flags | PageTableFlags::HUGE_PAGE);
+        }
+
+        assert!(unsafe { allocator.unmap_low_identity() });
+        assert!(unsafe { &*allocator.pd_table(0, 0) }[0].is_unused());
+        assert_eq!(allocator.buddy_alloc.free_chunk_count(), 1);
+        // It's done only once.
+        assert!(!unsafe { allocator.unmap_low_identity() });
+        assert_eq!(allocator.buddy_alloc.free_chunk_count(), 1);
+        allocator.buddy_alloc.verify_consistency();
+        mem::forget(allocator);
+    }
+
+    #[test]
+    fn unmap_low_identity_frees_the_first_2mib_when_unmapped() {
+        let mut allocator = allocator(0, &[(0x40000000, 0x40000000)]);
+        mock_pd_tables(&mut allocator);
+        allocator.low_chunk_reserved = true;
+
+        assert!(unsafe { allocator.unmap_low_identity() });
+        assert!(unsafe { &*allocator.pd_table(0, 0) }[0].is_unused());
+        assert_eq!(allocator.buddy_alloc.free_chunk_count(), 1);
+        mem::forget(allocator);
+    }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn seed_virtual_allocator_rejects_inverted_ranges() {
+        assert_eq!(
+            seed_virtual_allocator(chunk(), [0x400000..0x600000, 0x1000000..0x800000]).err(),
+            Some(MemInitError::InvalidUsedRange {
+                start: 0x1000000,
+                end: 0x800000
+            })
+        );
+    }
+
+    #[test]
+    fn build_initial_page_tables_rejects_too_many_mappings() {
+        let mut allocator = allocator(4, &[]);
+
+        let identity_mapped =
+            core::array::from_fn::<_, 10, _>(|i| i * 0x200000..(i + 1) * 0x200000);
+        let result = unsafe {
+            build_initial_page_tables(&mut allocator.buddy_alloc, identity_mapped, 0x800000)
+        };
+        assert_eq!(result.err(), Some(MemInitError::TooManyMappings));
+        // It failed before it took the PDP tables' chunk.
+        assert_eq!(allocator.buddy_alloc.free_chunk_count(), 4);
+        mem::forget(allocator);
+    }
+
+    #[test]
+    fn init_leaves_the_globals_untouched_on_failure() {
+        let survey = MemorySurvey {
+            kernel: 0..0,
+            boot_info: 0..0,
+            mem_end: 0,
+            total_ram: 0,
+            usable: crate::stack_vec::StackVec::new(),
+        };
+
+        // `Cr3::write` would fault on the host, so CR3 is untouched too.
+        let result = unsafe { init(&survey, 0) };
+        assert_eq!(result, Err(MemInitError::EmptyMemoryMap));
+        assert!(GLOBAL_CHUNK_ALLOCATOR.lock().is_none());
+    }
+
+    #[test]
+    fn truncate_virt_addr_drops_the_sign_extension() {
+        assert_eq!(
+            truncate_virt_addr(VirtAddr::new(KERNEL_STACK_VIRT as _)),
+            KERNEL_STACK_VIRT & (1 << 48) - 1
+        );
+        assert_eq!(truncate_virt_addr(VirtAddr::new(0x200000)), 0x200000);
+    }
+
+    #[test]
+    #[should_panic(expected = "The global chunk allocator isn't initialized")]
+    fn kmalloc_panics_before_init() {
+        unsafe {
+            kmalloc(0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "The global chunk allocator isn't initialized")]
+    fn kfree_panics_before_init() {
+        unsafe { kfree(chunk(), 0) };
+    }
+
+    /// The number of times `oom_handler` was called.
+    static OOM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Asks for a retry until it's called 3 times.
+    fn oom_handler(layout: Layout) -> bool {
+        assert_eq!(layout.size(), 0x800000);
+        OOM_CALLS.fetch_add(1, Ordering::Relaxed) < 2
+    }
+
+    #[test]
+    fn oom_handler_is_called_and_asks_for_retries() {
+        let layout = Layout::from_size_align(0x800000, 0x200000).unwrap();
+
+        // Without a handler, the first failure is final.
+        let mut attempts = 0;
+        assert_eq!(
+            retry_on_oom(layout, || {
+                attempts += 1;
+                None::<()>
+            }),
+            None
+        );
+        assert_eq!(attempts, 1);
+
+        set_oom_handler(oom_handler);
+        // The handler frees enough memory after its second call.
+        let mut attempts = 0;
+        let result = retry_on_oom(layout, || {
+            attempts += 1;
+            (attempts == 3).then_some(attempts)
+        });
+        assert_eq!(result, Some(3));
+        assert_eq!(OOM_CALLS.load(Ordering::Relaxed), 2);
+
+        // The third call doesn't ask for a retry.
+        let mut attempts = 0;
+        let result = retry_on_oom(layout, || {
+            attempts += 1;
+            None::<()>
+        });
+        assert_eq!(result, None);
+        assert_eq!(attempts, 1);
+        assert_eq!(OOM_CALLS.load(Ordering::Relaxed), 3);
+        clear_oom_handler();
+    }
+
+    #[test]
+    fn malloc_phys_returns_the_mapped_aligned_address() {
+        // With chunk 0 taken, the first free chunk is only 2MiB aligned.
+        let mut allocator = allocator(32, &[(0x40000000, 0x40000000)]);
+        mock_pd_tables(&mut allocator);
+        assert_eq!(allocator.buddy_alloc.malloc(0), Some(0));
+
+        for order in [0, 2, 1, 3] {
+            let (chunk, phys) = unsafe { allocator.malloc_phys(order) }.unwrap();
+            assert_eq!(chunk.len(), GlobalChunkAllocator::order_size(order));
+            assert_ne!(phys.as_u64(), 0);
+            assert_eq!(
+                phys.as_u64() % GlobalChunkAllocator::order_size(order) as u64,
+                0
             );
+            assert_eq!(phys_addr(&allocator, chunk), phys);
         }
+        allocator.buddy_alloc.verify_consistency();
+        mem::forget(allocator);
+    }
 
-        slice::from_raw_parts_mut(virt_addr.as_u64() as _, 0x200000 << order)
+    #[test]
+    #[should_panic(expected = "Free list entry 0x1000 of order 0 isn't a block")]
+    fn verify_consistency_catches_a_misaligned_free_list_entry() {
+        // The slab boxes panic when they're dropped, so the unwinding mustn't drop them.
+        let mut allocator = mem::ManuallyDrop::new(allocator(2, &[]));
+        allocator.buddy_alloc.verify_consistency();
+
+        let buddy_alloc = &mut allocator.buddy_alloc;
+        buddy_alloc.buddies[0].free_list = Some(SlabBox::new(
+            &mut buddy_alloc.free_list_alloc,
+            BuddyFreeList {
+                ptr: 0x1000,
+                next: buddy_alloc.buddies[0].free_list.take(),
+            },
+        ));
+        buddy_alloc.verify_consistency();
+    }
+
+    #[test]
+    #[should_panic(expected = "Unused block 0x800000 of order 0 is missing from the free list")]
+    fn verify_consistency_catches_a_missing_free_list_entry() {
+        let mut allocator = mem::ManuallyDrop::new(allocator(2, &[]));
+        allocator.buddy_alloc.set_unused(0, 4);
+        allocator.buddy_alloc.verify_consistency();
+    }
+
+    #[test]
+    fn order_size_and_max_order() {
+        assert_eq!(GlobalChunkAllocator::max_order(), GLOBAL_BUDDY_DEPTH - 1);
+        assert_eq!(GlobalChunkAllocator::max_order(), 7);
+        assert_eq!(GlobalChunkAllocator::order_size(0), 0x200000);
+        assert_eq!(GlobalChunkAllocator::order_size(1), 0x400000);
+        assert_eq!(
+            GlobalChunkAllocator::order_size(GlobalChunkAllocator::max_order()),
+            256 << 20
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Order 8 is too large")]
+    fn malloc_rejects_orders_above_max_order() {
+        let mut allocator = mem::ManuallyDrop::new(allocator(0, &[]));
+        unsafe { allocator.try_malloc(GlobalChunkAllocator::max_order() + 1) };
+    }
+
+    #[test]
+    fn pd_table_addresses_match_the_recursive_mapping() {
+        let base = (0xffff << 48) | (511 << 39) | (511 << 30);
+        assert_eq!(RECURSIVE_BASE, base);
+
+        for (p4, p3) in [
+            (0, 0),
+            (0, 1),
+            (1, 0),
+            (255, 7),
+            (256, 300),
+            (510, 1),
+            (511, 511),
+        ] {
+            assert_eq!(pd_tables_addr(p4), base | (p4 as usize) << 21);
+            assert_eq!(
+                pd_table_addr(p4, p3) as usize,
+                base | (p4 as usize) << 21 | (p3 as usize) << 12
+            );
+
+            // The PD table is the page of the recursive region that the PD tables map.
+            let virt_addr = VirtAddr::new(pd_table_addr(p4, p3) as u64);
+            assert_eq!(u16::from(virt_addr.p4_index()), 511);
+            assert_eq!(u16::from(virt_addr.p3_index()), 511);
+            assert_eq!(u16::from(virt_addr.p2_index()), p4);
+            assert_eq!(u16::from(virt_addr.p1_index()), p3);
+        }
+    }
+
+    #[test]
+    fn mem_segment_conversions_keep_the_fields() {
+        let segment = MemSegment {
+            ptr: 0x400000,
+            size: 0x200000,
+        };
+        let by_size = MemSegmentOrdBySize::from(segment);
+        assert_eq!((by_size.ptr, by_size.size), (0x400000, 0x200000));
+        assert_eq!(MemSegment::from(by_size), segment);
+
+        // A lower address but a larger size.
+        let other = MemSegment {
+            ptr: 0x200000,
+            size: 0x600000,
+        };
+        assert!(other < segment);
+        assert!(MemSegmentOrdBySize::from(other) > by_size);
+        // The address breaks the ties of the sizes.
+        let same_size = MemSegment {
+            ptr: 0x800000,
+            size: 0x200000,
+        };
+        assert!(MemSegmentOrdBySize::from(same_size) > by_size);
+    }
+
+    #[test]
+    fn build_buddy_allocator_over_a_small_map() {
+        let survey = MemorySurvey {
+            kernel: 0x100000..0x180000,
+            boot_info: 0x180000..0x181000,
+            mem_end: 0x10000000,
+            total_ram: 0x9fc00 + 0xff00000,
+            usable: [0..0x9fc00, 0x100000..0x10000000].into(),
+        };
+        // The bitmaps' and the free lists' frames are the first two free frames, 0x200000 and
+        // 0x600000, the stack is in between. Only they're accessed, in this buffer.
+        let phys_offset = std::vec![0u64; 0x800000 / 8].leak().as_mut_ptr() as usize;
+
+        let (mut buddy_alloc, chunks) =
+            unsafe { build_buddy_allocator(&survey, 0x400000, phys_offset) }.unwrap();
+        assert_eq!(
+            chunks,
+            [0..0x200000, 0x200000..0x400000, 0x600000..0x800000]
+        );
+        buddy_alloc.verify_consistency();
+        // 256MiB, without the kernel's, the bitmaps', the free lists' and the stack's chunks.
+        assert_eq!(buddy_alloc.free_chunk_count(), 128 - 4);
+
+        let mut allocated = std::vec::Vec::new();
+        while let Some(ptr) = buddy_alloc.malloc(0) {
+            assert!(ptr % 0x200000 == 0 && ptr < 0x10000000);
+            assert!(!(0..0x800000).contains(&ptr), "0x{:x} is used", ptr);
+            allocated.push(ptr);
+        }
+        assert_eq!(allocated.len(), 128 - 4);
+        buddy_alloc.verify_consistency();
+        mem::forget(buddy_alloc);
     }
 }
@@ -0,0 +1,107 @@
+use core::ptr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB};
+
+/// A frame allocator that recycles the frames it's given back, and takes new frames from `inner`
+/// only when there are no freed frames.
+///
+/// The free list is intrusive, every freed frame stores the next one in its first bytes, so the
+/// physical memory must be identity mapped. It will be used before the buddy allocator exists.
+#[derive(Debug)]
+pub struct RecyclingFrameAllocator<A> {
+    inner: A,
+    free_list: Option<PhysFrame<Size2MiB>>,
+}
+
+impl<A: FrameAllocator<Size2MiB>> RecyclingFrameAllocator<A> {
+    /// Creates a new RecyclingFrameAllocator, with an empty free list, on top of `inner`.
+    pub fn new(inner: A) -> Self {
+        Self {
+            inner,
+            free_list: None,
+        }
+    }
+
+    /// Returns the underlying allocator, the freed frames are lost.
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+}
+
+unsafe impl<A: FrameAllocator<Size2MiB>> FrameAllocator<Size2MiB> for RecyclingFrameAllocator<A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        match self.free_list {
+            Some(frame) => {
+                self.free_list = unsafe {
+                    ptr::read(frame.start_address().as_u64() as *const Option<PhysFrame<Size2MiB>>)
+                };
+                Some(frame)
+            }
+            None => self.inner.allocate_frame(),
+        }
+    }
+}
+
+impl<A: FrameAllocator<Size2MiB>> FrameDeallocator<Size2MiB> for RecyclingFrameAllocator<A> {
+    /// Pushes `frame` to the free list, it's the next frame to be allocated.
+    ///
+    /// # Safety
+    /// `frame` must be unused and identity mapped.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        ptr::write(
+            frame.start_address().as_u64() as *mut Option<PhysFrame<Size2MiB>>,
+            self.free_list.take(),
+        );
+        self.free_list = Some(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::PhysAddr;
+
+    /// Hands out the frames at `frames`.
+    struct Inner {
+        frames: std::vec::IntoIter<u64>,
+    }
+
+    unsafe impl FrameAllocator<Size2MiB> for Inner {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+            PhysFrame::from_start_address(PhysAddr::new(self.frames.next()?)).ok()
+        }
+    }
+
+    /// Returns the addresses of `n` leaked 2MiB aligned frames. The test runs with identity
+    /// "physical" memory, so the free list can be written to them.
+    fn frames(n: usize) -> std::vec::Vec<u64> {
+        let memory = std::vec![0u8; (n + 1) * 0x200000].leak();
+        let start = memory.as_ptr() as u64 + 0x1fffff & !0x1fffff;
+        (0..n as u64).map(|i| start + i * 0x200000).collect()
+    }
+
+    #[test]
+    fn reuses_the_freed_frames_in_lifo_order() {
+        let addrs = frames(4);
+        let mut frame_alloc = RecyclingFrameAllocator::new(Inner {
+            frames: addrs.clone().into_iter(),
+        });
+        let address = |frame: Option<PhysFrame<Size2MiB>>| frame.unwrap().start_address().as_u64();
+
+        let allocated: std::vec::Vec<_> = (0..3)
+            .map(|_| frame_alloc.allocate_frame().unwrap())
+            .collect();
+        for &frame in &allocated {
+            unsafe { frame_alloc.deallocate_frame(frame) };
+        }
+        for &frame in allocated.iter().rev() {
+            assert_eq!(frame_alloc.allocate_frame(), Some(frame));
+        }
+
+        unsafe { frame_alloc.deallocate_frame(allocated[1]) };
+        assert_eq!(address(frame_alloc.allocate_frame()), addrs[1]);
+
+        // The free list is empty, so the frames come from the inner allocator again.
+        assert_eq!(address(frame_alloc.allocate_frame()), addrs[3]);
+        assert_eq!(frame_alloc.allocate_frame(), None);
+    }
+}
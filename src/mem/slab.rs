@@ -1,16 +1,34 @@
 use core::marker::PhantomData;
-use core::mem::{self, size_of};
-use core::{fmt, ops, ptr};
+use core::mem::{self, align_of, size_of};
+use core::{fmt, iter, ops, ptr, slice};
 
-/// A slab allocator, that allocates only type T. It needs a page allocator, but it never
-/// deallocates.
+/// A slab allocator, that allocates only type T. It needs a page allocator, chunks that became
+/// entirely free can be given back with `reclaim_chunk`.
 #[derive(Debug)]
 pub struct SlabAllocator<T> {
     free_size: usize,
     free_list: ptr::NonNull<SlabFreeList>,
+    chunks: ptr::NonNull<SlabChunk>,
     _phantom: PhantomData<T>,
 }
 
+/// The header at the start of every chunk of a slab allocator, the chunks form a linked list.
+#[derive(Debug, Clone, Copy)]
+struct SlabChunk {
+    /// The address of the chunk as it was added, before the alignment and the header.
+    start: usize,
+    /// The size of the chunk as it was added.
+    len: usize,
+    /// The address of the first slab.
+    slabs_start: usize,
+    /// The size of the slabs area, a multiple of the slab size.
+    slabs_size: usize,
+    /// The address of the chunk this one was split from, if the owner split a chunk between two
+    /// allocators. Both parts record the same address, see `add_split_chunk`.
+    split_from: Option<usize>,
+    next: Option<ptr::NonNull<SlabChunk>>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C, align(16))]
 struct SlabFreeList {
@@ -19,34 +37,109 @@ struct SlabFreeList {
 }
 
 impl<T: Sized> SlabAllocator<T> {
-    const SLAB_SIZE: usize = size_of::<T>();
+    const SLAB_ALIGN: usize = if align_of::<T>() < 16 {
+        16
+    } else {
+        align_of::<T>()
+    };
+    /// The size of the generation tag after the value in every slab, it exists only in debug
+    /// builds. It takes `SLAB_ALIGN` bytes so the slabs stay a multiple of their alignment.
+    const TAG_SIZE: usize = if cfg!(debug_assertions) {
+        Self::SLAB_ALIGN
+    } else {
+        0
+    };
+    const SLAB_SIZE: usize = size_of::<T>() + Self::TAG_SIZE;
+
+    /// Returns the generation tag of the slab at `ptr`. It's bumped whenever the slab is
+    /// allocated or freed, so a box whose slab was freed or reused has a stale generation.
+    #[cfg(debug_assertions)]
+    #[inline]
+    fn generation(ptr: ptr::NonNull<T>) -> *mut u64 {
+        (ptr.as_ptr() as usize + size_of::<T>()) as *mut u64
+    }
+
+    /// Skips the start of `chunk` up to a `SLAB_ALIGN` aligned address. The slabs are a multiple
+    /// of their alignment in size, so every slab after the first is aligned too.
+    fn align_chunk(chunk: &'static mut [u8]) -> &'static mut [u8] {
+        let offset = chunk
+            .as_ptr()
+            .align_offset(Self::SLAB_ALIGN)
+            .min(chunk.len());
+        &mut chunk[offset..]
+    }
+
+    /// Writes the header of `chunk` at its start, and returns it. The slabs area is left
+    /// uninitialized.
+    unsafe fn init_chunk(
+        chunk: &'static mut [u8],
+        split_from: Option<usize>,
+        next: Option<ptr::NonNull<SlabChunk>>,
+    ) -> ptr::NonNull<SlabChunk> {
+        let (start, len) = (chunk.as_ptr() as usize, chunk.len());
+        let chunk = Self::align_chunk(chunk);
+        let header_size = size_of::<SlabChunk>() + Self::SLAB_ALIGN - 1 & !(Self::SLAB_ALIGN - 1);
+        assert!(
+            header_size + Self::SLAB_SIZE <= chunk.len(),
+            "Slab allocator's chunk, {} bytes, is too small",
+            len,
+        );
+
+        let header = ptr::NonNull::new(chunk.as_mut_ptr() as *mut SlabChunk).unwrap();
+        let slabs = &chunk[header_size..];
+        header.as_ptr().write(SlabChunk {
+            start,
+            len,
+            slabs_start: slabs.as_ptr() as usize,
+            slabs_size: slabs.len() - slabs.len() % Self::SLAB_SIZE,
+            split_from,
+            next,
+        });
+        header
+    }
 
     /// Creates a new slab allocator from a page allocator.
     ///
     /// # Safety
     /// `chunk_addr` has to be a pointer to a chunk of 2 MiB.
     pub fn new(chunk: &'static mut [u8]) -> Self {
+        Self::with_chunk(chunk, None)
+    }
+
+    /// Same as `new`, but `chunk` is a part of the chunk at `split_from`, which the owner split
+    /// between two allocators. See `add_split_chunk`.
+    pub fn new_split(chunk: &'static mut [u8], split_from: usize) -> Self {
+        Self::with_chunk(chunk, Some(split_from))
+    }
+
+    fn with_chunk(chunk: &'static mut [u8], split_from: Option<usize>) -> Self {
         unsafe {
             assert_eq!(size_of::<SlabFreeList>(), 16);
 
             assert!(
-                16 <= Self::SLAB_SIZE,
+                16 <= size_of::<T>(),
                 "Slab allocator's type T size, {} bytes, is smaller than 16 bytes",
-                Self::SLAB_SIZE,
+                size_of::<T>(),
             );
             assert_eq!(Self::SLAB_SIZE & 0xf, 0);
 
-            let free_size = chunk.len() - chunk.len() % Self::SLAB_SIZE;
+            let chunks = Self::init_chunk(chunk, split_from, None);
+            let SlabChunk {
+                slabs_start,
+                slabs_size,
+                ..
+            } = *chunks.as_ref();
             Self {
-                free_size,
+                free_size: slabs_size,
                 free_list: {
-                    let mut free_list = ptr::NonNull::new(chunk.as_mut_ptr() as _).unwrap();
+                    let mut free_list = ptr::NonNull::new(slabs_start as _).unwrap();
                     *free_list.as_mut() = SlabFreeList {
-                        size: free_size,
+                        size: slabs_size,
                         next: None,
                     };
                     free_list
                 },
+                chunks,
                 _phantom: PhantomData,
             }
         }
@@ -54,13 +147,29 @@ impl<T: Sized> SlabAllocator<T> {
 
     /// Allocates a pointer to `T`.
     pub fn add_chunk(&mut self, chunk: &'static mut [u8]) {
-        unsafe {
-            let alloc_size = chunk.len() - chunk.len() % Self::SLAB_SIZE;
+        self.add_chunk_part(chunk, None);
+    }
+
+    /// Same as `add_chunk`, but `chunk` is a part of the chunk at `split_from`, which the owner
+    /// split between this allocator and another one. The address is recorded in the chunk's
+    /// header, so the owner can find both parts with `split_from` before it reclaims them.
+    pub fn add_split_chunk(&mut self, chunk: &'static mut [u8], split_from: usize) {
+        self.add_chunk_part(chunk, Some(split_from));
+    }
 
-            self.free_size += alloc_size;
-            let mut free_list = ptr::NonNull::new(chunk.as_mut_ptr() as _).unwrap();
+    fn add_chunk_part(&mut self, chunk: &'static mut [u8], split_from: Option<usize>) {
+        unsafe {
+            self.chunks = Self::init_chunk(chunk, split_from, Some(self.chunks));
+            let SlabChunk {
+                slabs_start,
+                slabs_size,
+                ..
+            } = *self.chunks.as_ref();
+
+            self.free_size += slabs_size;
+            let mut free_list = ptr::NonNull::new(slabs_start as _).unwrap();
             *free_list.as_mut() = SlabFreeList {
-                size: alloc_size,
+                size: slabs_size,
                 next: Some(self.free_list),
             };
             self.free_list = free_list;
@@ -72,6 +181,126 @@ impl<T: Sized> SlabAllocator<T> {
         self.free_size < 64 * Self::SLAB_SIZE
     }
 
+    /// Returns how many slabs can be allocated before the allocator needs a new chunk.
+    pub fn spare_slabs(&self) -> usize {
+        (self.free_size / Self::SLAB_SIZE).saturating_sub(64)
+    }
+
+    /// Adds chunks from `chunk_supplier` until at least `additional` slabs can be allocated before
+    /// the allocator needs a new chunk, see `spare_slabs`. After it, a burst of `additional`
+    /// allocations never has to stop for a new chunk.
+    pub fn reserve(
+        &mut self,
+        additional: usize,
+        mut chunk_supplier: impl FnMut() -> &'static mut [u8],
+    ) {
+        while self.spare_slabs() < additional {
+            self.add_chunk(chunk_supplier());
+        }
+    }
+
+    /// Returns how many 2 MiB chunks have to be added, so the allocator won't need a new chunk.
+    pub fn chunks_needed(&self) -> usize {
+        let min_free_size = 64 * Self::SLAB_SIZE;
+        if self.free_size < min_free_size {
+            (min_free_size - self.free_size).div_ceil(0x200000)
+        } else {
+            0
+        }
+    }
+
+    fn chunk_headers(&self) -> impl Iterator<Item = &SlabChunk> {
+        iter::successors(Some(unsafe { self.chunks.as_ref() }), |chunk| {
+            chunk.next.map(|next| unsafe { &*next.as_ptr() })
+        })
+    }
+
+    /// Returns the chunks of the allocator, as they were added.
+    pub fn chunks(&self) -> impl Iterator<Item = ops::Range<usize>> + '_ {
+        self.chunk_headers()
+            .map(|chunk| chunk.start..chunk.start + chunk.len)
+    }
+
+    /// Returns the address of the chunk that `chunk`, a chunk returned by `chunks`, was split
+    /// from, or `None` if it was added whole or isn't a chunk of the allocator.
+    pub fn split_from(&self, chunk: &ops::Range<usize>) -> Option<usize> {
+        self.chunk_headers()
+            .find(|header| header.start == chunk.start && header.start + header.len == chunk.end)?
+            .split_from
+    }
+
+    /// Returns true if none of the chunk's slabs are allocated, and it can be removed without the
+    /// allocator needing a new chunk.
+    fn is_chunk_reclaimable(&self, chunk: &SlabChunk) -> bool {
+        let slabs = chunk.slabs_start..chunk.slabs_start + chunk.slabs_size;
+        let free_size: usize =
+            iter::successors(Some(self.free_list), |run| unsafe { run.as_ref().next })
+                .filter(|run| slabs.contains(&(run.as_ptr() as usize)))
+                .map(|run| unsafe { run.as_ref().size })
+                .sum();
+
+        free_size == chunk.slabs_size && 64 * Self::SLAB_SIZE + chunk.slabs_size <= self.free_size
+    }
+
+    /// Returns the chunks that can be given back with `reclaim_chunk`, as they were added. These
+    /// are the chunks that have no allocated slabs, as long as the allocator won't need a new
+    /// chunk without them.
+    ///
+    /// The walk is over the entire free list for every chunk, so it's slow.
+    pub fn free_chunks(&self) -> impl Iterator<Item = ops::Range<usize>> + '_ {
+        self.chunk_headers()
+            .filter(|chunk| self.is_chunk_reclaimable(chunk))
+            .map(|chunk| chunk.start..chunk.start + chunk.len)
+    }
+
+    /// Removes a chunk returned by `free_chunks` from the allocator, and returns it. Returns
+    /// `None` if `chunk` isn't reclaimable.
+    pub fn reclaim_chunk(&mut self, chunk: ops::Range<usize>) -> Option<&'static mut [u8]> {
+        let header = *self
+            .chunk_headers()
+            .find(|header| header.start == chunk.start && header.start + header.len == chunk.end)?;
+        if !self.is_chunk_reclaimable(&header) {
+            return None;
+        }
+
+        unsafe {
+            // The other chunks still have free slabs, so neither list becomes empty.
+            let slabs = header.slabs_start..header.slabs_start + header.slabs_size;
+            let in_chunk =
+                |run: ptr::NonNull<SlabFreeList>| slabs.contains(&(run.as_ptr() as usize));
+            while in_chunk(self.free_list) {
+                self.free_list = self.free_list.as_ref().next.unwrap();
+            }
+            let mut run = self.free_list;
+            while let Some(next) = run.as_ref().next {
+                if in_chunk(next) {
+                    run.as_mut().next = next.as_ref().next;
+                } else {
+                    run = next;
+                }
+            }
+
+            if self.chunks.as_ref().start == header.start {
+                self.chunks = header.next.unwrap();
+            } else {
+                let mut prev = self.chunks;
+                while let Some(next) = prev.as_ref().next {
+                    if next.as_ref().start == header.start {
+                        prev.as_mut().next = header.next;
+                        break;
+                    }
+                    prev = next;
+                }
+            }
+
+            self.free_size -= header.slabs_size;
+            Some(slice::from_raw_parts_mut(
+                header.start as *mut u8,
+                header.len,
+            ))
+        }
+    }
+
     /// Allocates a pointer to `T`. Make sure to not leak this memory.
     /// Using this function directly is not recommended, please use `SlabBox::<T>::new(slf, data)` instead.
     pub fn malloc(&mut self) -> Option<ptr::NonNull<T>> {
@@ -82,7 +311,10 @@ impl<T: Sized> SlabAllocator<T> {
                 self.free_list =
                     ptr::NonNull::new((self.free_list.as_ptr() as usize + Self::SLAB_SIZE) as _)
                         .unwrap();
-                *self.free_list.as_mut() = SlabFreeList { size, next };
+                *self.free_list.as_mut() = SlabFreeList {
+                    size: size - Self::SLAB_SIZE,
+                    next,
+                };
                 self.free_size -= Self::SLAB_SIZE;
 
                 Some(ptr)
@@ -118,9 +350,14 @@ impl<T: Sized> SlabAllocator<T> {
 }
 
 /// Represents a box allocated by a slab allocator.
-#[repr(transparent)]
+///
+/// In debug builds the box also holds the generation of its slab, and freeing it checks that the
+/// slab wasn't freed or reused in the meantime.
+#[cfg_attr(not(debug_assertions), repr(transparent))]
 pub struct SlabBox<T> {
     ptr: ptr::NonNull<T>,
+    #[cfg(debug_assertions)]
+    generation: u64,
     phantom: PhantomData<T>,
 }
 
@@ -133,21 +370,54 @@ impl<T> SlabBox<T> {
             ptr.cast::<mem::MaybeUninit<T>>().as_mut().write(x);
             Self {
                 ptr,
+                #[cfg(debug_assertions)]
+                generation: {
+                    let generation = SlabAllocator::generation(ptr);
+                    // The tag of a slab that was never allocated is garbage, only changes matter.
+                    *generation = (*generation).wrapping_add(1);
+                    *generation
+                },
                 phantom: PhantomData,
             }
         }
     }
 
+    /// Returns a pointer to the boxed value.
     #[inline]
     pub fn as_ptr(&self) -> *const T {
         self.ptr.as_ptr()
     }
 
+    /// Returns a mutable pointer to the boxed value.
     #[inline]
     pub fn as_mut_ptr(&mut self) -> *mut T {
         self.ptr.as_ptr()
     }
 
+    /// Checks in debug builds that the slab wasn't freed or reused since the box was allocated,
+    /// and ends the generation of the slab. The box must be freed right after.
+    ///
+    /// # Panics
+    /// Panics if the box's generation is stale.
+    #[inline]
+    unsafe fn end_generation(&self) {
+        #[cfg(debug_assertions)]
+        {
+            let generation = SlabAllocator::generation(self.ptr);
+            assert_eq!(
+                *generation, self.generation,
+                "A slab box was freed after its slab was freed or reused"
+            );
+            *generation = self.generation.wrapping_add(1);
+        }
+    }
+
+    /// Moves `x` into the box and returns the value it held, without allocating.
+    #[inline]
+    pub fn replace(&mut self, x: T) -> T {
+        mem::replace(&mut **self, x)
+    }
+
     /// Frees the allocation with the given allocator. This allocator doesn't have to be the same
     /// allocator that was used to allocate this box, but it's not recommended to use a different
     /// allocator or multiple slab allocators of the same type in general.
@@ -155,6 +425,7 @@ impl<T> SlabBox<T> {
     pub fn free(self, alloc: &mut SlabAllocator<T>) {
         unsafe {
             let md = mem::ManuallyDrop::new(self);
+            md.end_generation();
             md.ptr.as_ptr().drop_in_place();
             alloc.free(md.ptr);
         }
@@ -165,6 +436,7 @@ impl<T> SlabBox<T> {
     pub fn free_forget(self, alloc: &mut SlabAllocator<T>) {
         unsafe {
             let md = mem::ManuallyDrop::new(self);
+            md.end_generation();
             alloc.free(md.ptr);
         }
     }
@@ -175,12 +447,19 @@ impl<T> SlabBox<T> {
         let x;
         unsafe {
             let md = mem::ManuallyDrop::new(self);
+            md.end_generation();
             x = md.ptr.as_ptr().read();
             alloc.free(md.ptr);
         }
         x
     }
 
+    /// Same as `free_move`, frees the box and returns the value it held.
+    #[inline]
+    pub fn take_move(self, alloc: &mut SlabAllocator<T>) -> T {
+        self.free_move(alloc)
+    }
+
     /// Clones the box, does the same thing as
     /// ```
     /// // slf: SlabBox<T>
@@ -202,7 +481,7 @@ unsafe impl<T: Sync> Sync for SlabAllocator<T> {}
 impl<T> AsRef<T> for SlabBox<T> {
     #[inline]
     fn as_ref(&self) -> &T {
-        &*self
+        self
     }
 }
 
@@ -302,3 +581,95 @@ unsafe impl<T: Sync> Sync for SlabBox<T> {}
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(len: usize) -> &'static mut [u8] {
+        std::vec![0; len].leak()
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "A slab box was freed after its slab was freed or reused")]
+    fn freeing_a_stale_box_panics() {
+        let mut alloc = SlabAllocator::<[u64; 2]>::new(chunk(0x1000));
+        let slab_box = SlabBox::new(&mut alloc, [1, 2]);
+        let stale = unsafe { ptr::read(&slab_box) };
+        slab_box.free(&mut alloc);
+
+        // The freed slab is reused first.
+        let reused = mem::ManuallyDrop::new(SlabBox::new(&mut alloc, [3, 4]));
+        assert_eq!(reused.as_ptr(), stale.as_ptr());
+        stale.free(&mut alloc);
+    }
+
+    #[repr(align(64))]
+    struct Aligned([u8; 64]);
+
+    #[test]
+    fn over_aligned_slabs_in_a_misaligned_chunk() {
+        let chunk = chunk(0x2000 + 64);
+        let offset = chunk.as_ptr().align_offset(64) + 8;
+        let mut alloc = SlabAllocator::<Aligned>::new(&mut chunk[offset..]);
+
+        let boxes: std::vec::Vec<_> = (0..32u8)
+            .map(|i| SlabBox::new(&mut alloc, Aligned([i; 64])))
+            .collect();
+        for (i, slab_box) in boxes.iter().enumerate() {
+            assert_eq!(slab_box.as_ptr() as usize % 64, 0);
+            assert_eq!(slab_box.0, [i as u8; 64]);
+        }
+        for slab_box in boxes {
+            slab_box.free(&mut alloc);
+        }
+    }
+
+    #[test]
+    fn reserve_covers_a_burst_of_allocations() {
+        let mut alloc = SlabAllocator::<[u64; 2]>::new(chunk(0x1000));
+        assert!(alloc.spare_slabs() < 100);
+
+        let mut supplied = 0;
+        alloc.reserve(100, || {
+            supplied += 1;
+            chunk(0x1000)
+        });
+        assert!(0 < supplied);
+        assert!(100 <= alloc.spare_slabs());
+
+        let boxes: std::vec::Vec<_> = (0..100)
+            .map(|i| {
+                let slab_box = SlabBox::new(&mut alloc, [i, i]);
+                assert!(!alloc.needs_new_chunk());
+                slab_box
+            })
+            .collect();
+        for slab_box in boxes {
+            slab_box.free(&mut alloc);
+        }
+
+        // There's room already, so nothing is supplied.
+        alloc.reserve(100, || unreachable!());
+    }
+
+    #[test]
+    fn replace_and_take_move() {
+        let mut alloc = SlabAllocator::<[u64; 2]>::new(chunk(0x1000));
+        let free_size = alloc.free_size;
+
+        let mut slab_box = SlabBox::new(&mut alloc, [1, 2]);
+        let ptr = slab_box.as_ptr();
+        assert_eq!(slab_box.replace([3, 4]), [1, 2]);
+        assert_eq!(*slab_box, [3, 4]);
+        assert_eq!(slab_box.as_ptr(), ptr);
+        assert_eq!(
+            alloc.free_size,
+            free_size - SlabAllocator::<[u64; 2]>::SLAB_SIZE
+        );
+
+        assert_eq!(slab_box.take_move(&mut alloc), [3, 4]);
+        assert_eq!(alloc.free_size, free_size);
+    }
+}
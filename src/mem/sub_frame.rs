@@ -0,0 +1,88 @@
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+/// A frame allocator that splits the 2MiB frames of `parent` into 4KiB frames, for page tables
+/// and other small allocations. A new 2MiB frame is taken from `parent` only when all 512 frames
+/// of the previous one were handed out.
+#[derive(Debug)]
+pub struct SubFrameAllocator<A> {
+    parent: A,
+    frames: PhysFrameRange<Size4KiB>,
+}
+
+impl<A: FrameAllocator<Size2MiB>> SubFrameAllocator<A> {
+    /// Creates a new SubFrameAllocator on top of `parent`, it takes no frame until it's used.
+    pub fn new(parent: A) -> Self {
+        let zero = PhysFrame::containing_address(PhysAddr::zero());
+        Self {
+            parent,
+            frames: PhysFrame::range(zero, zero),
+        }
+    }
+
+    /// Returns the parent allocator, the 4KiB frames that weren't handed out yet are lost.
+    pub fn into_parent(self) -> A {
+        self.parent
+    }
+}
+
+unsafe impl<A: FrameAllocator<Size2MiB>> FrameAllocator<Size4KiB> for SubFrameAllocator<A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if self.frames.is_empty() {
+            let start =
+                PhysFrame::containing_address(self.parent.allocate_frame()?.start_address());
+            self.frames = PhysFrame::range(start, start + 512);
+        }
+        self.frames.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Hands out the 2MiB frames at `frames`, and counts the frames it handed out.
+    struct Parent {
+        frames: std::vec::IntoIter<u64>,
+        allocated: usize,
+    }
+
+    unsafe impl FrameAllocator<Size2MiB> for Parent {
+        fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+            let frame = PhysFrame::from_start_address(PhysAddr::new(self.frames.next()?)).ok()?;
+            self.allocated += 1;
+            Some(frame)
+        }
+    }
+
+    #[test]
+    fn splits_each_parent_frame_into_512_frames() {
+        let mut sub_frames = SubFrameAllocator::new(Parent {
+            frames: std::vec![0x600000, 0x200000].into_iter(),
+            allocated: 0,
+        });
+
+        for (parent_start, allocated) in [(0x600000, 1), (0x200000, 2)] {
+            let frames: BTreeSet<_> = (0..512)
+                .map(|_| {
+                    sub_frames
+                        .allocate_frame()
+                        .unwrap()
+                        .start_address()
+                        .as_u64()
+                })
+                .collect();
+            // The parent is asked for a frame only when the previous one is used up.
+            assert_eq!(sub_frames.parent.allocated, allocated);
+            assert_eq!(frames.len(), 512);
+            assert!(frames
+                .iter()
+                .copied()
+                .eq((parent_start..parent_start + 0x200000).step_by(0x1000)));
+        }
+        assert_eq!(sub_frames.allocate_frame(), None);
+        assert_eq!(sub_frames.into_parent().allocated, 2);
+    }
+}
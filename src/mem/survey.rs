@@ -0,0 +1,215 @@
+use crate::error::KernelError;
+use crate::stack_vec::StackVec;
+use core::ops::Range;
+use multiboot2::{BootInformation, ElfSectionFlags};
+
+/// The maximum number of usable memory regions `survey` records.
+pub const MAX_MEMORY_REGIONS: usize = 32;
+
+/// The memory layout described by the Multiboot2 information structure, computed once by
+/// `survey`.
+#[derive(Debug, Clone)]
+pub struct MemorySurvey {
+    /// The physical addresses of the kernel's allocated ELF sections.
+    pub kernel: Range<usize>,
+    /// The physical addresses of the Multiboot2 information structure.
+    pub boot_info: Range<usize>,
+    /// The end of the highest usable region, or 0 if there are none.
+    pub mem_end: usize,
+    /// The total size of the usable regions.
+    pub total_ram: usize,
+    /// The usable regions, as they appear in the memory map.
+    pub usable: StackVec<Range<usize>, MAX_MEMORY_REGIONS>,
+}
+
+impl MemorySurvey {
+    /// Returns the regions that must not be allocated: the kernel and the Multiboot2 information
+    /// structure.
+    pub fn reserved(&self) -> [Range<usize>; 2] {
+        [self.kernel.clone(), self.boot_info.clone()]
+    }
+}
+
+/// Surveys the memory map and the kernel's ELF sections.
+///
+/// Fails if the memory map tag or the ELF-Symbols tag are missing, if the kernel has no allocated
+/// sections, or if there are more than `MAX_MEMORY_REGIONS` usable regions.
+pub fn survey(boot_info: &BootInformation) -> Result<MemorySurvey, KernelError> {
+    let memory_map_tag = boot_info
+        .memory_map_tag()
+        .ok_or(KernelError::MissingMultibootTag("Memory Map"))?;
+    let elf_sections_tag = boot_info
+        .elf_sections_tag()
+        .ok_or(KernelError::MissingMultibootTag("ELF-Symbols"))?;
+
+    // Sections that aren't loaded into memory have no meaningful address.
+    let kernel_sections = || {
+        elf_sections_tag.sections().filter(|section| {
+            section.flags().contains(ElfSectionFlags::ALLOCATED) && section.size() != 0
+        })
+    };
+    let kernel_start = kernel_sections()
+        .map(|section| section.start_address())
+        .min()
+        .ok_or(KernelError::NoKernelSections)? as usize;
+    let kernel_end = kernel_sections()
+        .map(|section| section.end_address())
+        .max()
+        .ok_or(KernelError::NoKernelSections)? as usize;
+
+    let mut usable = StackVec::new();
+    for area in memory_map_tag.memory_areas() {
+        if usable
+            .push(area.start_address() as usize..area.end_address() as usize)
+            .is_some()
+        {
+            return Err(KernelError::TooManyMemoryRegions);
+        }
+    }
+
+    Ok(MemorySurvey {
+        kernel: kernel_start..kernel_end,
+        boot_info: boot_info.start_address()..boot_info.end_address(),
+        mem_end: usable.iter().map(|area| area.end).max().unwrap_or(0),
+        total_ram: usable.iter().map(|area| area.end - area.start).sum(),
+        usable,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// The Multiboot2 `ElfSectionFlags::ALLOCATED` flag.
+    const ALLOCATED: u64 = 0x2;
+
+    /// Returns a Multiboot2 information structure with a memory map tag of the `(start, size, type)`
+    /// areas, where type 1 is usable RAM, and an ELF-Symbols tag of the `(address, size, flags)`
+    /// sections. A tag is left out if it's `None`.
+    fn boot_info(
+        areas: Option<&[(u64, u64, u32)]>,
+        sections: Option<&[(u64, u64, u64)]>,
+    ) -> BootInformation {
+        let mut words: Vec<u32> = std::vec![0, 0];
+        let split = |value: u64| [value as u32, (value >> 32) as u32];
+
+        if let Some(areas) = areas {
+            words.extend([6, 16 + 24 * areas.len() as u32, 24, 0]);
+            for &(start, size, typ) in areas {
+                words.extend(split(start));
+                words.extend(split(size));
+                words.extend([typ, 0]);
+            }
+        }
+        if let Some(sections) = sections {
+            // The 64-bit section headers, the string table is the first section.
+            words.extend([
+                9,
+                20 + 64 * sections.len() as u32,
+                sections.len() as u32,
+                64,
+                0,
+            ]);
+            for &(addr, size, flags) in sections {
+                // The name and the type, `SHT_PROGBITS`.
+                words.extend([0, 1]);
+                words.extend(split(flags));
+                words.extend(split(addr));
+                // The file offset.
+                words.extend([0, 0]);
+                words.extend(split(size));
+                // The link, the info, the alignment and the entry size.
+                words.extend([0, 0, 0, 0, 0, 0]);
+            }
+            // The tags are 8 byte aligned.
+            words.push(0);
+        }
+        words.extend([0, 8]);
+        words[0] = 4 * words.len() as u32;
+
+        // A `u64` buffer, the structure is 8 byte aligned.
+        let buffer: &mut [u64] = std::vec![0; words.len().div_ceil(2) + 4].leak();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                words.as_ptr(),
+                buffer.as_mut_ptr() as *mut u32,
+                words.len(),
+            );
+            multiboot2::load(buffer.as_ptr() as usize).unwrap()
+        }
+    }
+
+    #[test]
+    fn survey_collects_the_layout() {
+        let boot_info = boot_info(
+            Some(&[
+                (0, 0x9fc00, 1),
+                (0x9fc00, 0x400, 2),
+                (0x100000, 0x3ff00000, 1),
+                (0x100000000, 0x40000000, 1),
+            ]),
+            Some(&[
+                (0x100000, 0x5000, ALLOCATED),
+                // Not loaded, it's ignored.
+                (0, 0x8000, 0),
+                (0x105000, 0x2000, ALLOCATED | 0x1),
+                // Empty, it's ignored.
+                (0x400000, 0, ALLOCATED),
+            ]),
+        );
+
+        let survey = survey(&boot_info).unwrap();
+        assert_eq!(survey.kernel, 0x100000..0x107000);
+        assert_eq!(
+            survey.boot_info,
+            boot_info.start_address()..boot_info.end_address()
+        );
+        assert_eq!(
+            *survey.usable,
+            [0..0x9fc00, 0x100000..0x40000000, 0x100000000..0x140000000]
+        );
+        assert_eq!(survey.mem_end, 0x140000000);
+        assert_eq!(survey.total_ram, 0x9fc00 + 0x3ff00000 + 0x40000000);
+        assert_eq!(
+            survey.reserved(),
+            [survey.kernel.clone(), survey.boot_info.clone()]
+        );
+    }
+
+    #[test]
+    fn survey_rejects_too_many_regions() {
+        let areas: Vec<_> = (0..MAX_MEMORY_REGIONS as u64 + 1)
+            .map(|i| (i * 0x200000, 0x100000, 1))
+            .collect();
+        let boot_info = boot_info(Some(&areas), Some(&[(0x100000, 0x1000, ALLOCATED)]));
+        assert_eq!(
+            survey(&boot_info).err(),
+            Some(KernelError::TooManyMemoryRegions)
+        );
+    }
+
+    #[test]
+    fn survey_reports_each_missing_tag() {
+        let areas = [(0x100000, 0x3ff00000, 1)];
+        let sections = [(0x100000, 0x1000, ALLOCATED)];
+
+        assert_eq!(
+            survey(&boot_info(None, Some(&sections))).err(),
+            Some(KernelError::MissingMultibootTag("Memory Map"))
+        );
+        assert_eq!(
+            survey(&boot_info(Some(&areas), None)).err(),
+            Some(KernelError::MissingMultibootTag("ELF-Symbols"))
+        );
+        assert_eq!(
+            survey(&boot_info(None, None)).err(),
+            Some(KernelError::MissingMultibootTag("Memory Map"))
+        );
+        assert_eq!(
+            survey(&boot_info(Some(&areas), Some(&[(0, 0x1000, 0)]))).err(),
+            Some(KernelError::NoKernelSections)
+        );
+        assert!(survey(&boot_info(Some(&areas), Some(&sections))).is_ok());
+    }
+}
@@ -1,36 +1,88 @@
-use super::BTree;
+use super::{BTree, MemSegment, MemSegmentOrdBySize};
 use core::ptr::NonNull;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-#[repr(C)]
-struct SizeFirstPtrSecond {
-    size: usize,
-    ptr: NonNull<u8>,
+/// Returns the key of the free area in the best-fit tree.
+#[inline]
+fn best_fit_key(ptr: NonNull<u8>, size: usize) -> MemSegmentOrdBySize {
+    MemSegment {
+        ptr: ptr.as_ptr() as usize,
+        size,
+    }
+    .into()
 }
 
 pub struct VirtualMemoryAllocator {
-    best_fit_tree: BTree<SizeFirstPtrSecond, ()>,
+    best_fit_tree: BTree<MemSegmentOrdBySize, ()>,
     merge_tree: BTree<NonNull<u8>, usize>,
 }
 
-impl VirtualMemoryAllocator {
-    pub fn new(free_areas: &[(NonNull<u8>, usize)], chunk: &'static mut [u8]) -> Self {
-        let (chunk0, chunk1) = chunk.split_at_mut(chunk.len() / 2);
+// The pointers are only addresses of free areas, they're never dereferenced.
+unsafe impl Send for VirtualMemoryAllocator {}
 
-        let mut best_fit_tree = BTree::new(chunk0);
-        let mut merge_tree = BTree::new(chunk1);
+impl VirtualMemoryAllocator {
+    /// Creates a new virtual memory allocator from `free_areas`, a list of `(ptr, size)` pairs.
+    /// The list is sorted in place, and adjacent areas are merged.
+    ///
+    /// # Panics
+    /// Panics if any of the free areas overlap.
+    pub fn new(free_areas: &mut [(NonNull<u8>, usize)], chunk: &'static mut [u8]) -> Self {
+        free_areas.sort_unstable_by_key(|&(ptr, _)| ptr);
+        let areas = free_areas.iter().copied().filter(|&(_, size)| size != 0);
 
-        for &(ptr, size) in free_areas.iter() {
-            assert!(best_fit_tree
-                .insert(SizeFirstPtrSecond { size, ptr }, ())
-                .is_none());
-            assert!(merge_tree.insert(ptr, size).is_none());
+        // The overlaps are checked before the trees exist, their boxes can't be dropped.
+        for ((ptr, size), (next_ptr, next_size)) in areas.clone().zip(areas.clone().skip(1)) {
+            assert!(
+                ptr.as_ptr() as usize + size <= next_ptr.as_ptr() as usize,
+                "Overlapping free areas (ptr={:?}, size=0x{:x}) and (ptr={:?}, size=0x{:x})",
+                ptr,
+                size,
+                next_ptr,
+                next_size,
+            );
         }
 
-        Self {
-            best_fit_tree,
-            merge_tree,
+        let (chunk0, chunk1) = chunk.split_at_mut(chunk.len() / 2);
+        let mut slf = Self {
+            best_fit_tree: BTree::new(chunk0),
+            merge_tree: BTree::new(chunk1),
+        };
+
+        let mut pending: Option<(NonNull<u8>, usize)> = None;
+        for (ptr, size) in areas {
+            match pending {
+                Some((pending_ptr, pending_size)) => {
+                    let pending_end = pending_ptr.as_ptr() as usize + pending_size;
+                    if pending_end == ptr.as_ptr() as usize {
+                        pending = Some((pending_ptr, pending_size + size));
+                    } else {
+                        slf.insert_free_area(pending_ptr, pending_size);
+                        pending = Some((ptr, size));
+                    }
+                }
+                None => pending = Some((ptr, size)),
+            }
+        }
+        if let Some((ptr, size)) = pending {
+            slf.insert_free_area(ptr, size);
         }
+
+        slf
+    }
+
+    fn insert_free_area(&mut self, ptr: NonNull<u8>, size: usize) {
+        assert!(self
+            .best_fit_tree
+            .insert(best_fit_key(ptr, size), ())
+            .is_none());
+        assert!(self.merge_tree.insert(ptr, size).is_none());
+    }
+
+    fn remove_free_area(&mut self, ptr: NonNull<u8>, size: usize) {
+        assert!(self
+            .best_fit_tree
+            .remove(&best_fit_key(ptr, size))
+            .is_some());
+        assert!(self.merge_tree.remove(&ptr).is_some());
     }
 
     pub fn needs_new_chunk(&self) -> bool {
@@ -57,33 +109,35 @@ impl VirtualMemoryAllocator {
         }
     }
 
+    /// Allocates `alloc_size` bytes, rounded up to 2MiB, from the smallest free area that fits.
+    /// Returns the address and the rounded size.
+    ///
+    /// # Panics
+    /// Panics if there's no such area.
     pub fn alloc(&mut self, alloc_size: usize) -> (NonNull<u8>, usize) {
+        self.try_alloc(alloc_size).expect("Out of virtual memory")
+    }
+
+    /// Does the same thing as `alloc`, but returns `None` if there's no free area that fits.
+    pub fn try_alloc(&mut self, alloc_size: usize) -> Option<(NonNull<u8>, usize)> {
         // Align to 2MiB
         let alloc_size = alloc_size + 0x1fffff & !0x1fffff;
 
-        let SizeFirstPtrSecond {
+        // The smallest area that fits, the lowest of them if there are several.
+        let best_fit = *self
+            .best_fit_tree
+            .ceil_entry(&MemSegmentOrdBySize {
+                size: alloc_size,
+                ptr: 0,
+            })?
+            .0;
+        self.best_fit_tree.remove(&best_fit);
+        let MemSegment {
             ptr,
             size: area_size,
-        } = match self.best_fit_tree.get_entry(&SizeFirstPtrSecond {
-            ptr: NonNull::dangling(),
-            size: alloc_size,
-        }) {
-            Ok(_) => unreachable!(),
-            Err(mut entry) => {
-                if alloc_size < entry.key().size {
-                    *entry.key()
-                } else {
-                    assert!(entry.next());
-                    assert!(alloc_size < entry.key().size);
-                    *entry.key()
-                }
-            }
-        };
+        } = best_fit.into();
+        let ptr = NonNull::new(ptr as *mut u8).unwrap();
 
-        self.best_fit_tree.remove(&SizeFirstPtrSecond {
-            ptr,
-            size: area_size,
-        });
         self.merge_tree.remove(&ptr);
 
         if alloc_size < area_size {
@@ -92,99 +146,346 @@ impl VirtualMemoryAllocator {
 
             assert!(self
                 .best_fit_tree
-                .insert(SizeFirstPtrSecond { ptr: new_ptr, size }, ())
+                .insert(best_fit_key(new_ptr, size), ())
                 .is_none());
             assert!(self.merge_tree.insert(new_ptr, size).is_none());
         } else {
             assert_eq!(alloc_size, area_size);
         }
 
-        (ptr, alloc_size)
+        Some((ptr, alloc_size))
+    }
+
+    /// Returns the number of free bytes from `ptr` to the end of the free area that contains it,
+    /// which is the area's size if `ptr` is its start. Returns `None` if `ptr` is allocated.
+    pub fn is_free(&self, ptr: NonNull<u8>) -> Option<usize> {
+        // The last area that starts at or before `ptr`.
+        let (area_ptr, area_size) = self.merge_tree.floor_entry(&ptr)?;
+
+        let area_end = area_ptr.as_ptr() as usize + area_size;
+        let ptr = ptr.as_ptr() as usize;
+        (ptr < area_end).then(|| area_end - ptr)
+    }
+
+    /// Returns the size of the largest free area, or 0 if there are none.
+    pub fn largest_contiguous(&self) -> usize {
+        self.best_fit_tree
+            .floor_entry(&MemSegmentOrdBySize {
+                size: usize::MAX,
+                ptr: usize::MAX,
+            })
+            .map_or(0, |(segment, _)| segment.size)
+    }
+
+    /// Does the same thing as `try_alloc`, but the returned address is aligned to `align`. The
+    /// bytes before the aligned start and after the allocation stay free.
+    ///
+    /// # Panics
+    /// Panics if `align` isn't a power of two.
+    pub fn try_alloc_aligned(
+        &mut self,
+        alloc_size: usize,
+        align: usize,
+    ) -> Option<(NonNull<u8>, usize)> {
+        let (area, start, alloc_size) = self.find_aligned(alloc_size, align)?;
+        let area_ptr = NonNull::new(area.ptr as *mut u8).unwrap();
+        self.remove_free_area(area_ptr, area.size);
+
+        if area.ptr < start {
+            self.insert_free_area(area_ptr, start - area.ptr);
+        }
+        let end = start + alloc_size;
+        if end < area.ptr + area.size {
+            let end_ptr = NonNull::new(end as *mut u8).unwrap();
+            self.insert_free_area(end_ptr, area.ptr + area.size - end);
+        }
+
+        Some((NonNull::new(start as *mut u8).unwrap(), alloc_size))
+    }
+
+    /// Returns true if `try_alloc_aligned(size, align)` would succeed. It only probes, nothing is
+    /// allocated.
+    ///
+    /// # Panics
+    /// Panics if `align` isn't a power of two.
+    pub fn can_satisfy(&self, size: usize, align: usize) -> bool {
+        self.find_aligned(size, align).is_some()
+    }
+
+    /// Returns the smallest free area that has `size` bytes, rounded up to 2MiB, at an `align`
+    /// aligned address, with that address and the rounded size.
+    fn find_aligned(&self, size: usize, align: usize) -> Option<(MemSegment, usize, usize)> {
+        assert!(align.is_power_of_two(), "Invalid alignment 0x{:x}", align);
+        let size = size.checked_add(0x1fffff)? & !0x1fffff;
+
+        // The areas that are large enough, from the smallest.
+        self.best_fit_tree
+            .iter_from(&MemSegmentOrdBySize { size, ptr: 0 })
+            .find_map(|(&segment, _)| {
+                let segment: MemSegment = segment.into();
+                let start = segment.ptr.checked_add(align - 1)? & !(align - 1);
+                let end = start.checked_add(size)?;
+                (end <= segment.ptr + segment.size).then_some((segment, start, size))
+            })
+    }
+
+    /// Returns the free areas as `(ptr, size)` pairs in address order. Adjacent areas are always
+    /// merged, so no area ends where the next one starts.
+    pub fn free_list(&self) -> impl Iterator<Item = (NonNull<u8>, usize)> + '_ {
+        self.merge_tree.iter().map(|(&ptr, &size)| (ptr, size))
     }
 
+    /// Gives back the `size` bytes at `ptr`, and merges them with the free areas right before and
+    /// right after them.
+    ///
+    /// # Panics
+    /// Panics if `size` isn't 2MiB aligned, or if the bytes overlap a free area.
     pub fn free(&mut self, mut ptr: NonNull<u8>, mut size: usize) {
         assert_eq!(size & 0x1fffff, 0);
+        let start = ptr.as_ptr() as usize;
+        let end = start + size;
 
-        let entry = self.merge_tree.get_entry(&ptr).unwrap_err();
-        if ptr < *entry.key() {
-            let end_ptr = unsafe { NonNull::new(ptr.as_ptr().add(size)).unwrap() };
-
-            let mut entry = if end_ptr == *entry.key() {
-                size += *entry.value();
-                drop(entry);
-
-                let (entry_ptr, entry_size) = self.merge_tree.remove(&end_ptr).unwrap();
-                self.best_fit_tree
-                    .remove(&SizeFirstPtrSecond {
-                        ptr: entry_ptr,
-                        size: entry_size,
-                    })
-                    .unwrap();
-
-                self.merge_tree.get_entry(&ptr).unwrap_err()
-            } else {
-                entry
-            };
-
-            if *entry.key() < ptr || entry.prev() {
-                let entry_end_ptr =
-                    unsafe { NonNull::new(entry.key().as_ptr().add(*entry.value())).unwrap() };
-
-                if entry_end_ptr == ptr {
-                    ptr = *entry.key();
-                    size += *entry.value();
-                    drop(entry);
-
-                    let (entry_ptr, entry_size) = self.merge_tree.remove(&ptr).unwrap();
-                    self.best_fit_tree
-                        .remove(&SizeFirstPtrSecond {
-                            ptr: entry_ptr,
-                            size: entry_size,
-                        })
-                        .unwrap();
-                }
+        if let Some((&prev_ptr, &prev_size)) = self.merge_tree.floor_entry(&ptr) {
+            let prev_end = prev_ptr.as_ptr() as usize + prev_size;
+            assert!(
+                prev_end <= start,
+                "Freed area (ptr={:?}, size=0x{:x}) overlaps a free area",
+                ptr,
+                size,
+            );
+            if prev_end == start {
+                self.remove_free_area(prev_ptr, prev_size);
+                ptr = prev_ptr;
+                size += prev_size;
             }
-        } else {
-            let entry_end_ptr =
-                unsafe { NonNull::new(entry.key().as_ptr().add(*entry.value())).unwrap() };
-            let mut entry = if ptr == entry_end_ptr {
-                ptr = *entry.key();
-                size += *entry.value();
-                drop(entry);
-
-                let (entry_ptr, entry_size) = self.merge_tree.remove(&ptr).unwrap();
-                self.best_fit_tree
-                    .remove(&SizeFirstPtrSecond {
-                        ptr: entry_ptr,
-                        size: entry_size,
-                    })
-                    .unwrap();
-
-                self.merge_tree.get_entry(&ptr).unwrap_err()
-            } else {
-                entry
-            };
-
-            if ptr < *entry.key() || entry.next() {
-                let end_ptr = unsafe { NonNull::new(ptr.as_ptr().add(size)).unwrap() };
-
-                if end_ptr == *entry.key() {
-                    size += *entry.value();
-                    drop(entry);
-
-                    let (entry_ptr, entry_size) = self.merge_tree.remove(&end_ptr).unwrap();
-                    self.best_fit_tree
-                        .remove(&SizeFirstPtrSecond {
-                            ptr: entry_ptr,
-                            size: entry_size,
-                        })
-                        .unwrap();
-                }
+        }
+        if let Some((&next_ptr, &next_size)) = self.merge_tree.ceil_entry(&ptr) {
+            let next_start = next_ptr.as_ptr() as usize;
+            assert!(
+                end <= next_start,
+                "Freed area (ptr={:?}, size=0x{:x}) overlaps a free area",
+                ptr,
+                size,
+            );
+            if end == next_start {
+                self.remove_free_area(next_ptr, next_size);
+                size += next_size;
             }
         }
 
-        self.merge_tree.insert(ptr, size);
-        self.best_fit_tree
-            .insert(SizeFirstPtrSecond { ptr, size }, ());
+        self.insert_free_area(ptr, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem;
+    use std::vec::Vec;
+
+    /// Returns an allocator with the free areas `areas`, given as `(address, size)` pairs.
+    fn vma(areas: &[(usize, usize)]) -> VirtualMemoryAllocator {
+        let mut areas: Vec<_> = areas
+            .iter()
+            .map(|&(ptr, size)| (NonNull::new(ptr as *mut u8).unwrap(), size))
+            .collect();
+        VirtualMemoryAllocator::new(&mut areas, std::vec![0; 0x200000].leak())
+    }
+
+    fn free_list(vma: &VirtualMemoryAllocator) -> Vec<(usize, usize)> {
+        vma.free_list()
+            .map(|(ptr, size)| (ptr.as_ptr() as usize, size))
+            .collect()
+    }
+
+    fn ptr(addr: usize) -> NonNull<u8> {
+        NonNull::new(addr as *mut u8).unwrap()
+    }
+
+    #[test]
+    fn free_merges_with_the_neighbors() {
+        let mut vma = vma(&[(0x200000, 0x200000), (0x800000, 0x200000)]);
+
+        // Touches neither area.
+        vma.free(ptr(0xe00000), 0x200000);
+        assert_eq!(
+            free_list(&vma),
+            [
+                (0x200000, 0x200000),
+                (0x800000, 0x200000),
+                (0xe00000, 0x200000)
+            ]
+        );
+        // Touches the area before it.
+        vma.free(ptr(0x400000), 0x200000);
+        assert_eq!(
+            free_list(&vma),
+            [
+                (0x200000, 0x400000),
+                (0x800000, 0x200000),
+                (0xe00000, 0x200000)
+            ]
+        );
+        // Touches the area after it.
+        vma.free(ptr(0xc00000), 0x200000);
+        assert_eq!(
+            free_list(&vma),
+            [
+                (0x200000, 0x400000),
+                (0x800000, 0x200000),
+                (0xc00000, 0x400000)
+            ]
+        );
+        // Touches both.
+        vma.free(ptr(0x600000), 0x200000);
+        vma.free(ptr(0xa00000), 0x200000);
+        assert_eq!(free_list(&vma), [(0x200000, 0xe00000)]);
+        assert_eq!(vma.largest_contiguous(), 0xe00000);
+
+        mem::forget(vma);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps a free area")]
+    fn free_panics_on_a_double_free() {
+        // The trees' boxes panic when they're dropped, so the unwinding mustn't drop them.
+        let mut vma = mem::ManuallyDrop::new(vma(&[(0x200000, 0x600000)]));
+        vma.free(ptr(0x400000), 0x200000);
+    }
+
+    #[test]
+    fn try_alloc_fails_when_no_area_fits() {
+        let mut vma = vma(&[(0x200000, 0x200000), (0x600000, 0x400000)]);
+
+        assert_eq!(vma.try_alloc(0x600000), None);
+        assert_eq!(vma.try_alloc(0x300000), Some((ptr(0x600000), 0x400000)));
+        assert_eq!(vma.try_alloc(1), Some((ptr(0x200000), 0x200000)));
+        assert_eq!(vma.try_alloc(1), None);
+        assert_eq!(vma.is_free(ptr(0x200000)), None);
+
+        vma.free(ptr(0x600000), 0x400000);
+        assert_eq!(vma.is_free(ptr(0x800000)), Some(0x200000));
+
+        mem::forget(vma);
+    }
+
+    #[test]
+    fn largest_contiguous_over_fragmented_areas() {
+        let empty = vma(&[]);
+        assert_eq!(empty.largest_contiguous(), 0);
+        mem::forget(empty);
+
+        let mut vma = vma(&[
+            (0x200000, 0x200000),
+            (0x600000, 0x600000),
+            (0x1000000, 0x400000),
+        ]);
+        assert_eq!(vma.largest_contiguous(), 0x600000);
+        vma.alloc(0x400000);
+        assert_eq!(vma.largest_contiguous(), 0x600000);
+        vma.alloc(0x600000);
+        assert_eq!(vma.largest_contiguous(), 0x200000);
+
+        mem::forget(vma);
+    }
+
+    #[test]
+    fn can_satisfy_matches_try_alloc_aligned() {
+        // A 6MiB area at 6MiB and a 14MiB area at 18MiB, only the second has 8MiB at an 8MiB
+        // aligned address.
+        let mut vma = vma(&[(0x600000, 0x600000), (0x1200000, 0xe00000)]);
+
+        assert!(vma.can_satisfy(0x600000, 0x200000));
+        assert!(vma.can_satisfy(0x400000, 0x800000));
+        assert!(vma.can_satisfy(0x800000, 0x800000));
+        assert!(!vma.can_satisfy(0xa00000, 0x800000));
+        assert!(vma.can_satisfy(0xe00000, 0x200000));
+        assert!(!vma.can_satisfy(0x1000000, 0x200000));
+        // The sizes and alignments that overflow don't fit anywhere.
+        assert!(!vma.can_satisfy(usize::MAX, 0x200000));
+        assert!(!vma.can_satisfy(0x200000, 1 << 63));
+
+        assert_eq!(
+            vma.try_alloc_aligned(0x800000, 0x800000),
+            Some((ptr(0x1800000), 0x800000))
+        );
+        // What's before the allocation stays free.
+        assert_eq!(
+            free_list(&vma),
+            [(0x600000, 0x600000), (0x1200000, 0x600000)]
+        );
+        assert!(!vma.can_satisfy(0x800000, 0x800000));
+        assert_eq!(vma.try_alloc_aligned(0x800000, 0x800000), None);
+        assert!(vma.can_satisfy(0x400000, 0x400000));
+        assert_eq!(
+            vma.try_alloc_aligned(0x200000, 0x400000),
+            Some((ptr(0x800000), 0x200000))
+        );
+        // And so does what's after it.
+        assert_eq!(
+            free_list(&vma),
+            [
+                (0x600000, 0x200000),
+                (0xa00000, 0x200000),
+                (0x1200000, 0x600000)
+            ]
+        );
+
+        mem::forget(vma);
+    }
+
+    #[test]
+    fn new_sorts_and_merges_the_areas() {
+        let vma = vma(&[
+            (0x1000000, 0x200000),
+            (0x200000, 0x200000),
+            (0xc00000, 0x400000),
+            (0x400000, 0x200000),
+            (0x800000, 0),
+            (0x800000, 0x200000),
+        ]);
+        assert_eq!(
+            free_list(&vma),
+            [
+                (0x200000, 0x400000),
+                (0x800000, 0x200000),
+                (0xc00000, 0x600000)
+            ]
+        );
+        assert_eq!(vma.largest_contiguous(), 0x600000);
+        assert_eq!(vma.is_free(ptr(0xc00000)), Some(0x600000));
+        mem::forget(vma);
+    }
+
+    #[test]
+    #[should_panic(expected = "Overlapping free areas")]
+    fn new_panics_on_overlapping_areas() {
+        mem::forget(vma(&[(0x800000, 0x400000), (0x200000, 0x800000)]));
+    }
+
+    #[test]
+    fn free_list_over_a_fragmented_set() {
+        let mut vma = vma(&[(0x200000, 0x1000000), (0x2000000, 0x400000)]);
+
+        // Takes the first area, and gives every other 2MiB of it back.
+        assert_eq!(vma.try_alloc(0x1000000), Some((ptr(0x200000), 0x1000000)));
+        for addr in (1..5).map(|i| 0x400000 * i) {
+            vma.free(ptr(addr), 0x200000);
+        }
+        let list = free_list(&vma);
+        assert_eq!(
+            list,
+            [
+                (0x400000, 0x200000),
+                (0x800000, 0x200000),
+                (0xc00000, 0x200000),
+                (0x1000000, 0x200000),
+                (0x2000000, 0x400000)
+            ]
+        );
+        // The areas are in address order, and never adjacent.
+        assert!(list.windows(2).all(|w| w[0].0 + w[0].1 < w[1].0));
+        assert_eq!(list.iter().map(|&(_, size)| size).sum::<usize>(), 0xc00000);
+        mem::forget(vma);
     }
 }
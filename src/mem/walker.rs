@@ -0,0 +1,243 @@
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// A read-only walker of the page tables, it translates virtual addresses like the MMU does.
+///
+/// The tables below the root are found by their physical addresses, so they must be mapped at
+/// `phys_offset + phys_addr`.
+#[derive(Debug, Clone, Copy)]
+pub struct PageTableWalker<'a> {
+    root: &'a PageTable,
+    phys_offset: VirtAddr,
+}
+
+impl<'a> PageTableWalker<'a> {
+    /// Creates a walker of the tables under the PML4 table `root`.
+    ///
+    /// # Safety
+    /// Every table reachable from `root` must be mapped at its physical address plus
+    /// `phys_offset`, and mustn't be modified while the walker is alive.
+    pub unsafe fn new(root: &'a PageTable, phys_offset: VirtAddr) -> Self {
+        Self { root, phys_offset }
+    }
+
+    /// Returns the table at the physical address `phys_addr`.
+    unsafe fn table(&self, phys_addr: PhysAddr) -> &'a PageTable {
+        &*(self.phys_offset + phys_addr.as_u64()).as_ptr()
+    }
+
+    /// Translates `addr` to the physical address it's mapped to, and returns it with the flags of
+    /// the entry that maps it. Returns `None` if `addr` isn't mapped.
+    ///
+    /// 1GiB and 2MiB pages are entries with the `HUGE_PAGE` flag in the PDP and PD tables.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+
+        let mut table = self.root;
+        for (level, &index) in indices.iter().enumerate() {
+            let entry = &table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+
+            // The size of the memory that an entry of this level maps.
+            let entry_size = 1u64 << 39 - 9 * level;
+            if level == 3 || level != 0 && flags.contains(PageTableFlags::HUGE_PAGE) {
+                return Some((entry.addr() + (addr.as_u64() & entry_size - 1), flags));
+            }
+
+            table = unsafe { self.table(entry.addr()) };
+        }
+        unreachable!()
+    }
+}
+
+impl PageTableWalker<'static> {
+    /// Creates a walker of the active page tables, the PML4 table is taken from CR3.
+    ///
+    /// # Safety
+    /// Same as `new`, the PML4 table must be mapped at its physical address plus `phys_offset`
+    /// too.
+    pub unsafe fn from_cr3(phys_offset: VirtAddr) -> Self {
+        let (frame, _) = Cr3::read();
+        let root = &*(phys_offset + frame.start_address().as_u64()).as_ptr();
+        Self::new(root, phys_offset)
+    }
+}
+
+/// The reasons `map_page` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// `alloc_table` returned `None` while a PDP or PD table was missing.
+    TableAllocationFailed,
+    /// The page, or a huge page that contains it, is already mapped.
+    AlreadyMapped,
+}
+
+/// Maps the 2MiB `page` to `frame` with `flags` in the tables under the PML4 table `root`.
+/// `HUGE_PAGE` and `PRESENT` are added to `flags`.
+///
+/// Missing PDP and PD tables are created in frames taken from `alloc_table`, and are linked as
+/// writable and present. Unlike the bump allocator's early mappings, nothing assumes that a frame
+/// is mapped at its own physical address.
+///
+/// # Safety
+/// Every table reachable from `root`, and every frame returned by `alloc_table`, must be mapped at
+/// its physical address plus `phys_offset`. The frames returned by `alloc_table` must be unused.
+pub unsafe fn map_page(
+    root: &mut PageTable,
+    phys_offset: VirtAddr,
+    page: Page<Size2MiB>,
+    frame: PhysFrame<Size2MiB>,
+    flags: PageTableFlags,
+    mut alloc_table: impl FnMut() -> Option<PhysFrame<Size4KiB>>,
+) -> Result<(), MapError> {
+    let table_flags = PageTableFlags::WRITABLE | PageTableFlags::PRESENT;
+
+    let mut table = root;
+    for index in [page.p4_index(), page.p3_index()] {
+        let entry = &mut table[index];
+        if entry.is_unused() {
+            let table_frame = alloc_table().ok_or(MapError::TableAllocationFailed)?;
+            let table_ptr: *mut PageTable =
+                (phys_offset + table_frame.start_address().as_u64()).as_mut_ptr();
+            table_ptr.write(PageTable::new());
+            entry.set_frame(table_frame, table_flags);
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(MapError::AlreadyMapped);
+        }
+        table = &mut *(phys_offset + entry.addr().as_u64()).as_mut_ptr();
+    }
+
+    let entry = &mut table[page.p2_index()];
+    if !entry.is_unused() {
+        return Err(MapError::AlreadyMapped);
+    }
+    entry.set_addr(
+        frame.start_address(),
+        flags | PageTableFlags::HUGE_PAGE | PageTableFlags::PRESENT,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a leaked empty table. The tests use 0 as the physical offset, so the table's
+    /// "physical" address is its address.
+    fn table() -> &'static mut PageTable {
+        std::boxed::Box::leak(std::boxed::Box::new(PageTable::new()))
+    }
+
+    fn phys(table: &PageTable) -> PhysFrame {
+        PhysFrame::from_start_address(PhysAddr::new(table as *const _ as u64)).unwrap()
+    }
+
+    fn virt(p4: u64, p3: u64, p2: u64, p1: u64, offset: u64) -> VirtAddr {
+        VirtAddr::new(p4 << 39 | p3 << 30 | p2 << 21 | p1 << 12 | offset)
+    }
+
+    #[test]
+    fn translates_4kib_and_2mib_mappings() {
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let (root, pdp, pd, pt) = (table(), table(), table(), table());
+        root[1].set_frame(phys(pdp), table_flags);
+        pdp[2].set_frame(phys(pd), table_flags);
+        pd[3].set_frame(phys(pt), table_flags);
+        pt[4].set_addr(PhysAddr::new(0x1234000), PageTableFlags::PRESENT);
+        pd[5].set_addr(
+            PhysAddr::new(0x40000000),
+            PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE | PageTableFlags::NO_EXECUTE,
+        );
+
+        let walker = unsafe { PageTableWalker::new(root, VirtAddr::zero()) };
+        assert_eq!(
+            walker.translate(virt(1, 2, 3, 4, 0x567)),
+            Some((PhysAddr::new(0x1234567), PageTableFlags::PRESENT))
+        );
+        assert_eq!(
+            walker.translate(virt(1, 2, 5, 0x1ab, 0xcde)),
+            Some((
+                PhysAddr::new(0x40000000 + (0x1ab << 12) + 0xcde),
+                PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE | PageTableFlags::NO_EXECUTE,
+            ))
+        );
+
+        // Missing entries at every level.
+        assert_eq!(walker.translate(virt(0, 2, 3, 4, 0)), None);
+        assert_eq!(walker.translate(virt(1, 0, 3, 4, 0)), None);
+        assert_eq!(walker.translate(virt(1, 2, 0, 4, 0)), None);
+        assert_eq!(walker.translate(virt(1, 2, 3, 0, 0)), None);
+    }
+
+    #[test]
+    fn map_page_into_a_mock_root() {
+        let root = table();
+        let mut allocated = 0;
+        let mut alloc_table = || {
+            allocated += 1;
+            Some(phys(table()))
+        };
+        let page = Page::containing_address(virt(0x123, 0x45, 0x67, 0, 0));
+        let frame = PhysFrame::from_start_address(PhysAddr::new(0x7e00000)).unwrap();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        unsafe {
+            assert_eq!(
+                map_page(root, VirtAddr::zero(), page, frame, flags, &mut alloc_table),
+                Ok(())
+            );
+            // The PDP and PD tables were created, and are reused by a page next to it.
+            let next = page + 1;
+            let next_frame = frame + 1;
+            assert_eq!(
+                map_page(
+                    root,
+                    VirtAddr::zero(),
+                    next,
+                    next_frame,
+                    flags,
+                    &mut alloc_table
+                ),
+                Ok(())
+            );
+            assert_eq!(
+                map_page(
+                    root,
+                    VirtAddr::zero(),
+                    page,
+                    next_frame,
+                    flags,
+                    &mut alloc_table
+                ),
+                Err(MapError::AlreadyMapped)
+            );
+            let other = Page::containing_address(virt(0x1, 0, 0, 0, 0));
+            assert_eq!(
+                map_page(root, VirtAddr::zero(), other, frame, flags, || None),
+                Err(MapError::TableAllocationFailed)
+            );
+        }
+        assert_eq!(allocated, 2);
+
+        let walker = unsafe { PageTableWalker::new(root, VirtAddr::zero()) };
+        let huge_flags = flags | PageTableFlags::HUGE_PAGE | PageTableFlags::PRESENT;
+        assert_eq!(
+            walker.translate(page.start_address() + 0x1234u64),
+            Some((PhysAddr::new(0x7e01234), huge_flags))
+        );
+        assert_eq!(
+            walker.translate((page + 1).start_address()),
+            Some((PhysAddr::new(0x8000000), huge_flags))
+        );
+        assert_eq!(walker.translate(virt(0x1, 0, 0, 0, 0)), None);
+    }
+}
@@ -214,7 +214,7 @@ impl<'a, T, const N: usize> OnStackRefMutStack<'a, T, N> {
     /// If the root is popped, the function will returns it, otherwise `self.pop()` will return
     /// None.
     #[inline]
-    pub fn pop(self: &mut Self) -> Option<&'a mut T> {
+    pub fn pop(&mut self) -> Option<&'a mut T> {
         let popped = self.0.pop();
         if self.is_empty() {
             popped.map(|x| unsafe { &mut *x })
@@ -224,6 +224,12 @@ impl<'a, T, const N: usize> OnStackRefMutStack<'a, T, N> {
     }
 }
 
+impl<'a, T, const N: usize> Default for OnStackRefMutStack<'a, T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // #[test]
 // fn ref_stack() {
 //     struct SelfRef {
@@ -1,59 +1,560 @@
 //! This module contains everithing related to the 16550 UART serial port logging.
 
+use crate::stack_vec::StackVec;
 use core::fmt::{self, Write};
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
+use log::{LevelFilter, Log};
+use multiboot2::BootInformation;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+/// The size of the line buffer of `SerialLogger`'s buffered mode.
+const LOG_BUFFER_SIZE: usize = 128;
+/// The capacity of `SerialLogger`'s module level table.
+const MAX_MODULE_LEVELS: usize = 16;
+/// The offset of the line status register from the base port of a UART.
+#[cfg_attr(test, allow(dead_code))]
+const LINE_STATUS_OFFSET: u16 = 5;
+/// The offset of the line control register from the base port of a UART.
+const LINE_CONTROL_OFFSET: u16 = 3;
+/// The line control bit that maps the divisor latch to the data and interrupt enable registers.
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+/// The frequency of a UART's clock divided by 16, the baud rate of divisor 1.
+const MAX_BAUD: u32 = 115200;
+/// The line status bit that is set when the transmit holding register, and the transmit FIFO
+/// when it's enabled, is empty.
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+/// How many times `Uart` polls the line status register for an empty transmitter before it drops
+/// a byte. A byte takes about 260us at 38400 baud, and a poll about 1us.
+const TRANSMIT_TIMEOUT_POLLS: usize = 100_000;
 
 lazy_static! {
     /// The serial port.
-    static ref SERIAL1: spin::Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
-        serial_port.init();
-        spin::Mutex::new(serial_port)
-    };
+    static ref SERIAL1: spin::Mutex<Uart> = spin::Mutex::new(unsafe { Uart::new(0x3f8) });
+    /// The second serial port, it's initialized on first use.
+    static ref SERIAL2: spin::Mutex<Uart> = spin::Mutex::new(unsafe { Uart::new(0x2f8) });
     /// The 16550 UART serial port logger.
     pub static ref SERIAL_LOGGER: SerialLogger = SerialLogger {
-        serial: &*SERIAL1,
+        sink: spin::Mutex::new(None),
+        buffer: spin::Mutex::new(StackVec::new()),
+        buffered: AtomicBool::new(false),
+        key_value: AtomicBool::new(false),
+        module_levels: spin::Mutex::new(StackVec::new()),
     };
 }
 
+/// Held by the tests that use the serial ports, or set the logger's sink or the global logger,
+/// which are shared by the tests running in parallel.
+#[cfg(test)]
+pub(crate) static LOGGER_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// The number of bytes the serial ports dropped since boot.
+static DROPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of bytes the serial ports dropped since boot, because the transmitter
+/// didn't empty in time. A nonzero count usually means that there's no UART at the port, or that
+/// it's stuck.
+pub fn dropped_byte_count() -> usize {
+    DROPPED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Returns true if `line_status`, a value of the line status register, says that the transmitter
+/// can take another byte without overflowing the transmit FIFO.
+fn is_transmit_empty(line_status: u8) -> bool {
+    line_status & LINE_STATUS_THR_EMPTY != 0
+}
+
+/// The registers of a UART that `Uart` drives.
+trait UartRegisters {
+    /// Creates and initializes the registers of the UART at the base port `base`.
+    ///
+    /// # Safety
+    /// There must be a UART at `base`, or nothing, and it mustn't be used by anything else.
+    unsafe fn new(base: u16) -> Self;
+    /// Reads the line status register.
+    fn line_status(&mut self) -> u8;
+    /// Writes `byte` to the transmitter.
+    fn send(&mut self, byte: u8);
+    /// Returns the `uart_16550` port, for `with_serial`.
+    fn serial_port(&mut self) -> &mut SerialPort;
+}
+
+/// The I/O ports of a 16550 UART.
+#[cfg_attr(test, allow(dead_code))]
+struct UartPorts {
+    port: SerialPort,
+    line_status: PortReadOnly<u8>,
+}
+
+impl UartRegisters for UartPorts {
+    unsafe fn new(base: u16) -> Self {
+        let mut port = SerialPort::new(base);
+        port.init();
+        Self {
+            port,
+            line_status: PortReadOnly::new(base + LINE_STATUS_OFFSET),
+        }
+    }
+
+    fn line_status(&mut self) -> u8 {
+        unsafe { self.line_status.read() }
+    }
+
+    fn send(&mut self, byte: u8) {
+        self.port.send(byte);
+    }
+
+    fn serial_port(&mut self) -> &mut SerialPort {
+        &mut self.port
+    }
+}
+
+/// The registers of the serial ports, the unit tests run on the host without UARTs.
+#[cfg(not(test))]
+type SerialRegisters = UartPorts;
+#[cfg(test)]
+type SerialRegisters = tests::MockRegisters;
+
+/// A 16550 UART whose writes never hang. Every byte waits until the transmitter is empty, so the
+/// transmit FIFO can't overflow, and it's dropped and counted in `dropped_byte_count()` if the
+/// transmitter doesn't empty after `TRANSMIT_TIMEOUT_POLLS` polls.
+struct Uart<R = SerialRegisters> {
+    registers: R,
+}
+
+impl<R: UartRegisters> Uart<R> {
+    /// Creates and initializes the UART at the base port `base`.
+    ///
+    /// # Safety
+    /// There must be a UART at `base`, or nothing, and it mustn't be used by anything else.
+    unsafe fn new(base: u16) -> Self {
+        Self {
+            registers: R::new(base),
+        }
+    }
+
+    /// Waits until the transmitter is empty, returns false if it didn't empty in time.
+    fn wait_for_transmit_empty(&mut self) -> bool {
+        (0..TRANSMIT_TIMEOUT_POLLS).any(|_| is_transmit_empty(self.registers.line_status()))
+    }
+
+    /// Sends `byte`, or drops it if the transmitter didn't empty in time.
+    fn send(&mut self, byte: u8) {
+        if self.wait_for_transmit_empty() {
+            self.registers.send(byte);
+        } else {
+            DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<R: UartRegisters> Write for Uart<R> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
 /// `SerialLogger` implements `log::Log`, it logs to the serial port with the format: `"LEVEL: MSG"`
+///
+/// In buffered mode the output is accumulated into a line buffer, and it's sent to the serial
+/// port only on a newline, when the buffer is full, or on `flush()`.
+///
+/// Records can be filtered by their module path with `set_module_level`, and the output can be
+/// redirected from the serial port with `set_sink`.
 pub struct SerialLogger {
-    serial: &'static spin::Mutex<SerialPort>,
+    sink: spin::Mutex<Option<Sink>>,
+    buffer: spin::Mutex<StackVec<u8, LOG_BUFFER_SIZE>>,
+    buffered: AtomicBool,
+    key_value: AtomicBool,
+    module_levels: spin::Mutex<StackVec<(&'static str, LevelFilter), MAX_MODULE_LEVELS>>,
 }
 
 impl SerialLogger {
     /// Forces the unlock the spinlock on the logger.
+    ///
+    /// # Safety
+    /// Nothing may hold the locks anymore, e.g. in a panic or a fatal exception that interrupted
+    /// the code that held them.
     pub unsafe fn force_unlock(&self) {
-        self.serial.force_unlock();
+        self.buffer.force_unlock();
+        self.sink.force_unlock();
+        self.sink().force_unlock();
+        self.module_levels.force_unlock();
+    }
+
+    /// Sets the level filter of the modules whose path starts with `prefix`, replacing the
+    /// previous filter of the same prefix. The longest matching prefix wins.
+    ///
+    /// NOTE: Records above `log::max_level()` are discarded before they reach the logger.
+    pub fn set_module_level(&self, prefix: &'static str, level: LevelFilter) {
+        let mut module_levels = self.module_levels.lock();
+        if let Some(entry) = module_levels.iter_mut().find(|(p, _)| *p == prefix) {
+            entry.1 = level;
+        } else {
+            assert!(
+                module_levels.push((prefix, level)).is_none(),
+                "Too many module levels, the capacity is {}",
+                MAX_MODULE_LEVELS,
+            );
+        }
+    }
+
+    /// Returns the level filter of the longest prefix of `target`, if any.
+    fn module_level(&self, target: &str) -> Option<LevelFilter> {
+        self.module_levels
+            .lock()
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+    }
+
+    /// Redirects the output to `sink`, or back to the serial port if it's `None`. The line buffer is
+    /// flushed to the previous sink first.
+    pub fn set_sink(&self, sink: Option<Sink>) {
+        self.flush();
+        *self.sink.lock() = sink;
+    }
+
+    /// Returns the sink the output goes to, the serial port if no sink was set.
+    pub fn sink(&self) -> Sink {
+        self.sink.lock().unwrap_or_else(|| &*SERIAL1)
+    }
+
+    /// Sends the line buffer to the sink, like `flush()` but returns the sink's error instead of
+    /// panicking. For the panic handler.
+    pub fn try_flush(&self) -> fmt::Result {
+        let mut buffer = self.buffer.lock();
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        LineBufferedWriter {
+            buffer: &mut buffer,
+            sink: self.sink(),
+        }
+        .flush()
+    }
+
+    /// Enables or disables the line buffered mode. Disabling it flushes the buffer.
+    pub fn set_buffered(&self, buffered: bool) {
+        self.buffered.store(buffered, Ordering::Relaxed);
+        if !buffered {
+            self.flush();
+        }
+    }
+
+    /// Returns true if the logger is in the line buffered mode.
+    pub fn is_buffered(&self) -> bool {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Sets the format of the records, `Format::Human` by default.
+    pub fn set_format(&self, format: Format) {
+        self.key_value
+            .store(format == Format::KeyValue, Ordering::Relaxed);
+    }
+
+    /// Returns the format of the records.
+    pub fn format(&self) -> Format {
+        if self.key_value.load(Ordering::Relaxed) {
+            Format::KeyValue
+        } else {
+            Format::Human
+        }
+    }
+
+    /// Writes `record` as a line in the current format.
+    fn write_record(&self, w: &mut dyn Write, record: &log::Record) -> fmt::Result {
+        match self.format() {
+            Format::Human => writeln!(w, "{}: {}", record.level(), record.args()),
+            Format::KeyValue => writeln!(
+                w,
+                "level={} target={} msg=\"{}\"",
+                record.level(),
+                record.target(),
+                Escaped(*record.args()),
+            ),
+        }
+    }
+}
+
+/// The formats of `SerialLogger`'s records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `"LEVEL: MSG"`, for reading.
+    Human,
+    /// `level=LEVEL target=TARGET msg="MSG"`, for scripts. Quotes, backslashes, and newlines and
+    /// carriage returns in the message are escaped like in Rust strings, so a record is always a
+    /// single line.
+    KeyValue,
+}
+
+/// Displays the message with the escapes of `Format::KeyValue`.
+struct Escaped<'a>(fmt::Arguments<'a>);
+
+/// Escapes everything it writes to `f`, see `Escaped`.
+struct EscapeWriter<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+}
+
+impl<'a, 'b> Write for EscapeWriter<'a, 'b> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while let Some(pos) = s.find(['"', '\\', '\n', '\r']) {
+            self.f.write_str(&s[..pos])?;
+            self.f.write_str(match s.as_bytes()[pos] {
+                b'"' => "\\\"",
+                b'\\' => "\\\\",
+                b'\n' => "\\n",
+                _ => "\\r",
+            })?;
+            s = &s[pos + 1..];
+        }
+        self.f.write_str(s)
+    }
+}
+
+impl<'a> fmt::Display for Escaped<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::write(&mut EscapeWriter { f }, self.0)
+    }
+}
+
+/// A destination of the logger's output, see `SerialLogger::set_sink`.
+pub type Sink = &'static spin::Mutex<dyn Write + Send>;
+
+/// Writes to the line buffer, and sends it to the sink on newlines or when it's full.
+struct LineBufferedWriter<'a> {
+    buffer: &'a mut StackVec<u8, LOG_BUFFER_SIZE>,
+    sink: Sink,
+}
+
+impl<'a> LineBufferedWriter<'a> {
+    /// Sends the buffer to the sink, except for a character that was cut by a full buffer.
+    fn flush(&mut self) -> fmt::Result {
+        let valid_len = match str::from_utf8(self.buffer) {
+            Ok(s) => s.len(),
+            Err(err) => err.valid_up_to(),
+        };
+        let s = unsafe { str::from_utf8_unchecked(&self.buffer[..valid_len]) };
+        self.sink.lock().write_str(s)?;
+        self.buffer.remove_range(..valid_len);
+        Ok(())
+    }
+}
+
+impl<'a> Write for LineBufferedWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.buffer.is_full() {
+                self.flush()?;
+            }
+            assert!(self.buffer.push(byte).is_none());
+            if byte == b'\n' {
+                self.flush()?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl log::Log for SerialLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        match self.module_level(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => true,
+        }
     }
     fn log(&self, record: &log::Record) {
-        if self.enabled(record.metadata()) {
-            writeln!(
-                &mut self.serial.lock(),
-                "{}: {}",
-                record.level(),
-                record.args()
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if self.is_buffered() {
+            let mut buffer = self.buffer.lock();
+            self.write_record(
+                &mut LineBufferedWriter {
+                    buffer: &mut buffer,
+                    sink: self.sink(),
+                },
+                record,
             )
-            .expect("Failed to write to logging serial");
+            .expect("Failed to write to the logging sink");
+        } else {
+            self.write_record(&mut *self.sink().lock(), record)
+                .expect("Failed to write to the logging sink");
         }
     }
-    fn flush(&self) {}
+    fn flush(&self) {
+        self.try_flush()
+            .expect("Failed to write to the logging sink");
+    }
 }
 
+/// True once `init_logger` was called.
+static LOGGER_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 /// The function initiates the serial port and the serial logger, `SERIAL_LOGGER`,
 /// and `init_logger` sets the default logger to serial.
+///
+/// The logger can be set only once, so only the first call does anything, the later calls don't
+/// even reset the max level.
 pub fn init_logger() {
+    if LOGGER_INITIALIZED.swap(true, Ordering::AcqRel) {
+        return;
+    }
     log::set_logger(&*SERIAL_LOGGER).expect("Failed to set logger");
     log::set_max_level(log::LevelFilter::Info);
 }
 
+/// Locks the serial port and passes it to `f`, for low-level use like changing the baud rate or
+/// sending raw control bytes.
+///
+/// NOTE: The logger uses the same port unless a sink was set, so all logging blocks until `f`
+/// returns, and logging from inside `f` deadlocks.
+pub fn with_serial<R>(f: impl FnOnce(&mut SerialPort) -> R) -> R {
+    f(SERIAL1.lock().registers.serial_port())
+}
+
+/// The parity modes of a UART.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    /// No parity bit.
+    None,
+    /// The parity bit makes the number of set bits odd.
+    Odd,
+    /// The parity bit makes the number of set bits even.
+    Even,
+    /// The parity bit is always set.
+    Mark,
+    /// The parity bit is always clear.
+    Space,
+}
+
+/// The reasons `configure` can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The baud rate doesn't divide 115200, or the divisor doesn't fit in 16 bits.
+    InvalidBaud(u32),
+    /// The number of data bits isn't between 5 and 8.
+    InvalidDataBits(u8),
+    /// The number of stop bits isn't 1 or 2.
+    InvalidStopBits(u8),
+}
+
+/// Returns the divisor latch value of `baud`, it must divide 115200 evenly.
+fn baud_divisor(baud: u32) -> Result<u16, ConfigError> {
+    if baud == 0 || !MAX_BAUD.is_multiple_of(baud) {
+        return Err(ConfigError::InvalidBaud(baud));
+    }
+    u16::try_from(MAX_BAUD / baud).map_err(|_| ConfigError::InvalidBaud(baud))
+}
+
+/// Returns the line control register value of the frame format, without the DLAB bit.
+fn line_control(data_bits: u8, parity: Parity, stop_bits: u8) -> Result<u8, ConfigError> {
+    if !(5..=8).contains(&data_bits) {
+        return Err(ConfigError::InvalidDataBits(data_bits));
+    }
+    let stop_bits = match stop_bits {
+        1 => 0,
+        2 => 1 << 2,
+        _ => return Err(ConfigError::InvalidStopBits(stop_bits)),
+    };
+    let parity = match parity {
+        Parity::None => 0,
+        Parity::Odd => 0b001 << 3,
+        Parity::Even => 0b011 << 3,
+        Parity::Mark => 0b101 << 3,
+        Parity::Space => 0b111 << 3,
+    };
+    Ok(data_bits - 5 | stop_bits | parity)
+}
+
+/// Programs the baud rate and the frame format of the UART at the base port `base`. `SerialPort`'s
+/// `init()` always sets 38400 baud, 8 data bits, no parity and 1 stop bit, this is for hosts that
+/// use another configuration. Nothing is written if the configuration is invalid.
+///
+/// 2 stop bits with 5 data bits are 1.5 stop bits, like the UART does.
+///
+/// # Safety
+/// There must be a UART at `base`. Nothing may use it during the call, for the serial port lock
+/// it with `with_serial`.
+pub unsafe fn configure(
+    base: u16,
+    baud: u32,
+    data_bits: u8,
+    parity: Parity,
+    stop_bits: u8,
+) -> Result<(), ConfigError> {
+    let divisor = baud_divisor(baud)?;
+    let line_control = line_control(data_bits, parity, stop_bits)?;
+
+    let mut line_control_port = Port::<u8>::new(base + LINE_CONTROL_OFFSET);
+    line_control_port.write(LINE_CONTROL_DLAB);
+    Port::<u8>::new(base).write(divisor as u8);
+    Port::<u8>::new(base + 1).write((divisor >> 8) as u8);
+    line_control_port.write(line_control);
+    Ok(())
+}
+
+/// Formats a size in bytes with the largest binary unit it reaches, with one decimal digit if it
+/// isn't a whole number of units. For example `HumanSize(1048576)` is `1 MiB` and
+/// `HumanSize(1536)` is `1.5 KiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanSize(pub u64);
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+        let mut unit = 0;
+        while unit + 1 < UNITS.len() && 1 << 10 * (unit + 1) <= self.0 {
+            unit += 1;
+        }
+        let whole = self.0 >> 10 * unit;
+        let tenths = (self.0 & (1 << 10 * unit) - 1) * 10 >> 10 * unit;
+        if tenths == 0 {
+            write!(f, "{} {}", whole, UNITS[unit])
+        } else {
+            write!(f, "{}.{} {}", whole, tenths, UNITS[unit])
+        }
+    }
+}
+
+/// Prints the memory map and the ELF sections of `boot_info` to the serial port, one aligned line
+/// per area or section.
+pub fn dump_boot_info(boot_info: &BootInformation) {
+    if let Some(memory_map_tag) = boot_info.memory_map_tag() {
+        crate::sprintln!("Memory areas: [");
+        for area in memory_map_tag.memory_areas() {
+            crate::sprintln!(
+                "    memory_area(addr={:#014x}, size={:#014x}), // {}",
+                area.start_address(),
+                area.size(),
+                HumanSize(area.size()),
+            );
+        }
+        crate::sprintln!("]");
+    }
+
+    if let Some(elf_sections_tag) = boot_info.elf_sections_tag() {
+        crate::sprintln!("ELF sections: [");
+        for section in elf_sections_tag.sections() {
+            crate::sprintln!(
+                "    elf_section(addr={:#014x}, size={:#014x}, flags={:#06x}), // {}",
+                section.start_address(),
+                section.size(),
+                section.flags(),
+                HumanSize(section.size()),
+            );
+        }
+        crate::sprintln!("]");
+    }
+}
+
 /// Intends `value` by `4 * indent` spaces.
 ///
 /// # Example
@@ -159,21 +660,83 @@ impl<T: fmt::Debug> fmt::Debug for Indent<T> {
             f,
         };
         if writer.f.alternate() {
-            write!(&mut writer, "{:#?}", &self.value)
+            write!(&mut writer, "{:#?}", self.value)
         } else {
-            write!(&mut writer, "{:?}", &self.value)
+            write!(&mut writer, "{:?}", self.value)
         }
     }
 }
 
-/// Prints to the serial port. Don't use directly, use `sprint!()` and `sprintln!()` instead.
-pub fn _sprint(args: core::fmt::Arguments) {
-    SERIAL1
+/// The serial ports `eprint!()` and `eprintln!()` can print to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPort {
+    /// The first serial port, the same port as `sprint!()` and the logger. It's the default.
+    Com1,
+    /// The second serial port.
+    Com2,
+}
+
+/// True if the error port is COM2.
+static ERROR_PORT_IS_COM2: AtomicBool = AtomicBool::new(false);
+
+/// Sets the serial port `eprint!()` and `eprintln!()` print to.
+pub fn set_error_port(port: ErrorPort) {
+    ERROR_PORT_IS_COM2.store(port == ErrorPort::Com2, Ordering::Relaxed);
+}
+
+/// Returns the serial port `eprint!()` and `eprintln!()` print to, COM1 by default.
+pub fn error_port() -> ErrorPort {
+    if ERROR_PORT_IS_COM2.load(Ordering::Relaxed) {
+        ErrorPort::Com2
+    } else {
+        ErrorPort::Com1
+    }
+}
+
+fn print_to(serial: &spin::Mutex<Uart>, args: fmt::Arguments) {
+    serial
         .lock()
         .write_fmt(args)
         .expect("Printing to serial failed");
 }
 
+/// Prints to the serial port. Don't use directly, use `sprint!()` and `sprintln!()` instead.
+pub fn _sprint(args: core::fmt::Arguments) {
+    print_to(&SERIAL1, args);
+}
+
+/// Prints to the serial port only if it isn't locked, and returns true if it printed. It never
+/// blocks, so it's safe in interrupt handlers that may interrupt a print.
+pub fn try_sprint(args: fmt::Arguments) -> bool {
+    match SERIAL1.try_lock() {
+        Some(mut serial) => {
+            serial.write_fmt(args).expect("Printing to serial failed");
+            true
+        }
+        None => false,
+    }
+}
+
+/// Prints to the error port. Don't use directly, use `eprint!()` and `eprintln!()` instead.
+pub fn _eprint(args: core::fmt::Arguments) {
+    match error_port() {
+        ErrorPort::Com1 => print_to(&SERIAL1, args),
+        ErrorPort::Com2 => print_to(&SERIAL2, args),
+    }
+}
+
+/// Sends raw bytes to the serial port without going through `core::fmt`.
+/// Useful for large binary dumps, where the formatting overhead adds up.
+///
+/// NOTE: `uart_16550` still translates backspace bytes (`0x08` and `0x7f`) on send, and bytes
+/// are dropped like in every other write if the transmitter is stuck.
+pub fn write_bytes(bytes: &[u8]) {
+    let mut serial = SERIAL1.lock();
+    for &byte in bytes {
+        serial.send(byte);
+    }
+}
+
 /// Print to serial port.
 #[macro_export]
 macro_rules! sprint {
@@ -195,3 +758,378 @@ macro_rules! sprintln {
         $crate::sprint!(concat!($fmt, "\n"), $($arg)*);
     }};
 }
+
+/// Print to the error port, see `set_error_port`.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {{
+        $crate::serial::_eprint(format_args!($($arg)*));
+    }};
+}
+
+/// Print to the error port with newline, see `set_error_port`.
+#[macro_export]
+macro_rules! eprintln {
+    () => {{
+        $crate::eprint!("\n");
+    }};
+    ($fmt:expr) => {{
+        $crate::eprint!(concat!($fmt, "\n"));
+    }};
+    ($fmt:expr, $($arg:tt)*) => {{
+        $crate::eprint!(concat!($fmt, "\n"), $($arg)*);
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    static CAPTURE: spin::Mutex<String> = spin::Mutex::new(String::new());
+
+    /// The registers of a UART that records the bytes sent to it.
+    pub(super) struct MockRegisters {
+        port: SerialPort,
+        /// The value the line status register reads, the transmitter is always empty by default.
+        pub line_status: u8,
+        pub sent: Vec<u8>,
+    }
+
+    impl UartRegisters for MockRegisters {
+        unsafe fn new(base: u16) -> Self {
+            Self {
+                // `SerialPort::new` doesn't access the ports, only `init` and the sends do.
+                port: SerialPort::new(base),
+                line_status: LINE_STATUS_THR_EMPTY,
+                sent: Vec::new(),
+            }
+        }
+
+        fn line_status(&mut self) -> u8 {
+            self.line_status
+        }
+
+        fn send(&mut self, byte: u8) {
+            self.sent.push(byte);
+        }
+
+        fn serial_port(&mut self) -> &mut SerialPort {
+            &mut self.port
+        }
+    }
+
+    fn log_info(args: fmt::Arguments) {
+        SERIAL_LOGGER.log(
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .target("mxos::serial::tests")
+                .args(args)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn logs_to_sink_and_force_unlocks() {
+        let _lock = LOGGER_LOCK.lock();
+        SERIAL_LOGGER.set_sink(Some(&CAPTURE));
+        log_info(format_args!("hello {}", 42));
+        assert_eq!(CAPTURE.lock().as_str(), "INFO: hello 42\n");
+
+        // A panic while the sink was being set leaves its lock held.
+        core::mem::forget(SERIAL_LOGGER.sink.lock());
+        unsafe {
+            SERIAL_LOGGER.force_unlock();
+        }
+        log_info(format_args!("after unlock"));
+        assert_eq!(
+            CAPTURE.lock().as_str(),
+            "INFO: hello 42\nINFO: after unlock\n"
+        );
+        SERIAL_LOGGER.set_sink(None);
+    }
+
+    #[test]
+    fn module_levels_filter_by_the_longest_prefix() {
+        fn log_at(level: log::Level, target: &str, msg: &str) {
+            SERIAL_LOGGER.log(
+                &log::Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{}", msg))
+                    .build(),
+            );
+        }
+
+        static SINK: spin::Mutex<String> = spin::Mutex::new(String::new());
+        let _lock = LOGGER_LOCK.lock();
+        SERIAL_LOGGER.set_sink(Some(&SINK));
+        SERIAL_LOGGER.set_module_level("mxos::mem", LevelFilter::Error);
+        SERIAL_LOGGER.set_module_level("mxos::mem::btree", LevelFilter::Warn);
+
+        log_at(log::Level::Warn, "mxos::mem::btree", "btree warn");
+        log_at(log::Level::Info, "mxos::mem::btree", "btree info");
+        log_at(log::Level::Warn, "mxos::mem::slab", "slab warn");
+        log_at(log::Level::Error, "mxos::mem::slab", "slab error");
+        log_at(log::Level::Trace, "mxos::idt", "idt trace");
+        assert_eq!(
+            SINK.lock().as_str(),
+            "WARN: btree warn\nERROR: slab error\nTRACE: idt trace\n"
+        );
+        assert_eq!(
+            SERIAL_LOGGER.module_level("mxos::mem::btree::Node"),
+            Some(LevelFilter::Warn)
+        );
+        assert_eq!(SERIAL_LOGGER.module_level("mxos::idt"), None);
+
+        // Setting a prefix again replaces its level. The module levels can't be removed, so
+        // they're left at the most verbose level.
+        SERIAL_LOGGER.set_module_level("mxos::mem::btree", LevelFilter::Trace);
+        SERIAL_LOGGER.set_module_level("mxos::mem", LevelFilter::Trace);
+        assert_eq!(
+            SERIAL_LOGGER.module_level("mxos::mem::btree"),
+            Some(LevelFilter::Trace)
+        );
+        SERIAL_LOGGER.set_sink(None);
+    }
+
+    #[test]
+    fn line_buffered_writer_flushes_on_newlines_and_flush() {
+        static SINK: spin::Mutex<String> = spin::Mutex::new(String::new());
+        let mut buffer = StackVec::new();
+        let mut writer = LineBufferedWriter {
+            buffer: &mut buffer,
+            sink: &SINK,
+        };
+
+        writer.write_str("abc").unwrap();
+        assert_eq!(SINK.lock().as_str(), "");
+        writer.write_str("def\nghi").unwrap();
+        assert_eq!(SINK.lock().as_str(), "abcdef\n");
+        assert_eq!(writer.buffer.as_slice(), b"ghi");
+        writer.flush().unwrap();
+        assert_eq!(SINK.lock().as_str(), "abcdef\nghi");
+        assert!(writer.buffer.is_empty());
+
+        // A full buffer is flushed too, it can't wait for the newline.
+        SINK.lock().clear();
+        let line = "x".repeat(LOG_BUFFER_SIZE + 2);
+        writer.write_str(&line).unwrap();
+        assert_eq!(SINK.lock().len(), LOG_BUFFER_SIZE);
+        assert_eq!(writer.buffer.len(), 2);
+    }
+
+    #[test]
+    fn write_bytes_sends_the_raw_bytes() {
+        let _lock = LOGGER_LOCK.lock();
+        SERIAL1.lock().registers.sent.clear();
+        write_bytes(b"\0dump\0\xff\n\0");
+        write_bytes(b"");
+        assert_eq!(SERIAL1.lock().registers.sent, b"\0dump\0\xff\n\0");
+    }
+
+    #[test]
+    fn eprintln_prints_to_the_error_port() {
+        let _lock = LOGGER_LOCK.lock();
+        SERIAL1.lock().registers.sent.clear();
+        SERIAL2.lock().registers.sent.clear();
+
+        set_error_port(ErrorPort::Com2);
+        crate::eprintln!("error {}", 1);
+        crate::sprintln!("output {}", 2);
+        assert_eq!(SERIAL1.lock().registers.sent, b"output 2\n");
+        assert_eq!(SERIAL2.lock().registers.sent, b"error 1\n");
+
+        set_error_port(ErrorPort::Com1);
+        assert_eq!(error_port(), ErrorPort::Com1);
+        crate::eprint!("error 3");
+        assert_eq!(SERIAL1.lock().registers.sent, b"output 2\nerror 3");
+        assert_eq!(SERIAL2.lock().registers.sent, b"error 1\n");
+    }
+
+    #[test]
+    fn init_logger_twice_keeps_the_max_level() {
+        let _lock = LOGGER_LOCK.lock();
+        init_logger();
+        log::set_max_level(LevelFilter::Warn);
+        // It would panic if it set the logger again.
+        init_logger();
+        assert_eq!(log::max_level(), LevelFilter::Warn);
+        assert!(LOGGER_INITIALIZED.load(Ordering::Relaxed));
+
+        log::set_max_level(LevelFilter::Info);
+    }
+
+    #[test]
+    fn uart_drops_bytes_while_the_transmitter_is_full() {
+        assert!(is_transmit_empty(0x60));
+        assert!(is_transmit_empty(LINE_STATUS_THR_EMPTY));
+        assert!(!is_transmit_empty(0x00));
+        assert!(!is_transmit_empty(0xdf));
+
+        let mut uart: Uart<MockRegisters> = unsafe { Uart::new(0x3f8) };
+        let dropped = dropped_byte_count();
+        uart.registers.line_status = 0x01;
+        uart.write_str("ab").unwrap();
+        assert!(uart.registers.sent.is_empty());
+        // Only this test drops bytes, the other tests' transmitters are always empty.
+        assert_eq!(dropped_byte_count(), dropped + 2);
+
+        uart.registers.line_status = 0x61;
+        uart.write_str("cd").unwrap();
+        assert_eq!(uart.registers.sent, b"cd");
+        assert_eq!(dropped_byte_count(), dropped + 2);
+    }
+
+    #[test]
+    fn with_serial_passes_the_locked_com1_port() {
+        let _lock = LOGGER_LOCK.lock();
+        let port = with_serial(|port| {
+            // COM1 stays locked while `f` runs.
+            assert!(SERIAL1.try_lock().is_none());
+            port as *mut SerialPort
+        });
+        assert_eq!(
+            port,
+            SERIAL1.lock().registers.serial_port() as *mut SerialPort
+        );
+        assert_eq!(with_serial(|_| 7), 7);
+    }
+
+    #[test]
+    fn human_size_picks_the_largest_unit() {
+        assert_eq!(format!("{}", HumanSize(0)), "0 B");
+        assert_eq!(format!("{}", HumanSize(1023)), "1023 B");
+        assert_eq!(format!("{}", HumanSize(1024)), "1 KiB");
+        assert_eq!(format!("{}", HumanSize(1536)), "1.5 KiB");
+        assert_eq!(format!("{}", HumanSize(1048576)), "1 MiB");
+        assert_eq!(format!("{}", HumanSize(3 << 30)), "3 GiB");
+        assert_eq!(format!("{}", HumanSize(1 << 50)), "1024 TiB");
+    }
+
+    #[test]
+    fn human_size_truncates_the_tenths() {
+        assert_eq!(format!("{}", HumanSize(1100)), "1 KiB");
+        assert_eq!(format!("{}", HumanSize(1048575)), "1023.9 KiB");
+        assert_eq!(format!("{}", HumanSize(u64::MAX)), "16777215.9 TiB");
+    }
+
+    #[test]
+    fn baud_divisor_divides_115200() {
+        assert_eq!(baud_divisor(115200), Ok(1));
+        assert_eq!(baud_divisor(38400), Ok(3));
+        assert_eq!(baud_divisor(9600), Ok(12));
+        assert_eq!(baud_divisor(50), Ok(2304));
+        assert_eq!(baud_divisor(2), Ok(57600));
+    }
+
+    #[test]
+    fn baud_divisor_rejects_invalid_bauds() {
+        assert_eq!(baud_divisor(0), Err(ConfigError::InvalidBaud(0)));
+        assert_eq!(baud_divisor(7), Err(ConfigError::InvalidBaud(7)));
+        assert_eq!(baud_divisor(230400), Err(ConfigError::InvalidBaud(230400)));
+        // The divisor 115200 doesn't fit in 16 bits.
+        assert_eq!(baud_divisor(1), Err(ConfigError::InvalidBaud(1)));
+    }
+
+    #[test]
+    fn line_control_encodes_the_frame_format() {
+        assert_eq!(line_control(8, Parity::None, 1), Ok(0x03));
+        assert_eq!(line_control(5, Parity::None, 1), Ok(0x00));
+        assert_eq!(line_control(8, Parity::Odd, 1), Ok(0x0b));
+        assert_eq!(line_control(7, Parity::Even, 2), Ok(0x1e));
+        assert_eq!(line_control(6, Parity::Mark, 1), Ok(0x29));
+        assert_eq!(line_control(5, Parity::Space, 2), Ok(0x3c));
+    }
+
+    #[test]
+    fn line_control_rejects_invalid_formats() {
+        assert_eq!(
+            line_control(4, Parity::None, 1),
+            Err(ConfigError::InvalidDataBits(4))
+        );
+        assert_eq!(
+            line_control(9, Parity::None, 1),
+            Err(ConfigError::InvalidDataBits(9))
+        );
+        assert_eq!(
+            line_control(8, Parity::None, 0),
+            Err(ConfigError::InvalidStopBits(0))
+        );
+        assert_eq!(
+            line_control(8, Parity::None, 3),
+            Err(ConfigError::InvalidStopBits(3))
+        );
+    }
+
+    #[test]
+    fn key_value_lines_parse_back() {
+        /// Parses a `Format::KeyValue` line into its level, target and unescaped message.
+        fn parse(line: &str) -> (log::Level, &str, String) {
+            let line = line.strip_prefix("level=").unwrap();
+            let (level, line) = line.split_once(" target=").unwrap();
+            let (target, msg) = line.split_once(" msg=\"").unwrap();
+            let msg = msg.strip_suffix("\"\n").unwrap();
+            assert!(!msg.contains('\n'));
+
+            let mut unescaped = String::new();
+            let mut chars = msg.chars();
+            while let Some(c) = chars.next() {
+                unescaped.push(match c {
+                    '\\' => match chars.next().unwrap() {
+                        'n' => '\n',
+                        'r' => '\r',
+                        c => c,
+                    },
+                    '"' => panic!("An unescaped quote in {:?}", msg),
+                    c => c,
+                });
+            }
+            (level.parse().unwrap(), target, unescaped)
+        }
+
+        let _lock = LOGGER_LOCK.lock();
+        SERIAL_LOGGER.set_format(Format::KeyValue);
+        let mut line = String::new();
+        let msg = "say \"hi\"\r\nto C:\\dir msg=\"x\" target=y";
+        SERIAL_LOGGER
+            .write_record(
+                &mut line,
+                &log::Record::builder()
+                    .level(log::Level::Warn)
+                    .target("mxos::mem::btree")
+                    .args(format_args!("{}", msg))
+                    .build(),
+            )
+            .unwrap();
+        SERIAL_LOGGER.set_format(Format::Human);
+
+        assert_eq!(line.matches('\n').count(), 1);
+        assert_eq!(
+            parse(&line),
+            (log::Level::Warn, "mxos::mem::btree", String::from(msg))
+        );
+    }
+
+    #[test]
+    fn escaped_escapes_quotes_backslashes_and_newlines() {
+        fn escaped(args: fmt::Arguments) -> String {
+            format!("{}", Escaped(args))
+        }
+
+        assert_eq!(escaped(format_args!("plain")), "plain");
+        assert_eq!(escaped(format_args!("")), "");
+        assert_eq!(
+            escaped(format_args!("say \"hi\"\r\n")),
+            "say \\\"hi\\\"\\r\\n"
+        );
+        assert_eq!(escaped(format_args!("C:\\dir\\")), "C:\\\\dir\\\\");
+        // The arguments are escaped too, not only the format string.
+        assert_eq!(
+            escaped(format_args!("{}={:?}", "a\nb", "\"")),
+            "a\\nb=\\\"\\\\\\\"\\\""
+        );
+        assert_eq!(escaped(format_args!("\t\u{e9}")), "\t\u{e9}");
+    }
+}
@@ -7,7 +7,7 @@
 use core::{
     fmt,
     mem::{self, ManuallyDrop, MaybeUninit},
-    ops::{Bound, RangeBounds},
+    ops::{Bound, Range, RangeBounds},
     ptr, slice,
 };
 
@@ -53,6 +53,10 @@ impl<T> MaybeUninitExt for MaybeUninit<T> {
     }
 }
 
+/// The byte that fills the slots vacated by `pop`, `remove` and the other removals in debug builds,
+/// so stale elements exposed by a wrong length are easier to spot.
+pub const POISON: u8 = 0xa5;
+
 /// A `StackVec` that doesn't store it's own length.
 /// Most of the operations are inline, that's because this struct is intended to be wrapped.
 ///
@@ -73,6 +77,8 @@ pub struct OuterLenStackVecDrain<'a, T, const N: usize> {
     iter: slice::Iter<'a, T>,
 }
 
+// The methods take the length from the owner, it must be the vector's length.
+#[allow(clippy::missing_safety_doc)]
 impl<T, const N: usize> OuterLenStackVec<T, N> {
     /// Creates a new empty, zero length, `OuterLenStackVec`.
     pub fn new() -> Self {
@@ -96,7 +102,13 @@ impl<T, const N: usize> OuterLenStackVec<T, N> {
     #[must_use]
     #[inline]
     pub unsafe fn insert(&mut self, len: &mut usize, idx: usize, item: T) -> Option<T> {
-        assert!(idx <= *len);
+        assert!(
+            idx <= *len,
+            "Insertion index {} is out of bounds for len {} (capacity {})",
+            idx,
+            *len,
+            N,
+        );
 
         if idx == N {
             Some(item)
@@ -124,7 +136,9 @@ impl<T, const N: usize> OuterLenStackVec<T, N> {
     pub unsafe fn pop(&mut self, len: &mut usize) -> Option<T> {
         if 0 < *len {
             *len -= 1;
-            Some(self._data[*len].as_ptr().read())
+            let item = self._data[*len].as_ptr().read();
+            self.poison(*len);
+            Some(item)
         } else {
             None
         }
@@ -132,7 +146,12 @@ impl<T, const N: usize> OuterLenStackVec<T, N> {
 
     #[inline]
     pub unsafe fn remove(&mut self, len: &mut usize, idx: usize) -> T {
-        assert!(idx < *len);
+        assert!(
+            idx < *len,
+            "Removal index {} is out of bounds for len {}",
+            idx,
+            *len,
+        );
         *len -= 1;
 
         let item = self._data[idx].as_ptr().read();
@@ -141,13 +160,37 @@ impl<T, const N: usize> OuterLenStackVec<T, N> {
             self._data.as_mut_ptr().add(idx),
             *len - idx,
         );
+        self.poison(*len);
 
         item
     }
 
+    /// Fills the vacated slot `idx` with `POISON` in debug builds, release builds skip it.
+    #[inline(always)]
+    unsafe fn poison(&mut self, idx: usize) {
+        self.poison_range(idx..idx + 1);
+    }
+
+    /// Fills the vacated slots in `range` with `POISON` in debug builds, release builds skip it.
+    #[inline(always)]
+    unsafe fn poison_range(&mut self, range: Range<usize>) {
+        if cfg!(debug_assertions) {
+            ptr::write_bytes(
+                self._data[range.clone()].as_mut_ptr() as *mut u8,
+                POISON,
+                range.len() * mem::size_of::<T>(),
+            );
+        }
+    }
+
     #[inline]
     pub unsafe fn split_at(&mut self, len: &mut usize, left_len: usize) -> StackVec<T, N> {
-        assert!(left_len <= *len);
+        assert!(
+            left_len <= *len,
+            "Split index {} is out of bounds for len {}",
+            left_len,
+            *len,
+        );
 
         let mut right = StackVec::new();
         right.set_len(*len - left_len);
@@ -157,6 +200,7 @@ impl<T, const N: usize> OuterLenStackVec<T, N> {
             right.data_mut().as_mut_ptr(),
             right._len,
         );
+        self.poison_range(left_len..*len);
         *len = left_len;
 
         right
@@ -192,6 +236,16 @@ impl<T, const N: usize> OuterLenStackVec<T, N> {
         cloned
     }
 
+    /// Consumes the vector as a `StackVec` of length `len`, and returns its owning iterator.
+    ///
+    /// # Safety
+    /// `len` must be the vector's length: at most `N`, and the first `len` elements must be
+    /// initialized. They're moved out, so they mustn't be used through the length's other copies.
+    #[inline]
+    pub unsafe fn into_iter(self, len: usize) -> StackVecIntoIter<T, N> {
+        StackVec::from_raw_parts(self, len).into_iter()
+    }
+
     #[inline]
     pub unsafe fn drain<'a, R: RangeBounds<usize>>(
         &'a mut self,
@@ -209,7 +263,13 @@ impl<T, const N: usize> OuterLenStackVec<T, N> {
             Bound::Included(&end) => end + 1,
             Bound::Unbounded => len0,
         };
-        assert!(start <= end && end <= len0);
+        assert!(
+            start <= end && end <= len0,
+            "Range {}..{} is out of bounds for len {}",
+            start,
+            end,
+            len0,
+        );
 
         *len = start;
         let range_slice = slice::from_raw_parts_mut(self.as_mut_ptr().add(start), end - start);
@@ -243,6 +303,12 @@ impl<'a, T, const N: usize> OuterLenStackVecDrain<'a, T, N> {
     pub fn as_slice(&self) -> &[T] {
         self.iter.as_slice()
     }
+
+    /// Returns the number of drained elements that haven't been yielded yet.
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.iter.len()
+    }
 }
 
 impl<'a, T: fmt::Debug, const N: usize> fmt::Debug for OuterLenStackVecDrain<'a, T, N> {
@@ -273,7 +339,12 @@ impl<'a, T, const N: usize> DoubleEndedIterator for OuterLenStackVecDrain<'a, T,
     }
 }
 
-impl<'a, T, const N: usize> ExactSizeIterator for OuterLenStackVecDrain<'a, T, N> {}
+impl<'a, T, const N: usize> ExactSizeIterator for OuterLenStackVecDrain<'a, T, N> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.remaining()
+    }
+}
 
 impl<'a, T, const N: usize> Drop for OuterLenStackVecDrain<'a, T, N> {
     fn drop(&mut self) {
@@ -287,12 +358,12 @@ impl<'a, T, const N: usize> Drop for OuterLenStackVecDrain<'a, T, N> {
                 // nothing.
                 self.0.for_each(drop);
 
-                if self.0.tail_len > 0 {
-                    unsafe {
-                        let source_vec = self.0.vec.as_mut();
+                unsafe {
+                    let source_vec = self.0.vec.as_mut();
+                    let start = *self.0.vec_len;
+                    let tail = self.0.tail_start;
+                    if self.0.tail_len > 0 {
                         // memmove back untouched tail, update to new length
-                        let start = *self.0.vec_len;
-                        let tail = self.0.tail_start;
                         if tail != start {
                             let src = source_vec.as_ptr().add(tail);
                             let dst = source_vec.as_mut_ptr().add(start);
@@ -300,6 +371,7 @@ impl<'a, T, const N: usize> Drop for OuterLenStackVecDrain<'a, T, N> {
                         }
                         *self.0.vec_len = start + self.0.tail_len;
                     }
+                    source_vec.poison_range(start + self.0.tail_len..tail + self.0.tail_len);
                 }
             }
         }
@@ -316,8 +388,25 @@ impl<'a, T, const N: usize> Drop for OuterLenStackVecDrain<'a, T, N> {
     }
 }
 
+/// Types whose all-zero bit pattern is a valid value.
+///
+/// # Safety
+/// A value of all zero bytes must be valid for the type.
+pub unsafe trait Zeroable {}
+
+macro_rules! impl_zeroable {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl Zeroable for $t {})*
+    };
+}
+
+impl_zeroable!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
 /// A dynamicaly sized array with a limited capacity `N` stored on the stack.
 /// The interface of `StackVec` is designed to be similar to `Vec`.
+///
+/// A zero capacity `StackVec` is allowed and always empty and full: every push or insert
+/// overflows and returns the item back, and every pop returns `None`.
 #[repr(C)]
 pub struct StackVec<T, const N: usize> {
     _data: OuterLenStackVec<T, N>,
@@ -348,6 +437,28 @@ impl<T, const N: usize> StackVec<T, N> {
         unsafe { Self::from_raw_parts(OuterLenStackVec::new(), 0) }
     }
 
+    /// Creates a `StackVec` of `len` zeroed elements, by zeroing the memory in bulk.
+    ///
+    /// # Panics
+    /// Panics if `len` is greater than the capacity.
+    pub fn zeroed(len: usize) -> Self
+    where
+        T: Zeroable,
+    {
+        assert!(
+            len <= N,
+            "Length {} is greater than the capacity {}",
+            len,
+            N,
+        );
+        let mut vec = Self::new();
+        unsafe {
+            ptr::write_bytes(vec.as_mut_ptr(), 0, len);
+            vec.set_len(len);
+        }
+        vec
+    }
+
     #[inline(always)]
     fn data(&self) -> &[MaybeUninit<T>; N] {
         &self._data._data
@@ -376,6 +487,12 @@ impl<T, const N: usize> StackVec<T, N> {
         self._len
     }
 
+    /// Returns true if the vector contains no elements.
+    #[inline(always)]
+    pub const fn is_empty(&self) -> bool {
+        self._len == 0
+    }
+
     /// Returns the maximum number of elements in the vector, also referred to as its 'capacity'.
     /// This function just returns the generic constant `N`.
     #[inline(always)]
@@ -427,6 +544,9 @@ impl<T, const N: usize> StackVec<T, N> {
     }
 
     /// Creates a `StackVec` directly from its raw components.
+    ///
+    /// # Safety
+    /// The first `len` elements of `data` must be initialized, and `len` must be at most `N`.
     #[inline(always)]
     pub const unsafe fn from_raw_parts(data: OuterLenStackVec<T, N>, len: usize) -> Self {
         Self {
@@ -438,28 +558,115 @@ impl<T, const N: usize> StackVec<T, N> {
     /// Inserts `item` at `idx` such that `self[idx] == item`, elements are shifted to the right to
     /// make space. The function returns the rightmost element in case of an overflow.
     ///
+    /// When the vector is full, the last element is shifted out and returned, so inserting in the
+    /// front or the middle evicts the last element. Inserting at the end (`idx == len`) of a full
+    /// vector returns `item` itself, and the vector is unchanged. Use `try_insert` to never evict.
+    ///
     /// Because this shifts over the remaining elements, it has a worst-case performance of O(n).
     #[must_use]
     pub fn insert(&mut self, idx: usize, item: T) -> Option<T> {
         unsafe { self._data.insert(&mut self._len, idx, item) }
     }
 
+    /// Inserts `item` at `idx` like `insert`, but if the vector is full it's left unchanged and
+    /// `Err(item)` is returned.
+    ///
+    /// # Panics
+    /// Panics if `idx` is greater than the length.
+    pub fn try_insert(&mut self, idx: usize, item: T) -> Result<(), T> {
+        assert!(
+            idx <= self.len(),
+            "Insertion index {} is out of bounds for len {} (capacity {})",
+            idx,
+            self.len(),
+            N,
+        );
+        if self.is_full() {
+            return Err(item);
+        }
+        assert!(self.insert(idx, item).is_none());
+        Ok(())
+    }
+
     /// Appends an element to the back of the vector, returns the item in case of an overflow.
     #[must_use]
     pub fn push(&mut self, item: T) -> Option<T> {
         unsafe { self._data.push(&mut self._len, item) }
     }
 
+    /// Same as `push`, but with the standard library's signature: returns `Err(item)` in case of
+    /// an overflow.
+    #[inline]
+    pub fn push_within_capacity(&mut self, item: T) -> Result<(), T> {
+        match self.push(item) {
+            Some(item) => Err(item),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the spare capacity of the vector, the uninitialized elements after its length.
+    ///
+    /// Once the elements are written, they can be added to the vector with `set_len`.
+    #[inline]
+    pub fn spare_capacity_mut(&mut self) -> &mut [MaybeUninit<T>] {
+        let len = self.len();
+        &mut self.data_mut()[len..]
+    }
+
     /// Removes the last element from the vector and returns it, or None if it is empty.
     pub fn pop(&mut self) -> Option<T> {
         unsafe { self._data.pop(&mut self._len) }
     }
 
-    // /// Splits the vector, such that `self` will contain the left pa
+    /// Prepends an element to the front of the vector, returns the last element in case of an
+    /// overflow. Same as `insert(0, item)`.
+    ///
+    /// Because this shifts over all the elements, it has a performance of O(n).
+    #[must_use]
+    pub fn push_front(&mut self, item: T) -> Option<T> {
+        self.insert(0, item)
+    }
+
+    /// Removes the first element from the vector and returns it, or None if it is empty.
+    ///
+    /// Because this shifts over all the remaining elements, it has a performance of O(n).
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.remove(0))
+        }
+    }
+
+    /// Splits the vector, such that `self` will contain the left part, the first `left_len`
+    /// elements, and the right part is moved to the returned vector.
+    ///
+    /// # Panics
+    /// Panics if `left_len > len`.
     pub fn split_at(&mut self, left_len: usize) -> Self {
         unsafe { self._data.split_at(&mut self._len, left_len) }
     }
 
+    /// Removes the first `n` elements and returns them as a new vector, the rest are shifted to
+    /// the front. It's the opposite of `split_at`, which keeps the prefix.
+    ///
+    /// # Panics
+    /// Panics if `n > len`.
+    pub fn take_n(&mut self, n: usize) -> Self {
+        let tail = self.split_at(n);
+        mem::replace(self, tail)
+    }
+
+    /// Divides the live elements into two mutable slices at `mid`, unlike `split_at` it doesn't
+    /// move any elements.
+    ///
+    /// # Panics
+    /// Panics if `mid > len`.
+    #[inline]
+    pub fn split_at_mut(&mut self, mid: usize) -> (&mut [T], &mut [T]) {
+        self.as_slice_mut().split_at_mut(mid)
+    }
+
     /// Removes and returns the element at position index within the vector, shifting all elements after it to the left.
     ///
     /// Because this shifts over the remaining elements, it has a worst-case performance of O(n).
@@ -467,6 +674,18 @@ impl<T, const N: usize> StackVec<T, N> {
         unsafe { self._data.remove(&mut self._len, idx) }
     }
 
+    /// Removes the first element that is equal to `item` and returns it, shifting all elements
+    /// after it to the left. Returns `None` if there's no such element.
+    ///
+    /// Because this searches and shifts over the elements, it has a performance of O(n).
+    pub fn remove_item(&mut self, item: &T) -> Option<T>
+    where
+        T: PartialEq,
+    {
+        let idx = self.iter().position(|x| x == item)?;
+        Some(self.remove(idx))
+    }
+
     pub fn as_slice(&self) -> &[T] {
         unsafe { self._data.as_slice(self.len()) }
     }
@@ -475,7 +694,98 @@ impl<T, const N: usize> StackVec<T, N> {
         unsafe { self._data.as_slice_mut(self.len()) }
     }
 
-    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> StackVecDrain<T, N> {
+    /// Returns a reference to the element at `idx`, or `None` if `idx` is out of bounds.
+    ///
+    /// This shadows the slice's `get`, so method resolution doesn't depend on autoref/deref.
+    #[inline]
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        if idx < self.len() {
+            Some(unsafe { &*self.as_ptr().add(idx) })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the element at `idx`, or `None` if `idx` is out of bounds.
+    #[inline]
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        if idx < self.len() {
+            Some(unsafe { &mut *self.as_mut_ptr().add(idx) })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the index of the first element for which `pred` is false, assuming the vector is
+    /// partitioned by `pred` (all the elements for which it's true come first). For a sorted
+    /// vector, `partition_point(|x| *x < key)` is the index of the first element not less than
+    /// `key`. It's a binary search, O(log n).
+    #[inline]
+    pub fn partition_point<P: FnMut(&T) -> bool>(&self, pred: P) -> usize {
+        self.as_slice().partition_point(pred)
+    }
+
+    /// Retains only the elements for which `pred` returns true, and the elements of `other` at
+    /// the same positions, so parallel vectors (like keys and values) stay aligned. `pred` is
+    /// called with both elements, in order, and the order of the retained elements is kept.
+    ///
+    /// If `pred` panics, the elements after the retained ones are leaked.
+    ///
+    /// # Panics
+    /// Panics if the lengths of the vectors differ.
+    pub fn retain_with<U, const M: usize, F>(&mut self, other: &mut StackVec<U, M>, mut pred: F)
+    where
+        F: FnMut(&T, &U) -> bool,
+    {
+        let len = self.len();
+        assert_eq!(len, other.len(), "The lengths of the vectors differ");
+
+        unsafe {
+            // The lengths cover only the retained elements, so a panic never drops twice.
+            self.set_len(0);
+            other.set_len(0);
+            let mut retained = 0;
+            for i in 0..len {
+                let item = self.as_mut_ptr().add(i);
+                let other_item = other.as_mut_ptr().add(i);
+                if pred(&*item, &*other_item) {
+                    ptr::copy(item, self.as_mut_ptr().add(retained), 1);
+                    ptr::copy(other_item, other.as_mut_ptr().add(retained), 1);
+                    retained += 1;
+                    self.set_len(retained);
+                    other.set_len(retained);
+                } else {
+                    ptr::drop_in_place(item);
+                    ptr::drop_in_place(other_item);
+                }
+            }
+            self._data.poison_range(retained..len);
+            other._data.poison_range(retained..len);
+        }
+    }
+
+    /// Views the elements as a slice of `U`s, e.g. a `StackVec<u8, N>` as `&[u64]`. Returns `None`
+    /// if the elements' size isn't a multiple of `U`'s size, if the data isn't aligned for `U`, or
+    /// if `U` is zero sized.
+    ///
+    /// # Safety
+    /// `T` mustn't have padding bytes, and every bit pattern must be a valid `U` (like for the
+    /// integer types).
+    pub unsafe fn reinterpret_as<U>(&self) -> Option<&[U]> {
+        let size = self.len() * mem::size_of::<T>();
+        if mem::size_of::<U>() == 0
+            || !size.is_multiple_of(mem::size_of::<U>())
+            || !(self.as_ptr() as usize).is_multiple_of(mem::align_of::<U>())
+        {
+            return None;
+        }
+        Some(slice::from_raw_parts(
+            self.as_ptr() as *const U,
+            size / mem::size_of::<U>(),
+        ))
+    }
+
+    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> StackVecDrain<'_, T, N> {
         let len = self.len();
         let start = match range.start_bound() {
             Bound::Excluded(&start) => start + 1,
@@ -487,7 +797,13 @@ impl<T, const N: usize> StackVec<T, N> {
             Bound::Included(&end) => end + 1,
             Bound::Unbounded => len,
         };
-        assert!(start <= end && end <= len);
+        assert!(
+            start <= end && end <= len,
+            "Range {}..{} is out of bounds for len {}",
+            start,
+            end,
+            len,
+        );
 
         unsafe {
             self.set_len(start);
@@ -500,6 +816,161 @@ impl<T, const N: usize> StackVec<T, N> {
             }
         }
     }
+
+    /// Removes and drops the elements in `range`, shifting the elements after it to the left.
+    /// It's the same as dropping `drain(range)` without producing the iterator.
+    ///
+    /// If an element's `drop` panics, the rest are still dropped and the tail is still shifted.
+    pub fn remove_range<R: RangeBounds<usize>>(&mut self, range: R) {
+        let len = self.len();
+        let start = match range.start_bound() {
+            Bound::Excluded(&start) => start + 1,
+            Bound::Included(&start) => start,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Excluded(&end) => end,
+            Bound::Included(&end) => end + 1,
+            Bound::Unbounded => len,
+        };
+        assert!(
+            start <= end && end <= len,
+            "Range {}..{} is out of bounds for len {}",
+            start,
+            end,
+            len,
+        );
+
+        /// Moves the tail back when dropped, even during unwinding.
+        struct TailGuard<'a, T, const N: usize> {
+            vec: &'a mut StackVec<T, N>,
+            start: usize,
+            end: usize,
+            len: usize,
+        }
+
+        impl<'a, T, const N: usize> Drop for TailGuard<'a, T, N> {
+            fn drop(&mut self) {
+                unsafe {
+                    let ptr = self.vec.as_mut_ptr();
+                    ptr::copy(ptr.add(self.end), ptr.add(self.start), self.len - self.end);
+                    self.vec.set_len(self.start + self.len - self.end);
+                    self.vec
+                        ._data
+                        .poison_range(self.start + self.len - self.end..self.len);
+                }
+            }
+        }
+
+        unsafe {
+            // Until the guard moves the tail back, the removed and tail elements are hidden.
+            self.set_len(start);
+            let guard = TailGuard {
+                vec: self,
+                start,
+                end,
+                len,
+            };
+            ptr::drop_in_place(core::ptr::slice_from_raw_parts_mut(
+                guard.vec.as_mut_ptr().add(start),
+                end - start,
+            ));
+        }
+    }
+
+    /// Same as `drain`, but yields the drained elements from the last to the first. The tail is
+    /// moved back when the iterator is dropped, like in `drain`.
+    #[inline]
+    pub fn drain_rev<R: RangeBounds<usize>>(
+        &mut self,
+        range: R,
+    ) -> core::iter::Rev<StackVecDrain<'_, T, N>> {
+        self.drain(range).rev()
+    }
+
+    /// Returns an iterator over `size` elements of the vector at a time, starting at the
+    /// beginning. The last chunk is shorter if `size` doesn't divide the length.
+    ///
+    /// # Panics
+    /// Panics if `size` is 0.
+    #[inline]
+    pub fn chunks_mut(&mut self, size: usize) -> slice::ChunksMut<'_, T> {
+        self.as_slice_mut().chunks_mut(size)
+    }
+
+    /// Removes `range` from the vec and returns it as a new owned vec.
+    pub fn drain_to_new<R: RangeBounds<usize>>(&mut self, range: R) -> Self {
+        let len = self.len();
+        let start = match range.start_bound() {
+            Bound::Excluded(&start) => start + 1,
+            Bound::Included(&start) => start,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Excluded(&end) => end,
+            Bound::Included(&end) => end + 1,
+            Bound::Unbounded => len,
+        };
+        assert!(
+            start <= end && end <= len,
+            "Range {}..{} is out of bounds for len {}",
+            start,
+            end,
+            len,
+        );
+
+        let mut moved = self.split_at(start);
+        let tail = moved.split_at(end - start);
+        for x in tail {
+            assert!(self.push(x).is_none());
+        }
+        moved
+    }
+
+    /// Removes consecutive repeated elements, keeping the first of every run.
+    pub fn dedup(&mut self)
+    where
+        T: PartialEq,
+    {
+        self.dedup_by(|a, b| a == b)
+    }
+
+    /// Removes consecutive elements that resolve to the same key, keeping the first of every run.
+    pub fn dedup_by_key<K: PartialEq, F: FnMut(&mut T) -> K>(&mut self, mut key: F) {
+        self.dedup_by(|a, b| key(a) == key(b))
+    }
+
+    /// Removes consecutive elements for which `same_bucket(a, b)` returns true, where `a` is the
+    /// current element and `b` is the last element that was kept. The removed elements are
+    /// dropped.
+    pub fn dedup_by<F: FnMut(&mut T, &mut T) -> bool>(&mut self, mut same_bucket: F) {
+        let len = self.len();
+        if len <= 1 {
+            return;
+        }
+
+        unsafe {
+            // Leak instead of double dropping if `same_bucket` panics.
+            self.set_len(0);
+
+            let data = self.as_mut_ptr();
+            let mut write = 1;
+            for read in 1..len {
+                let read_ptr = data.add(read);
+                if same_bucket(&mut *read_ptr, &mut *data.add(write - 1)) {
+                    ptr::drop_in_place(read_ptr);
+                } else {
+                    if read != write {
+                        ptr::copy_nonoverlapping(read_ptr, data.add(write), 1);
+                    }
+                    write += 1;
+                }
+            }
+
+            self.set_len(write);
+            self._data.poison_range(write..len);
+        }
+    }
 }
 
 impl<T, const N: usize> core::ops::Deref for StackVec<T, N> {
@@ -518,6 +989,34 @@ impl<T, const N: usize> core::ops::DerefMut for StackVec<T, N> {
     }
 }
 
+impl<T, const N: usize> AsRef<[T]> for StackVec<T, N> {
+    #[inline(always)]
+    fn as_ref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> AsMut<[T]> for StackVec<T, N> {
+    #[inline(always)]
+    fn as_mut(&mut self) -> &mut [T] {
+        self.as_slice_mut()
+    }
+}
+
+impl<T, const N: usize> core::borrow::Borrow<[T]> for StackVec<T, N> {
+    #[inline(always)]
+    fn borrow(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> core::borrow::BorrowMut<[T]> for StackVec<T, N> {
+    #[inline(always)]
+    fn borrow_mut(&mut self) -> &mut [T] {
+        self.as_slice_mut()
+    }
+}
+
 impl<T, const N: usize> Default for StackVec<T, N> {
     #[inline(always)]
     fn default() -> Self {
@@ -525,9 +1024,47 @@ impl<T, const N: usize> Default for StackVec<T, N> {
     }
 }
 
+impl<T, const N: usize, const M: usize> From<[T; M]> for StackVec<T, N> {
+    /// Moves the elements of `array` into a new vector. `M > N` is a compile error.
+    fn from(array: [T; M]) -> Self {
+        const { assert!(M <= N, "The array's length is larger than the capacity") };
+        let array = ManuallyDrop::new(array);
+        let mut vec = Self::new();
+        unsafe {
+            ptr::copy_nonoverlapping(array.as_ptr(), vec.as_mut_ptr(), M);
+            vec.set_len(M);
+        }
+        vec
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<T, const N: usize> From<heapless::Vec<T, N>> for StackVec<T, N> {
+    /// Moves the elements of `vec` into a new vector of the same capacity.
+    fn from(vec: heapless::Vec<T, N>) -> Self {
+        let mut stack_vec = Self::new();
+        for item in vec {
+            assert!(stack_vec.push(item).is_none());
+        }
+        stack_vec
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<T, const N: usize> From<StackVec<T, N>> for heapless::Vec<T, N> {
+    /// Moves the elements of `stack_vec` into a new `heapless::Vec` of the same capacity.
+    fn from(stack_vec: StackVec<T, N>) -> Self {
+        let mut vec = Self::new();
+        for item in stack_vec {
+            assert!(vec.push(item).is_ok());
+        }
+        vec
+    }
+}
+
 impl<T, const N: usize> Drop for StackVec<T, N> {
     fn drop(&mut self) {
-        while let Some(_) = self.pop() {}
+        while self.pop().is_some() {}
     }
 }
 
@@ -537,6 +1074,22 @@ impl<T: Clone, const N: usize> Clone for StackVec<T, N> {
     }
 }
 
+/// Appends the UTF-8 bytes of the written strings. A string that doesn't fit isn't written at all
+/// and an error is returned, so the buffer always holds whole strings.
+impl<const N: usize> fmt::Write for StackVec<u8, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.len();
+        if N - len < s.len() {
+            return Err(fmt::Error);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(s.as_ptr(), self.as_mut_ptr().add(len), s.len());
+            self.set_len(len + s.len());
+        }
+        Ok(())
+    }
+}
+
 impl<T: fmt::Debug, const N: usize> fmt::Debug for StackVec<T, N> {
     #[inline(always)]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -665,7 +1218,7 @@ impl<T: fmt::Debug, const N: usize> fmt::Debug for StackVecIntoIter<T, N> {
 impl<T, const N: usize> Drop for StackVecIntoIter<T, N> {
     fn drop(&mut self) {
         // self.for_each()
-        while let Some(_) = self.next() {}
+        for _ in self.by_ref() {}
 
         unsafe {
             self._data.set_len(0);
@@ -677,6 +1230,12 @@ impl<'a, T, const N: usize> StackVecDrain<'a, T, N> {
     pub fn as_slice(&self) -> &[T] {
         self.iter.as_slice()
     }
+
+    /// Returns the number of drained elements that haven't been yielded yet.
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.iter.len()
+    }
 }
 
 impl<'a, T: fmt::Debug, const N: usize> fmt::Debug for StackVecDrain<'a, T, N> {
@@ -707,7 +1266,12 @@ impl<'a, T, const N: usize> DoubleEndedIterator for StackVecDrain<'a, T, N> {
     }
 }
 
-impl<'a, T, const N: usize> ExactSizeIterator for StackVecDrain<'a, T, N> {}
+impl<'a, T, const N: usize> ExactSizeIterator for StackVecDrain<'a, T, N> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.remaining()
+    }
+}
 
 impl<'a, T, const N: usize> Drop for StackVecDrain<'a, T, N> {
     fn drop(&mut self) {
@@ -721,12 +1285,12 @@ impl<'a, T, const N: usize> Drop for StackVecDrain<'a, T, N> {
                 // nothing.
                 self.0.for_each(drop);
 
-                if self.0.tail_len > 0 {
-                    unsafe {
-                        let source_vec = self.0.vec.as_mut();
+                unsafe {
+                    let source_vec = self.0.vec.as_mut();
+                    let start = source_vec.len();
+                    let tail = self.0.tail_start;
+                    if self.0.tail_len > 0 {
                         // memmove back untouched tail, update to new length
-                        let start = source_vec.len();
-                        let tail = self.0.tail_start;
                         if tail != start {
                             let src = source_vec.as_ptr().add(tail);
                             let dst = source_vec.as_mut_ptr().add(start);
@@ -734,6 +1298,9 @@ impl<'a, T, const N: usize> Drop for StackVecDrain<'a, T, N> {
                         }
                         source_vec.set_len(start + self.0.tail_len);
                     }
+                    source_vec
+                        ._data
+                        .poison_range(start + self.0.tail_len..tail + self.0.tail_len);
                 }
             }
         }
@@ -749,3 +1316,712 @@ impl<'a, T, const N: usize> Drop for StackVecDrain<'a, T, N> {
         DropGuard(self);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::vec::Vec;
+
+    /// Counts its drops, and panics when it's dropped if `panics` is set.
+    struct DropCounter<'a> {
+        drops: &'a Cell<usize>,
+        panics: bool,
+    }
+
+    impl<'a> Drop for DropCounter<'a> {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+            if self.panics {
+                panic!("DropCounter panicked");
+            }
+        }
+    }
+
+    /// Returns a vector of `(i, counter)` for `i` in `0..len`, the counter of `panic_at` panics.
+    fn counted<const N: usize>(
+        drops: &Cell<usize>,
+        len: usize,
+        panic_at: Option<usize>,
+    ) -> StackVec<(usize, DropCounter<'_>), N> {
+        let mut vec = StackVec::new();
+        for i in 0..len {
+            let counter = DropCounter {
+                drops,
+                panics: panic_at == Some(i),
+            };
+            assert!(vec.push((i, counter)).is_none());
+        }
+        vec
+    }
+
+    fn indices<T, const N: usize>(vec: &StackVec<(usize, T), N>) -> Vec<usize> {
+        vec.iter().map(|&(i, _)| i).collect()
+    }
+
+    #[test]
+    fn remove_range_drops_the_range_once() {
+        let drops = Cell::new(0);
+        let mut vec = counted::<8>(&drops, 6, None);
+
+        vec.remove_range(1..4);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(indices(&vec), [0, 4, 5]);
+
+        vec.remove_range(..);
+        assert_eq!(drops.get(), 6);
+        assert!(vec.is_empty());
+    }
+
+    #[test]
+    fn remove_range_shifts_the_tail_when_a_drop_panics() {
+        let drops = Cell::new(0);
+        let mut vec = counted::<8>(&drops, 6, Some(2));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| vec.remove_range(1..4)));
+        assert!(result.is_err());
+        // The elements after the panicking one are dropped too, and nothing is dropped twice.
+        assert_eq!(drops.get(), 3);
+        assert_eq!(indices(&vec), [0, 4, 5]);
+
+        drop(vec);
+        assert_eq!(drops.get(), 6);
+    }
+
+    /// Returns the vector `[1, 2, .., len]`.
+    #[cfg(debug_assertions)]
+    fn numbers<const N: usize>(len: u32) -> StackVec<u32, N> {
+        let mut vec = StackVec::new();
+        for i in 1..=len {
+            assert!(vec.push(i).is_none());
+        }
+        vec
+    }
+
+    /// Asserts that the slots `slots` starting at `data` are filled with `POISON`.
+    #[cfg(debug_assertions)]
+    unsafe fn assert_poisoned<T>(data: *const T, slots: Range<usize>) {
+        let bytes = slice::from_raw_parts(
+            data.add(slots.start) as *const u8,
+            slots.len() * mem::size_of::<T>(),
+        );
+        assert!(bytes.iter().all(|&b| b == POISON), "{:x?}", bytes);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn removals_poison_the_vacated_tail() {
+        unsafe {
+            let mut vec = numbers::<8>(6);
+            vec.drain(1..3);
+            assert_eq!(vec.as_slice(), [1, 4, 5, 6]);
+            assert_poisoned(vec.as_ptr(), 4..6);
+
+            let mut vec = numbers::<8>(6);
+            vec.drain(2..);
+            assert_poisoned(vec.as_ptr(), 2..6);
+
+            let mut vec = numbers::<8>(6);
+            vec.remove_range(..2);
+            assert_eq!(vec.as_slice(), [3, 4, 5, 6]);
+            assert_poisoned(vec.as_ptr(), 4..6);
+
+            let mut vec = numbers::<8>(6);
+            let right = vec.split_at(2);
+            assert_eq!(right.as_slice(), [3, 4, 5, 6]);
+            assert_poisoned(vec.as_ptr(), 2..6);
+
+            let mut vec = numbers::<8>(6);
+            let moved = vec.drain_to_new(1..3);
+            assert_eq!(
+                (vec.as_slice(), moved.as_slice()),
+                ([1, 4, 5, 6].as_slice(), [2, 3].as_slice())
+            );
+            assert_poisoned(vec.as_ptr(), 4..6);
+            assert_poisoned(moved.as_ptr(), 2..5);
+
+            let mut vec = numbers::<8>(6);
+            vec.dedup_by_key(|i| *i / 3);
+            assert_eq!(vec.as_slice(), [1, 3, 6]);
+            assert_poisoned(vec.as_ptr(), 3..6);
+
+            let mut vec = numbers::<8>(6);
+            let mut other = numbers::<8>(6);
+            vec.retain_with(&mut other, |&i, _| i % 2 == 0);
+            assert_eq!(
+                (vec.as_slice(), other.as_slice()),
+                ([2, 4, 6].as_slice(), [2, 4, 6].as_slice())
+            );
+            assert_poisoned(vec.as_ptr(), 3..6);
+            assert_poisoned(other.as_ptr(), 3..6);
+
+            let mut data = OuterLenStackVec::<u32, 8>::new();
+            let mut len = 0;
+            for i in 1..=6 {
+                assert!(data.push(&mut len, i).is_none());
+            }
+            data.drain(&mut len, 1..3);
+            assert_eq!(data.as_slice(len), [1, 4, 5, 6]);
+            assert_poisoned(data.as_ptr(), 4..6);
+        }
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn heapless_round_trip() {
+        let mut vec = StackVec::<u32, 4>::new();
+        for i in [3, 1, 4] {
+            assert!(vec.push(i).is_none());
+        }
+
+        let heapless_vec = heapless::Vec::from(vec);
+        assert_eq!(heapless_vec.as_slice(), [3, 1, 4]);
+        assert_eq!(heapless_vec.capacity(), 4);
+
+        let vec = StackVec::from(heapless_vec);
+        assert_eq!(vec.as_slice(), [3, 1, 4]);
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn heapless_round_trip_moves_the_elements() {
+        let drops = Cell::new(0);
+        let vec = counted::<4>(&drops, 4, None);
+
+        let heapless_vec: heapless::Vec<_, 4> = vec.into();
+        assert_eq!(drops.get(), 0);
+        let vec = StackVec::from(heapless_vec);
+        assert_eq!(drops.get(), 0);
+        assert_eq!(indices(&vec), [0, 1, 2, 3]);
+
+        drop(vec);
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    fn from_array_of_the_exact_size() {
+        let vec = StackVec::<u32, 3>::from([3, 1, 4]);
+        assert_eq!(vec.as_slice(), [3, 1, 4]);
+        assert_eq!(vec.len(), 3);
+        assert!(vec.is_full());
+    }
+
+    #[test]
+    fn from_smaller_array() {
+        let vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        assert_eq!(vec.as_slice(), [3, 1, 4]);
+        assert_eq!(vec.capacity(), 8);
+
+        let drops = Cell::new(0);
+        let vec = StackVec::<_, 4>::from([(), ()].map(|()| DropCounter {
+            drops: &drops,
+            panics: false,
+        }));
+        assert_eq!(vec.len(), 2);
+        assert_eq!(drops.get(), 0);
+        drop(vec);
+        assert_eq!(drops.get(), 2);
+
+        assert!(StackVec::<u32, 4>::from([]).is_empty());
+    }
+
+    #[test]
+    fn split_at_mut_moves_nothing() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4, 1, 5]);
+        let data = vec.as_ptr();
+
+        let (left, right) = vec.split_at_mut(2);
+        assert_eq!(
+            (left.as_ptr(), right.as_ptr()),
+            (data, data.wrapping_add(2))
+        );
+        assert_eq!((&*left, &*right), ([3, 1].as_slice(), [4, 1, 5].as_slice()));
+        left[0] = 9;
+        right[0] = 2;
+        assert_eq!(vec.as_slice(), [9, 1, 2, 1, 5]);
+        assert_eq!(vec.len(), 5);
+
+        let (left, right) = vec.split_at_mut(5);
+        assert_eq!((left.len(), right.len()), (5, 0));
+    }
+
+    #[test]
+    fn split_at_moves_the_right_part() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4, 1, 5]);
+        let right = vec.split_at(2);
+        assert_eq!(vec.as_slice(), [3, 1]);
+        assert_eq!(right.as_slice(), [4, 1, 5]);
+    }
+
+    #[test]
+    fn zero_capacity_is_always_empty_and_full() {
+        let mut vec = StackVec::<u8, 0>::new();
+        assert!(vec.is_empty());
+        assert!(vec.is_full());
+        assert_eq!(vec.capacity(), 0);
+
+        assert_eq!(vec.push(1), Some(1));
+        assert_eq!(vec.push_within_capacity(2), Err(2));
+        assert_eq!(vec.insert(0, 3), Some(3));
+        assert_eq!(vec.try_insert(0, 4), Err(4));
+        assert_eq!(vec.push_front(5), Some(5));
+        assert_eq!(vec.pop(), None);
+        assert_eq!(vec.pop_front(), None);
+        assert_eq!(vec.drain(..).count(), 0);
+        assert!(vec.drain_to_new(..).is_empty());
+        assert!(vec.is_empty());
+        assert_eq!(vec.as_slice(), []);
+    }
+
+    #[test]
+    #[should_panic(expected = "Removal index 3 is out of bounds for len 3")]
+    fn remove_panics_past_the_end() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        vec.remove(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insertion index 4 is out of bounds for len 3 (capacity 8)")]
+    fn insert_panics_past_the_end() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        let _ = vec.insert(4, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Split index 4 is out of bounds for len 3")]
+    fn split_at_panics_past_the_end() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        vec.split_at(4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Range 2..4 is out of bounds for len 3")]
+    fn drain_panics_past_the_end() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        vec.drain(2..=3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Range 2..1 is out of bounds for len 3")]
+    fn remove_range_panics_on_a_reversed_range() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        #[allow(clippy::reversed_empty_ranges)]
+        vec.remove_range(2..1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Removal index 0 is out of bounds for len 0")]
+    fn outer_len_remove_panics_when_empty() {
+        let mut data = OuterLenStackVec::<u32, 4>::new();
+        let mut len = 0;
+        unsafe {
+            data.remove(&mut len, 0);
+        }
+    }
+
+    #[test]
+    fn drain_len_and_remaining_from_both_ends() {
+        let mut vec = StackVec::<u32, 8>::from([1, 2, 3, 4, 5, 6]);
+        let mut drain = vec.drain(1..5);
+        assert_eq!((drain.len(), drain.remaining()), (4, 4));
+        assert_eq!(drain.next(), Some(2));
+        assert_eq!((drain.len(), drain.remaining()), (3, 3));
+        assert_eq!(drain.next_back(), Some(5));
+        assert_eq!((drain.len(), drain.remaining()), (2, 2));
+        assert_eq!(drain.as_slice(), [3, 4]);
+        assert_eq!(drain.next_back(), Some(4));
+        assert_eq!(drain.next(), Some(3));
+        assert_eq!((drain.len(), drain.remaining()), (0, 0));
+        assert_eq!(drain.next(), None);
+        drop(drain);
+        assert_eq!(vec.as_slice(), [1, 6]);
+
+        let mut data = OuterLenStackVec::<u32, 8>::new();
+        let mut len = 0;
+        unsafe {
+            for i in 1..=6 {
+                assert!(data.push(&mut len, i).is_none());
+            }
+            let mut drain = data.drain(&mut len, 2..);
+            assert_eq!((drain.len(), drain.remaining()), (4, 4));
+            assert_eq!(drain.next_back(), Some(6));
+            assert_eq!(drain.next(), Some(3));
+            assert_eq!((drain.len(), drain.remaining()), (2, 2));
+            assert_eq!(drain.size_hint(), (2, Some(2)));
+            drop(drain);
+            assert_eq!(data.as_slice(len), [1, 2]);
+        }
+    }
+
+    #[test]
+    fn drain_to_new_moves_without_dropping() {
+        let drops = Cell::new(0);
+        let mut vec = counted::<8>(&drops, 6, None);
+
+        let moved = vec.drain_to_new(1..4);
+        assert_eq!(drops.get(), 0);
+        assert_eq!(indices(&moved), [1, 2, 3]);
+        assert_eq!(indices(&vec), [0, 4, 5]);
+
+        drop(moved);
+        assert_eq!(drops.get(), 3);
+        let moved = vec.drain_to_new(..);
+        assert!(vec.is_empty());
+        assert_eq!(indices(&moved), [0, 4, 5]);
+        assert_eq!(drops.get(), 3);
+        drop((vec, moved));
+        assert_eq!(drops.get(), 6);
+    }
+
+    #[test]
+    fn try_insert_leaves_a_full_vector_unchanged() {
+        let mut vec = StackVec::<u32, 4>::from([1, 2, 3]);
+        assert_eq!(vec.try_insert(0, 0), Ok(()));
+        assert_eq!(vec.as_slice(), [0, 1, 2, 3]);
+
+        for idx in [0, 2, 4] {
+            assert_eq!(vec.try_insert(idx, 9), Err(9));
+            assert_eq!(vec.as_slice(), [0, 1, 2, 3]);
+        }
+
+        vec.pop();
+        assert_eq!(vec.try_insert(2, 9), Ok(()));
+        assert_eq!(vec.as_slice(), [0, 1, 9, 2]);
+        vec.pop();
+        assert_eq!(vec.try_insert(3, 8), Ok(()));
+        assert_eq!(vec.as_slice(), [0, 1, 9, 8]);
+    }
+
+    #[test]
+    fn retain_with_keeps_the_vectors_aligned() {
+        let drops = Cell::new(0);
+        let mut keys = StackVec::<u32, 8>::from([1, 2, 3, 4, 5, 6]);
+        let mut values = counted::<6>(&drops, 6, None);
+
+        let mut calls = Vec::new();
+        keys.retain_with(&mut values, |&key, &(i, _)| {
+            calls.push((key, i));
+            key % 3 != 0
+        });
+        assert_eq!(calls, [(1, 0), (2, 1), (3, 2), (4, 3), (5, 4), (6, 5)]);
+        assert_eq!(keys.as_slice(), [1, 2, 4, 5]);
+        assert_eq!(indices(&values), [0, 1, 3, 4]);
+        assert_eq!(drops.get(), 2);
+
+        keys.retain_with(&mut values, |_, _| false);
+        assert!(keys.is_empty() && values.is_empty());
+        assert_eq!(drops.get(), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "The lengths of the vectors differ")]
+    fn retain_with_panics_on_different_lengths() {
+        let mut keys = StackVec::<u32, 8>::from([1, 2, 3]);
+        let mut values = StackVec::<u32, 8>::from([1, 2]);
+        keys.retain_with(&mut values, |_, _| true);
+    }
+
+    #[test]
+    fn reinterpret_as_checks_the_size_and_alignment() {
+        let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        let mut vec = StackVec::<u8, 16>::from(bytes);
+        unsafe {
+            assert_eq!(
+                vec.reinterpret_as::<u64>(),
+                Some(
+                    [
+                        u64::from_ne_bytes([1, 2, 3, 4, 5, 6, 7, 8]),
+                        u64::from_ne_bytes([9, 10, 11, 12, 13, 14, 15, 16]),
+                    ]
+                    .as_slice()
+                )
+            );
+            assert_eq!(vec.reinterpret_as::<u8>(), Some(bytes.as_slice()));
+            assert_eq!(vec.reinterpret_as::<()>(), None);
+            vec.pop();
+            assert_eq!(vec.reinterpret_as::<u64>(), None);
+            assert_eq!(vec.reinterpret_as::<u16>(), None);
+            vec.drain(..);
+            assert_eq!(vec.reinterpret_as::<u64>(), Some([].as_slice()));
+        }
+
+        /// Places the vector 8 bytes past a 16 byte boundary.
+        #[repr(C, align(16))]
+        struct Misaligned {
+            _pad: u64,
+            vec: StackVec<u8, 16>,
+        }
+
+        let misaligned = Misaligned {
+            _pad: 0,
+            vec: StackVec::from(bytes),
+        };
+        assert_eq!(misaligned.vec.as_ptr() as usize % 16, 8);
+        unsafe {
+            assert_eq!(misaligned.vec.reinterpret_as::<u128>(), None);
+            assert_eq!(
+                misaligned.vec.reinterpret_as::<u64>().map(<[_]>::len),
+                Some(2)
+            );
+        }
+    }
+
+    #[test]
+    fn zeroed_fills_only_the_length() {
+        let mut vec = StackVec::<u64, 8>::zeroed(5);
+        assert_eq!(vec.len(), 5);
+        assert_eq!(vec.as_slice(), [0; 5]);
+        assert_eq!(vec.spare_capacity_mut().len(), 3);
+        assert_eq!(vec.push(7), None);
+        assert_eq!(vec.as_slice(), [0, 0, 0, 0, 0, 7]);
+
+        assert!(StackVec::<u64, 8>::zeroed(0).is_empty());
+        assert!(StackVec::<u64, 8>::zeroed(8).is_full());
+    }
+
+    #[test]
+    #[should_panic(expected = "Length 9 is greater than the capacity 8")]
+    fn zeroed_panics_past_the_capacity() {
+        StackVec::<u64, 8>::zeroed(9);
+    }
+
+    #[test]
+    fn fmt_write_is_all_or_nothing() {
+        use core::fmt::Write;
+
+        let mut vec = StackVec::<u8, 8>::new();
+        assert!(vec.write_str("abcde").is_ok());
+        // Three bytes are left, so the four byte string isn't written at all.
+        assert!(vec.write_str("fghi").is_err());
+        assert_eq!(vec.as_slice(), b"abcde");
+        // A multi-byte character is never cut.
+        assert!(vec.write_str("\u{e9}\u{e9}").is_err());
+        assert_eq!(vec.as_slice(), b"abcde");
+        assert!(vec.write_str("fgh").is_ok());
+        assert!(vec.is_full());
+        assert!(vec.write_str("").is_ok());
+
+        let mut vec = StackVec::<u8, 8>::new();
+        assert!(write!(vec, "{}-{}", 12, 34).is_ok());
+        assert_eq!(vec.as_slice(), b"12-34");
+        assert!(write!(vec, "{}", 6789).is_err());
+        assert_eq!(vec.as_slice(), b"12-34");
+    }
+
+    #[test]
+    fn take_n_moves_the_prefix() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4, 1, 5]);
+        let front = vec.take_n(2);
+        assert_eq!(front.as_slice(), [3, 1]);
+        assert_eq!(vec.as_slice(), [4, 1, 5]);
+
+        assert!(vec.take_n(0).is_empty());
+        assert_eq!(vec.as_slice(), [4, 1, 5]);
+        assert_eq!(vec.take_n(3).as_slice(), [4, 1, 5]);
+        assert!(vec.is_empty());
+
+        let drops = Cell::new(0);
+        let mut vec = counted::<8>(&drops, 4, None);
+        let front = vec.take_n(3);
+        assert_eq!((indices(&front), indices(&vec)), (vec![0, 1, 2], vec![3]));
+        assert_eq!(drops.get(), 0);
+        drop((front, vec));
+        assert_eq!(drops.get(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Split index 4 is out of bounds for len 3")]
+    fn take_n_panics_past_the_end() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        vec.take_n(4);
+    }
+
+    #[test]
+    fn remove_item_removes_the_first_match() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4, 1, 5]);
+        assert_eq!(vec.remove_item(&1), Some(1));
+        assert_eq!(vec.as_slice(), [3, 4, 1, 5]);
+        assert_eq!(vec.remove_item(&5), Some(5));
+        assert_eq!(vec.as_slice(), [3, 4, 1]);
+
+        assert_eq!(vec.remove_item(&9), None);
+        assert_eq!(vec.as_slice(), [3, 4, 1]);
+        assert_eq!(StackVec::<u32, 8>::new().remove_item(&3), None);
+    }
+
+    #[test]
+    fn drain_rev_yields_from_the_last() {
+        let mut vec = StackVec::<u32, 8>::from([1, 2, 3, 4, 5, 6]);
+        assert_eq!(vec.drain_rev(1..4).collect::<Vec<_>>(), [4, 3, 2]);
+        assert_eq!(vec.as_slice(), [1, 5, 6]);
+
+        // The tail is moved back even if the iterator isn't exhausted.
+        let mut drain = vec.drain_rev(..2);
+        assert_eq!(drain.next(), Some(5));
+        drop(drain);
+        assert_eq!(vec.as_slice(), [6]);
+
+        let drops = Cell::new(0);
+        let mut vec = counted::<8>(&drops, 5, None);
+        let mut drain = vec.drain_rev(1..4);
+        assert_eq!(drain.next().map(|(i, _)| i), Some(3));
+        assert_eq!(drain.len(), 2);
+        drop(drain);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(indices(&vec), [0, 4]);
+    }
+
+    #[test]
+    fn spare_capacity_and_push_within_capacity() {
+        let mut vec = StackVec::<u32, 4>::from([3, 1]);
+        let spare = vec.spare_capacity_mut();
+        assert_eq!(spare.len(), 2);
+        spare[0].write(4);
+        unsafe {
+            vec.set_len(3);
+        }
+        assert_eq!(vec.as_slice(), [3, 1, 4]);
+
+        assert_eq!(vec.push_within_capacity(1), Ok(()));
+        assert!(vec.is_full());
+        assert!(vec.spare_capacity_mut().is_empty());
+        assert_eq!(vec.push_within_capacity(5), Err(5));
+        assert_eq!(vec.as_slice(), [3, 1, 4, 1]);
+    }
+
+    #[test]
+    fn partition_point_finds_the_first_false() {
+        let vec = StackVec::<u32, 8>::from([1, 2, 2, 4, 7, 9]);
+        assert_eq!(vec.partition_point(|&x| x < 2), 1);
+        assert_eq!(vec.partition_point(|&x| x <= 2), 3);
+        assert_eq!(vec.partition_point(|&x| x < 5), 4);
+        assert_eq!(vec.partition_point(|&x| x < 1), 0);
+        assert_eq!(vec.partition_point(|&x| x < 10), 6);
+        assert_eq!(StackVec::<u32, 8>::new().partition_point(|_| true), 0);
+    }
+
+    #[test]
+    fn chunks_mut_covers_only_the_length() {
+        let mut vec = StackVec::<u32, 8>::from([1, 2, 3, 4, 5]);
+        let lens: Vec<_> = vec.chunks_mut(2).map(|chunk| chunk.len()).collect();
+        assert_eq!(lens, [2, 2, 1]);
+        for chunk in vec.chunks_mut(2) {
+            chunk.reverse();
+        }
+        assert_eq!(vec.as_slice(), [2, 1, 4, 3, 5]);
+        assert_eq!(vec.chunks_mut(5).count(), 1);
+        assert_eq!(StackVec::<u32, 8>::new().chunks_mut(3).count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunks_mut_panics_on_a_zero_size() {
+        let mut vec = StackVec::<u32, 8>::from([1, 2, 3]);
+        let _ = vec.chunks_mut(0);
+    }
+
+    #[test]
+    fn as_ref_as_mut_and_borrow_view_the_live_elements() {
+        use core::borrow::{Borrow, BorrowMut};
+
+        fn sum(items: impl AsRef<[u32]>) -> u32 {
+            items.as_ref().iter().sum()
+        }
+
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        assert_eq!(AsRef::<[u32]>::as_ref(&vec), [3, 1, 4]);
+        assert_eq!(Borrow::<[u32]>::borrow(&vec), [3, 1, 4]);
+        AsMut::<[u32]>::as_mut(&mut vec)[0] = 2;
+        BorrowMut::<[u32]>::borrow_mut(&mut vec)[2] = 5;
+        assert_eq!(vec.as_slice(), [2, 1, 5]);
+        assert_eq!(sum(&vec), 8);
+        assert_eq!(sum(vec), 8);
+    }
+
+    #[test]
+    fn dedup_keeps_the_first_of_every_run() {
+        let mut vec = StackVec::<u32, 8>::from([1, 1, 2, 3, 3, 3, 1, 1]);
+        vec.dedup();
+        assert_eq!(vec.as_slice(), [1, 2, 3, 1]);
+
+        let mut vec = StackVec::<u32, 8>::from([10, 11, 25, 21, 30, 12]);
+        vec.dedup_by_key(|x| *x / 10);
+        assert_eq!(vec.as_slice(), [10, 25, 30, 12]);
+
+        // `b` is the last kept element, so a run is compared with its first element.
+        let mut vec = StackVec::<u32, 8>::from([1, 2, 3, 4, 6, 7]);
+        vec.dedup_by(|a, b| *a - *b <= 2);
+        assert_eq!(vec.as_slice(), [1, 4, 7]);
+
+        let drops = Cell::new(0);
+        let mut vec = counted::<8>(&drops, 6, None);
+        vec.dedup_by(|&mut (a, _), &mut (b, _)| a / 2 == b / 2);
+        assert_eq!(indices(&vec), [0, 2, 4]);
+        assert_eq!(drops.get(), 3);
+
+        let mut vec = StackVec::<u32, 8>::from([7]);
+        vec.dedup();
+        assert_eq!(vec.as_slice(), [7]);
+    }
+
+    #[test]
+    fn push_front_and_pop_front() {
+        let mut vec = StackVec::<u32, 3>::new();
+        assert_eq!(vec.pop_front(), None);
+        assert_eq!(vec.push_front(1), None);
+        assert_eq!(vec.push_front(2), None);
+        assert_eq!(vec.push_front(3), None);
+        assert_eq!(vec.as_slice(), [3, 2, 1]);
+
+        // A full vector evicts its last element.
+        assert_eq!(vec.push_front(4), Some(1));
+        assert_eq!(vec.as_slice(), [4, 3, 2]);
+
+        assert_eq!(vec.pop_front(), Some(4));
+        assert_eq!(vec.pop_front(), Some(3));
+        assert_eq!(vec.as_slice(), [2]);
+        assert_eq!(vec.pop_front(), Some(2));
+        assert_eq!(vec.pop_front(), None);
+    }
+
+    #[test]
+    fn get_and_get_mut_are_bounded_by_the_length() {
+        let mut vec = StackVec::<u32, 8>::from([3, 1, 4]);
+        assert_eq!(vec.get(0), Some(&3));
+        assert_eq!(vec.get(2), Some(&4));
+        // The slots past the length are within the capacity, but they aren't elements.
+        assert_eq!(vec.get(3), None);
+        assert_eq!(vec.get(8), None);
+        assert_eq!(vec.get(usize::MAX), None);
+
+        *vec.get_mut(1).unwrap() = 5;
+        assert_eq!(vec.as_slice(), [3, 5, 4]);
+        assert_eq!(vec.get_mut(3), None);
+        vec.pop();
+        assert_eq!(vec.get(2), None);
+        assert_eq!(vec.get_mut(2), None);
+    }
+
+    #[test]
+    fn outer_len_into_iter_yields_and_drops_once() {
+        let drops = Cell::new(0);
+        let (data, len) = counted::<8>(&drops, 5, None).into_raw_parts();
+        assert_eq!(len, 5);
+
+        let mut iter = unsafe { data.into_iter(len) };
+        assert_eq!(iter.next().map(|(i, _)| i), Some(0));
+        assert_eq!(iter.next_back().map(|(i, _)| i), Some(4));
+        assert_eq!(drops.get(), 2);
+        assert_eq!(iter.len(), 3);
+        // The elements that weren't yielded are dropped with the iterator.
+        drop(iter);
+        assert_eq!(drops.get(), 5);
+
+        let drops = Cell::new(0);
+        let (data, len) = counted::<8>(&drops, 3, None).into_raw_parts();
+        let yielded: Vec<_> = unsafe { data.into_iter(len) }.map(|(i, _)| i).collect();
+        assert_eq!(yielded, [0, 1, 2]);
+        assert_eq!(drops.get(), 3);
+    }
+}
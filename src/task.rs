@@ -0,0 +1,195 @@
+//! A minimal cooperative, single-threaded async executor.
+//!
+
+use crate::stack_vec::StackVec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// The maximum number of tasks an executor can hold, one for every bit of `WOKEN_TASKS`.
+pub const MAX_TASKS: usize = 64;
+
+/// A bitmap of the tasks that were woken and should be polled again. It's a lock-free static so
+/// wakers can be used from interrupt handlers.
+static WOKEN_TASKS: AtomicU64 = AtomicU64::new(0);
+
+/// The id of a task, its index in the executor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    fn wake(self) {
+        WOKEN_TASKS.fetch_or(1 << self.0, Ordering::AcqRel);
+    }
+
+    fn waker(self) -> Waker {
+        unsafe { Waker::from_raw(raw_waker(self)) }
+    }
+}
+
+fn raw_waker(id: TaskId) -> RawWaker {
+    fn clone(data: *const ()) -> RawWaker {
+        raw_waker(TaskId(data as usize))
+    }
+    fn wake(data: *const ()) {
+        TaskId(data as usize).wake();
+    }
+    fn drop(_data: *const ()) {}
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+    RawWaker::new(id.0 as *const (), &VTABLE)
+}
+
+/// A task, a pinned future that outputs nothing.
+pub struct Task<'a> {
+    future: Pin<&'a mut dyn Future<Output = ()>>,
+}
+
+impl<'a> Task<'a> {
+    /// Creates a task from a pinned future.
+    pub fn new(future: Pin<&'a mut dyn Future<Output = ()>>) -> Self {
+        Self { future }
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// A single-threaded executor that polls its tasks only when they're woken. The wakers are
+/// shared between executors, so there should be only one.
+///
+/// The futures aren't boxed, they're borrowed for `'a` instead.
+pub struct Executor<'a> {
+    /// The tasks by their ids, the slots of finished tasks are `None` until they're reused.
+    tasks: StackVec<Option<Task<'a>>, MAX_TASKS>,
+}
+
+impl<'a> Executor<'a> {
+    /// Creates an empty executor.
+    pub fn new() -> Self {
+        Self {
+            tasks: StackVec::new(),
+        }
+    }
+
+    /// Adds a task and wakes it, so it'll be polled for the first time. Returns the task back if
+    /// the executor is full.
+    pub fn spawn(&mut self, task: Task<'a>) -> Result<TaskId, Task<'a>> {
+        let idx = match self.tasks.iter().position(Option::is_none) {
+            Some(idx) => {
+                self.tasks[idx] = Some(task);
+                idx
+            }
+            None => {
+                if let Some(Some(task)) = self.tasks.push(Some(task)) {
+                    return Err(task);
+                }
+                self.tasks.len() - 1
+            }
+        };
+        let id = TaskId(idx);
+        id.wake();
+        Ok(id)
+    }
+
+    /// Polls every woken task once. Returns true if there are still unfinished tasks.
+    pub fn run_ready(&mut self) -> bool {
+        let woken = WOKEN_TASKS.swap(0, Ordering::AcqRel);
+        for (idx, slot) in self.tasks.iter_mut().enumerate() {
+            if woken & 1 << idx == 0 {
+                continue;
+            }
+            if let Some(task) = slot {
+                let waker = TaskId(idx).waker();
+                if task.poll(&mut Context::from_waker(&waker)).is_ready() {
+                    *slot = None;
+                }
+            }
+        }
+        while let Some(None) = self.tasks.last() {
+            self.tasks.pop();
+        }
+        !self.tasks.is_empty()
+    }
+
+    /// Runs the tasks until all of them finish.
+    pub fn run(&mut self) {
+        while self.run_ready() {
+            if WOKEN_TASKS.load(Ordering::Acquire) == 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl<'a> Default for Executor<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns a future that yields to the executor once, so the other tasks can run.
+pub fn yield_now() -> impl Future<Output = ()> {
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    YieldNow(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use core::pin::pin;
+    use std::vec::Vec;
+
+    #[test]
+    fn runs_cooperating_tasks_to_completion() {
+        let trace = RefCell::new(Vec::new());
+        let ping = pin!(async {
+            for i in 0..3 {
+                trace.borrow_mut().push(("ping", i));
+                yield_now().await;
+            }
+        });
+        let pong = pin!(async {
+            for i in 0..3 {
+                trace.borrow_mut().push(("pong", i));
+                yield_now().await;
+            }
+        });
+
+        let mut executor = Executor::new();
+        assert_eq!(executor.spawn(Task::new(ping)).ok(), Some(TaskId(0)));
+        assert_eq!(executor.spawn(Task::new(pong)).ok(), Some(TaskId(1)));
+        executor.run();
+
+        assert_eq!(
+            *trace.borrow(),
+            [
+                ("ping", 0),
+                ("pong", 0),
+                ("ping", 1),
+                ("pong", 1),
+                ("ping", 2),
+                ("pong", 2),
+            ]
+        );
+        assert!(!executor.run_ready());
+    }
+}
@@ -0,0 +1,111 @@
+//! A boot watchdog, it reports over serial when the boot doesn't make progress.
+//!
+//! The timer interrupt calls `tick()`, and the boot code calls `kick()` whenever it makes
+//! progress. If `timeout` ticks pass without a kick, the current phase is reported, and again
+//! every `timeout` ticks after that. It's disabled until `enable()` is called, and the timer
+//! interrupt comes only once interrupts are enabled.
+//!
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+/// The phases of the boot, the phase a stall happens in is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BootPhase {
+    /// `init_early()`, the logger initialization.
+    Early,
+    /// `init_cpu()`, the GDT, the IDT and the PICs.
+    Cpu,
+    /// `mem::init()`.
+    Memory,
+    /// Running the first tasks.
+    Tasks,
+}
+
+impl BootPhase {
+    const ALL: [Self; 4] = [Self::Early, Self::Cpu, Self::Memory, Self::Tasks];
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+static TICKS_SINCE_KICK: AtomicU64 = AtomicU64::new(0);
+static PHASE: AtomicU8 = AtomicU8::new(BootPhase::Early as u8);
+
+/// Enables the watchdog, a stall is `timeout` timer ticks without a kick. It also kicks.
+///
+/// # Panics
+/// Panics if `timeout` is zero.
+pub fn enable(timeout: u64) {
+    assert_ne!(timeout, 0, "The watchdog timeout must be positive");
+    TIMEOUT.store(timeout, Ordering::Relaxed);
+    kick();
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Disables the watchdog, `tick()` does nothing until it's enabled again.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Tells the watchdog that the boot made progress, it restarts the stall countdown.
+pub fn kick() {
+    TICKS_SINCE_KICK.store(0, Ordering::Relaxed);
+}
+
+/// Sets the current boot phase, and kicks the watchdog since entering a phase is progress.
+pub fn set_phase(phase: BootPhase) {
+    PHASE.store(phase as u8, Ordering::Relaxed);
+    kick();
+}
+
+/// Returns the current boot phase.
+pub fn phase() -> BootPhase {
+    BootPhase::ALL[PHASE.load(Ordering::Relaxed) as usize]
+}
+
+/// Returns the number of timer ticks since interrupts were enabled, counted even while the
+/// watchdog is disabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns true if `ticks` ticks since the last kick should be reported as a stall.
+#[inline]
+pub fn is_stall_report_due(ticks: u64, timeout: u64) -> bool {
+    ticks != 0 && ticks.is_multiple_of(timeout)
+}
+
+/// Counts a timer tick and reports a stall if it's due. Called by the timer interrupt handler.
+///
+/// The stalled code may hold the serial port, so the report is dropped instead of waiting for it.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let ticks = TICKS_SINCE_KICK.fetch_add(1, Ordering::Relaxed) + 1;
+    if is_stall_report_due(ticks, TIMEOUT.load(Ordering::Relaxed)) {
+        crate::serial::try_sprint(format_args!(
+            "WATCHDOG: boot appears stalled in phase {:?} ({} ticks since the last kick)\n",
+            phase(),
+            ticks,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_is_reported_once_per_timeout() {
+        assert!(!is_stall_report_due(0, 100));
+        assert!(!is_stall_report_due(1, 100));
+        assert!(!is_stall_report_due(99, 100));
+        assert!(is_stall_report_due(100, 100));
+        assert!(!is_stall_report_due(101, 100));
+        assert!(is_stall_report_due(200, 100));
+        assert!(is_stall_report_due(1, 1));
+    }
+}